@@ -0,0 +1,252 @@
+use crate::platform::Metadata;
+use crate::timestamp::Timestamp;
+use crate::{DirUsage, ScanOptions};
+use fxhash::FxHashMap;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+const MAGIC: &[u8] = b"du-rs cache 1\n";
+
+/// Directories from an earlier scan, and those of the current one as it
+/// goes, keyed by device and inode.
+///
+/// A directory counts as unchanged while its modification and status change
+/// times are. Its files are then not read again: their count and sizes come
+/// from the cache, and only its subdirectories are walked, each checked the
+/// same way. Adding, removing or renaming an entry changes a directory's
+/// times, but rewriting a file in place does not, so such a file keeps its
+/// cached size until something else changes its directory.
+#[derive(Debug, Default)]
+pub struct DirCache {
+    // What the options that decide a directory's own usage looked like, so a
+    // cache saved with other ones is not used.
+    key: Vec<u8>,
+    earlier: FxHashMap<(u64, u64), CachedDir>,
+    current: Mutex<FxHashMap<(u64, u64), CachedDir>>,
+    reused: AtomicU64,
+}
+
+/// A directory's own files, as [`DirCache`] keeps them.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CachedDir {
+    mtime: Timestamp,
+    ctime: Timestamp,
+    files: u64,
+    files_size: i64,
+    // Latest time of the files, of the kind the scan dates entries by.
+    time: Timestamp,
+    entries: u64,
+    pub(crate) subdirs: Vec<Box<[u8]>>,
+}
+
+impl CachedDir {
+    pub(crate) fn new(meta: &Metadata) -> CachedDir {
+        CachedDir {
+            mtime: meta.modified,
+            ctime: meta.changed,
+            ..CachedDir::default()
+        }
+    }
+
+    pub(crate) fn add_file(&mut self, size: i64, time: Timestamp) {
+        self.files += 1;
+        self.files_size = self.files_size.saturating_add(size);
+        self.time = self.time.max(time);
+    }
+
+    // Counts the files into `usage`, along with the entries that are not
+    // subdirectories; those are counted as they are walked.
+    pub(crate) fn add_files_to(&self, usage: &mut DirUsage) {
+        usage.add(self.files_size);
+        usage.mtime = usage.mtime.max(self.time);
+        usage.inodes += self.files;
+        usage.files += self.files;
+        usage.files_size = usage.files_size.saturating_add(self.files_size);
+        usage.entries += self.entries.saturating_sub(self.subdirs.len() as u64);
+    }
+
+    // Starts this scan's record of a reused directory, its subdirectories
+    // added back as they are walked.
+    pub(crate) fn reuse(&self) -> CachedDir {
+        CachedDir {
+            subdirs: Vec::new(),
+            ..self.clone()
+        }
+    }
+}
+
+impl DirCache {
+    /// An empty cache for scans with `options`.
+    pub fn new(options: &ScanOptions) -> DirCache {
+        DirCache {
+            key: cache_key(options),
+            ..DirCache::default()
+        }
+    }
+
+    /// Reads a cache as [`DirCache::write`] saved it. One saved with options
+    /// that count differently reads as empty; anything that is not a cache
+    /// is an `InvalidData` error.
+    pub fn read<R: Read>(mut reader: R, options: &ScanOptions) -> io::Result<DirCache> {
+        let mut cache = DirCache::new(options);
+        let mut magic = [0; MAGIC.len()];
+        match reader.read_exact(&mut magic) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(invalid("not a du-rs cache"));
+            }
+            result => result?,
+        }
+        if magic != MAGIC {
+            return Err(invalid("not a du-rs cache"));
+        }
+        if read_bytes(&mut reader)? != cache.key {
+            return Ok(cache);
+        }
+        while let Some(dev) = read_first_u64(&mut reader)? {
+            let ino = read_u64(&mut reader)?;
+            let mut dir = CachedDir {
+                mtime: read_timestamp(&mut reader)?,
+                ctime: read_timestamp(&mut reader)?,
+                files: read_u64(&mut reader)?,
+                files_size: read_u64(&mut reader)? as i64,
+                time: read_timestamp(&mut reader)?,
+                entries: read_u64(&mut reader)?,
+                subdirs: Vec::new(),
+            };
+            for _ in 0..read_u64(&mut reader)? {
+                dir.subdirs.push(read_bytes(&mut reader)?.into());
+            }
+            cache.earlier.insert((dev, ino), dir);
+        }
+        Ok(cache)
+    }
+
+    /// Saves the directories of the scans made with this cache. Those only
+    /// found in the cache it was read from are left out.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        write_bytes(&mut writer, &self.key)?;
+        let current = self
+            .current
+            .lock()
+            .map_err(|_| io::Error::other("cache lock poisoned"))?;
+        for (&(dev, ino), dir) in current.iter() {
+            writer.write_all(&dev.to_le_bytes())?;
+            writer.write_all(&ino.to_le_bytes())?;
+            write_timestamp(&mut writer, dir.mtime)?;
+            write_timestamp(&mut writer, dir.ctime)?;
+            writer.write_all(&dir.files.to_le_bytes())?;
+            writer.write_all(&dir.files_size.to_le_bytes())?;
+            write_timestamp(&mut writer, dir.time)?;
+            writer.write_all(&dir.entries.to_le_bytes())?;
+            writer.write_all(&(dir.subdirs.len() as u64).to_le_bytes())?;
+            for name in &dir.subdirs {
+                write_bytes(&mut writer, name)?;
+            }
+        }
+        writer.flush()
+    }
+
+    /// How many directories were taken from the cache rather than read.
+    pub fn reused(&self) -> u64 {
+        self.reused.load(Ordering::Relaxed)
+    }
+
+    // The saved directory with the inode of `meta`, unless it has changed.
+    pub(crate) fn lookup(&self, meta: &Metadata) -> Option<&CachedDir> {
+        let found = CachedDir::new(meta);
+        let dir = self
+            .earlier
+            .get(&(meta.dev, meta.ino))
+            .filter(|dir| dir.mtime == found.mtime && dir.ctime == found.ctime)?;
+        self.reused.fetch_add(1, Ordering::Relaxed);
+        Some(dir)
+    }
+
+    pub(crate) fn store(&self, inode: (u64, u64), mut dir: CachedDir, usage: &DirUsage) {
+        dir.entries = usage.entries;
+        if let Ok(mut current) = self.current.lock() {
+            current.insert(inode, dir);
+        }
+    }
+}
+
+// The options a directory's own usage depends on.
+fn cache_key(options: &ScanOptions) -> Vec<u8> {
+    format!(
+        "{:?} {:?} {} {:?} {:?} {:?} {:?} {} {} {:?} {:?} {:?}",
+        options.size_format,
+        options.time,
+        options.count_links,
+        options.dereference,
+        options.exclude_from,
+        options.exclude,
+        options.exclude_dirs,
+        options.gitignore,
+        options.exclude_caches,
+        options.exclude_smaller_than,
+        options.exclude_larger_than,
+        options.newer,
+    )
+    .into_bytes()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader
+        .read_exact(&mut buf)
+        .map_err(|_| invalid("cache is cut short"))?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+// Like `read_u64`, but `None` at the end of the input, where a record may end.
+fn read_first_u64<R: Read>(reader: &mut R) -> io::Result<Option<u64>> {
+    let mut buf = [0; 8];
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(invalid("cache is cut short")),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Some(u64::from_le_bytes(buf)))
+}
+
+fn read_timestamp<R: Read>(reader: &mut R) -> io::Result<Timestamp> {
+    Ok(Timestamp {
+        secs: read_u64(reader)? as i64,
+        nsecs: read_u64(reader)? as i64,
+    })
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_u64(reader)?;
+    // Names and the key are short; a huge length is a damaged file rather
+    // than something to allocate for.
+    if len > 1 << 16 {
+        return Err(invalid("cache holds an overlong name"));
+    }
+    let mut bytes = vec![0; len as usize];
+    reader
+        .read_exact(&mut bytes)
+        .map_err(|_| invalid("cache is cut short"))?;
+    Ok(bytes)
+}
+
+fn write_timestamp<W: Write>(writer: &mut W, time: Timestamp) -> io::Result<()> {
+    writer.write_all(&time.secs.to_le_bytes())?;
+    writer.write_all(&time.nsecs.to_le_bytes())
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    writer.write_all(bytes)
+}
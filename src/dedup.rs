@@ -0,0 +1,247 @@
+use crate::platform;
+use fxhash::{FxHashMap, FxHasher};
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Bytes read from each end of a file for the quick comparison.
+const SAMPLE: u64 = 4096;
+
+/// Files seen during a walk, keyed by size, to look for duplicates among.
+#[derive(Debug, Default)]
+pub struct DuplicateFinder {
+    by_size: Mutex<FxHashMap<u64, Vec<PathBuf>>>,
+}
+
+/// Files that look identical.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    /// Size of each file, in bytes.
+    pub size: u64,
+    pub paths: Vec<PathBuf>,
+}
+
+impl DuplicateGroup {
+    /// Bytes freed by keeping a single copy.
+    pub fn reclaimable(&self) -> u64 {
+        self.size * (self.paths.len() as u64 - 1)
+    }
+}
+
+impl DuplicateFinder {
+    /// Adds a file of `size` bytes. Empty files are all alike and ignored.
+    pub fn record(&self, path: &[u8], size: u64) {
+        if size == 0 {
+            return;
+        }
+        if let Ok(mut by_size) = self.by_size.lock() {
+            by_size
+                .entry(size)
+                .or_default()
+                .push(PathBuf::from(platform::os_str(path).into_owned()));
+        }
+    }
+
+    /// Groups of files with the same size and content, most reclaimable
+    /// first. Only files sharing a size are read: their first and last 4K
+    /// by default, or all of them with `full`. Unreadable files are left out.
+    /// The recorded files are handed over, leaving the finder empty.
+    pub fn groups(&self, full: bool) -> Vec<DuplicateGroup> {
+        let by_size = match self.by_size.lock() {
+            Ok(mut by_size) => std::mem::take(&mut *by_size),
+            Err(_) => return Vec::new(),
+        };
+
+        let mut groups = Vec::new();
+        for (size, mut paths) in by_size {
+            // The same file may be given more than once on the command line.
+            paths.sort();
+            paths.dedup();
+            if paths.len() < 2 {
+                continue;
+            }
+
+            let mut by_hash: FxHashMap<u64, Vec<PathBuf>> = FxHashMap::default();
+            for path in paths {
+                if let Ok(hash) = content_hash(&path, size, full) {
+                    by_hash.entry(hash).or_default().push(path);
+                }
+            }
+            groups.extend(
+                by_hash
+                    .into_values()
+                    .filter(|paths| paths.len() > 1)
+                    .flat_map(|paths| split_alike(paths, size, full))
+                    .filter(|paths| paths.len() > 1)
+                    .map(|paths| DuplicateGroup { size, paths }),
+            );
+        }
+
+        groups.sort_by(|a, b| {
+            b.reclaimable()
+                .cmp(&a.reclaimable())
+                .then_with(|| a.paths.cmp(&b.paths))
+        });
+        groups
+    }
+}
+
+// The parts of a file of `size` bytes that are compared, as offsets and
+// lengths: all of it, or its first and last `SAMPLE` bytes.
+fn compared_parts(size: u64, full: bool) -> Vec<(u64, u64)> {
+    if full || size <= 2 * SAMPLE {
+        vec![(0, size)]
+    } else {
+        vec![(0, SAMPLE), (size - SAMPLE, SAMPLE)]
+    }
+}
+
+fn content_hash(path: &Path, size: u64, full: bool) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut hasher = FxHasher::default();
+    let mut buffer = vec![0; 64 * 1024];
+
+    for (start, len) in compared_parts(size, full) {
+        file.seek(SeekFrom::Start(start))?;
+        let mut left = len;
+        while left > 0 {
+            let chunk = &mut buffer[..left.min(64 * 1024) as usize];
+            file.read_exact(chunk)?;
+            hasher.write(chunk);
+            left -= chunk.len() as u64;
+        }
+    }
+    Ok(hasher.finish())
+}
+
+// A 64-bit hash that is not meant to resist collisions only narrows down the
+// candidates, so files sharing one are compared byte for byte over the same
+// parts before they are called duplicates.
+fn split_alike(paths: Vec<PathBuf>, size: u64, full: bool) -> Vec<Vec<PathBuf>> {
+    let mut groups: Vec<Vec<PathBuf>> = Vec::new();
+    'paths: for path in paths {
+        for group in &mut groups {
+            match same_content(&group[0], &path, size, full) {
+                Ok(true) => {
+                    group.push(path);
+                    continue 'paths;
+                }
+                Ok(false) => {}
+                Err(_) => continue 'paths,
+            }
+        }
+        groups.push(vec![path]);
+    }
+    groups
+}
+
+fn same_content(a: &Path, b: &Path, size: u64, full: bool) -> io::Result<bool> {
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    let mut buffer_a = vec![0; 64 * 1024];
+    let mut buffer_b = vec![0; 64 * 1024];
+
+    for (start, len) in compared_parts(size, full) {
+        a.seek(SeekFrom::Start(start))?;
+        b.seek(SeekFrom::Start(start))?;
+        let mut left = len;
+        while left > 0 {
+            let n = left.min(64 * 1024) as usize;
+            a.read_exact(&mut buffer_a[..n])?;
+            b.read_exact(&mut buffer_b[..n])?;
+            if buffer_a[..n] != buffer_b[..n] {
+                return Ok(false);
+            }
+            left -= n as u64;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // A directory of its own under the temp dir, removed once dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("du-rs-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn file(&self, name: &str, content: &[u8]) -> PathBuf {
+            let path = self.0.join(name);
+            fs::write(&path, content).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn find(files: &[&PathBuf], full: bool) -> Vec<DuplicateGroup> {
+        let finder = DuplicateFinder::default();
+        for path in files {
+            finder.record(
+                path.as_os_str().as_encoded_bytes(),
+                fs::metadata(path).unwrap().len(),
+            );
+        }
+        finder.groups(full)
+    }
+
+    #[test]
+    fn groups_identical_files_and_leaves_out_the_rest() {
+        let dir = TempDir::new("dedup-identical");
+        let a = dir.file("a", &[7; 10_000]);
+        let b = dir.file("b", &[7; 10_000]);
+        let unique = dir.file("unique", &[8; 10_000]);
+        let empty = dir.file("empty", b"");
+        let also_empty = dir.file("also-empty", b"");
+
+        for full in [false, true] {
+            let groups = find(&[&a, &unique, &b, &empty, &also_empty], full);
+            assert_eq!(
+                groups,
+                [DuplicateGroup {
+                    size: 10_000,
+                    paths: vec![a.clone(), b.clone()]
+                }]
+            );
+            assert_eq!(groups[0].reclaimable(), 10_000);
+        }
+    }
+
+    #[test]
+    fn files_alike_only_in_their_hash_are_not_duplicates() {
+        // FxHash takes 8 bytes at a time as `(rotl(h, 5) ^ word) * K`, so a
+        // second word can be picked to cancel out any difference in the first.
+        const K: u64 = 0x517c_c1b7_2722_0a95;
+        let (first_a, first_b, second_a) = (1u64, 2u64, 3u64);
+        let second_b = first_a.wrapping_mul(K).rotate_left(5)
+            ^ second_a
+            ^ first_b.wrapping_mul(K).rotate_left(5);
+        let content_a = [first_a.to_le_bytes(), second_a.to_le_bytes()].concat();
+        let content_b = [first_b.to_le_bytes(), second_b.to_le_bytes()].concat();
+
+        let dir = TempDir::new("dedup-collision");
+        let a = dir.file("a", &content_a);
+        let b = dir.file("b", &content_b);
+        assert_eq!(
+            content_hash(&a, 16, true).unwrap(),
+            content_hash(&b, 16, true).unwrap()
+        );
+
+        assert_eq!(find(&[&a, &b], true), []);
+        assert_eq!(find(&[&a, &b], false), []);
+    }
+}
@@ -0,0 +1,31 @@
+use std::sync::Mutex;
+
+/// Usage summed by how deep it sits below the root.
+#[derive(Debug, Default)]
+pub struct DepthSizes {
+    sizes: Mutex<Vec<i64>>,
+}
+
+impl DepthSizes {
+    /// Adds the own usage of a directory at `depth`: its inode and the files
+    /// directly in it. Files given as roots are at depth 0.
+    pub fn add(&self, depth: i32, size: i64) {
+        let Ok(depth) = usize::try_from(depth) else {
+            return;
+        };
+        if let Ok(mut sizes) = self.sizes.lock() {
+            if sizes.len() <= depth {
+                sizes.resize(depth + 1, 0);
+            }
+            sizes[depth] = sizes[depth].saturating_add(size);
+        }
+    }
+
+    /// Total at each depth, the root first, up to the deepest one reached.
+    pub fn levels(&self) -> Vec<i64> {
+        self.sizes
+            .lock()
+            .map(|sizes| sizes.clone())
+            .unwrap_or_default()
+    }
+}
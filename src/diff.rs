@@ -0,0 +1,416 @@
+use fxhash::FxHashMap;
+use std::io::{self, BufRead};
+
+/// Sizes by path from a scan saved with `--json`, one record per line.
+/// Grand total records are left out.
+#[derive(Debug, Default)]
+pub struct SavedScan {
+    sizes: FxHashMap<Vec<u8>, i64>,
+    measure: Option<Vec<u8>>,
+}
+
+/// A path whose size differs between two scans. A side is `None` when the
+/// path is missing from that scan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub path: Vec<u8>,
+    pub old: Option<i64>,
+    pub new: Option<i64>,
+}
+
+impl Change {
+    pub fn delta(&self) -> i64 {
+        self.new.unwrap_or(0).saturating_sub(self.old.unwrap_or(0))
+    }
+}
+
+impl SavedScan {
+    /// Reads JSON lines as `--json` writes them. Blank lines are skipped and a
+    /// line that is not such a record is an `InvalidData` error naming it.
+    pub fn read<R: BufRead>(reader: R) -> io::Result<SavedScan> {
+        let mut sizes = FxHashMap::default();
+        let mut measure = None;
+        for (number, line) in reader.split(b'\n').enumerate() {
+            let line = line?;
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let record = Parser::new(&line).record().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {} is not a du-rs JSON record", number + 1),
+                )
+            })?;
+            match &measure {
+                None => measure = Some(record.measure),
+                Some(first) if *first != record.measure => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "line {} measures sizes unlike the lines before it",
+                            number + 1
+                        ),
+                    ));
+                }
+                Some(_) => {}
+            }
+            if !record.total {
+                sizes.insert(record.path, record.size);
+            }
+        }
+        Ok(SavedScan {
+            sizes,
+            measure: measure.flatten(),
+        })
+    }
+
+    /// What the sizes measure, as `--json` names it: `disk`, `apparent` or
+    /// `entries`. `None` for scans saved before it was recorded, whose sizes
+    /// are in whatever unit the scan printed.
+    pub fn measure(&self) -> Option<&[u8]> {
+        self.measure.as_deref()
+    }
+
+    /// Paths that grew, shrank, appeared or went away between `self` and
+    /// `newer`, largest change first.
+    pub fn changes(&self, newer: &SavedScan) -> Vec<Change> {
+        let mut changes: Vec<Change> = newer
+            .sizes
+            .iter()
+            .map(|(path, &size)| Change {
+                path: path.clone(),
+                old: self.sizes.get(path).copied(),
+                new: Some(size),
+            })
+            .chain(
+                self.sizes
+                    .iter()
+                    .filter(|(path, _)| !newer.sizes.contains_key(*path))
+                    .map(|(path, &size)| Change {
+                        path: path.clone(),
+                        old: Some(size),
+                        new: None,
+                    }),
+            )
+            .filter(|change| change.old != change.new)
+            .collect();
+        changes.sort_by(|a, b| {
+            b.delta()
+                .unsigned_abs()
+                .cmp(&a.delta().unsigned_abs())
+                .then_with(|| a.path.cmp(&b.path))
+        });
+        changes
+    }
+}
+
+struct Record {
+    path: Vec<u8>,
+    size: i64,
+    measure: Option<Vec<u8>>,
+    total: bool,
+}
+
+// Just enough JSON for the records `--json` writes: an object holding `path`
+// or `path_bytes`, `bytes`, `measure`, and possibly `time` and `total`. Other
+// members are skipped, so records with more fields still read.
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a [u8]) -> Parser<'a> {
+        Parser { input, pos: 0 }
+    }
+
+    fn record(&mut self) -> Option<Record> {
+        let mut path = None;
+        let mut size = None;
+        let mut measure = None;
+        let mut total = false;
+
+        self.expect(b'{')?;
+        if !self.eat(b'}') {
+            loop {
+                let key = self.string()?;
+                self.expect(b':')?;
+                match &key[..] {
+                    b"path" => path = Some(self.string()?),
+                    b"path_bytes" => path = Some(self.bytes()?),
+                    // Scans saved before the field was renamed call it "size".
+                    b"bytes" | b"size" => size = Some(self.integer()?),
+                    b"measure" => measure = Some(self.string()?),
+                    b"total" => total = self.literal(b"true"),
+                    _ => self.skip_value()?,
+                }
+                if self.eat(b'}') {
+                    break;
+                }
+                self.expect(b',')?;
+            }
+        }
+        self.skip_space();
+        if self.pos != self.input.len() {
+            return None;
+        }
+        Some(Record {
+            path: path?,
+            size: size?,
+            measure,
+            total,
+        })
+    }
+
+    fn skip_space(&mut self) {
+        while self
+            .input
+            .get(self.pos)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_space();
+        if self.input.get(self.pos) == Some(&byte) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Option<()> {
+        self.eat(byte).then_some(())
+    }
+
+    fn literal(&mut self, word: &[u8]) -> bool {
+        self.skip_space();
+        if self.input[self.pos..].starts_with(word) {
+            self.pos += word.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn integer(&mut self) -> Option<i64> {
+        self.skip_space();
+        let start = self.pos;
+        if self.input.get(self.pos) == Some(&b'-') {
+            self.pos += 1;
+        }
+        while self.input.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.input[start..self.pos])
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    // Strings come back as UTF-8 bytes, escapes resolved.
+    fn string(&mut self) -> Option<Vec<u8>> {
+        self.expect(b'"')?;
+        let mut out = Vec::new();
+        loop {
+            let byte = *self.input.get(self.pos)?;
+            self.pos += 1;
+            match byte {
+                b'"' => return Some(out),
+                b'\\' => {
+                    let escaped = *self.input.get(self.pos)?;
+                    self.pos += 1;
+                    match escaped {
+                        b'"' | b'\\' | b'/' => out.push(escaped),
+                        b'b' => out.push(0x08),
+                        b'f' => out.push(0x0c),
+                        b'n' => out.push(b'\n'),
+                        b'r' => out.push(b'\r'),
+                        b't' => out.push(b'\t'),
+                        b'u' => {
+                            let c = self.unicode_escape()?;
+                            out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                        }
+                        _ => return None,
+                    }
+                }
+                _ => out.push(byte),
+            }
+        }
+    }
+
+    // After `\u`; a surrogate pair takes a second `\uXXXX`.
+    fn unicode_escape(&mut self) -> Option<char> {
+        let first = self.hex4()?;
+        if !(0xd800..0xdc00).contains(&first) {
+            return char::from_u32(first);
+        }
+        if !self.input[self.pos..].starts_with(b"\\u") {
+            return None;
+        }
+        self.pos += 2;
+        let second = self.hex4()?;
+        if !(0xdc00..0xe000).contains(&second) {
+            return None;
+        }
+        char::from_u32(0x10000 + ((first - 0xd800) << 10) + (second - 0xdc00))
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let digits = self.input.get(self.pos..self.pos + 4)?;
+        self.pos += 4;
+        u32::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()
+    }
+
+    fn bytes(&mut self) -> Option<Vec<u8>> {
+        self.expect(b'[')?;
+        let mut out = Vec::new();
+        if self.eat(b']') {
+            return Some(out);
+        }
+        loop {
+            out.push(u8::try_from(self.integer()?).ok()?);
+            if self.eat(b']') {
+                return Some(out);
+            }
+            self.expect(b',')?;
+        }
+    }
+
+    fn skip_value(&mut self) -> Option<()> {
+        self.skip_space();
+        match *self.input.get(self.pos)? {
+            b'"' => self.string().map(drop),
+            b'[' | b'{' => {
+                // Nested values are not written by `--json`; step over them
+                // by bracket depth, minding strings.
+                let mut depth = 0;
+                loop {
+                    match *self.input.get(self.pos)? {
+                        b'"' => {
+                            self.string()?;
+                            continue;
+                        }
+                        b'[' | b'{' => depth += 1,
+                        b']' | b'}' => depth -= 1,
+                        _ => {}
+                    }
+                    self.pos += 1;
+                    if depth == 0 {
+                        return Some(());
+                    }
+                }
+            }
+            _ => {
+                let start = self.pos;
+                while self
+                    .input
+                    .get(self.pos)
+                    .is_some_and(|b| !matches!(b, b',' | b'}' | b']') && !b.is_ascii_whitespace())
+                {
+                    self.pos += 1;
+                }
+                (self.pos > start).then_some(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(lines: &str) -> SavedScan {
+        SavedScan::read(lines.as_bytes()).unwrap()
+    }
+
+    fn change(path: &[u8], old: Option<i64>, new: Option<i64>) -> Change {
+        Change {
+            path: path.to_vec(),
+            old,
+            new,
+        }
+    }
+
+    #[test]
+    fn changes_cover_added_removed_and_changed_paths() {
+        let old = scan(concat!(
+            "{\"path\":\"a\",\"bytes\":100,\"measure\":\"disk\"}\n",
+            "{\"path\":\"gone\",\"bytes\":40,\"measure\":\"disk\"}\n",
+            "{\"path\":\"same\",\"bytes\":7,\"measure\":\"disk\"}\n",
+            "{\"path\":\"total\",\"bytes\":147,\"measure\":\"disk\",\"total\":true}\n",
+        ));
+        let new = scan(concat!(
+            "{\"path\":\"a\",\"bytes\":30,\"measure\":\"disk\"}\n",
+            "\n",
+            "{\"path\":\"new\",\"bytes\":500,\"measure\":\"disk\"}\n",
+            "{\"path\":\"same\",\"bytes\":7,\"measure\":\"disk\"}\n",
+            "{\"path\":\"total\",\"bytes\":537,\"measure\":\"disk\",\"total\":true}\n",
+        ));
+        assert_eq!(old.measure(), Some(&b"disk"[..]));
+        assert_eq!(
+            old.changes(&new),
+            [
+                change(b"new", None, Some(500)),
+                change(b"a", Some(100), Some(30)),
+                change(b"gone", Some(40), None),
+            ]
+        );
+        assert_eq!(old.changes(&new)[1].delta(), -70);
+    }
+
+    #[test]
+    fn path_bytes_and_escapes_read_back() {
+        let old = scan("{\"path_bytes\":[102,111,255],\"bytes\":1}\n");
+        let new = scan(concat!(
+            "{\"path_bytes\":[102,111,255],\"bytes\":3,\"time\":\"2024-01-02 03:04\"}\n",
+            "{\"path\":\"tab\\there \\u00e9\\ud83d\\ude00\",\"bytes\":2}\n",
+        ));
+        assert_eq!(old.measure(), None);
+        assert_eq!(
+            old.changes(&new),
+            [
+                change(b"fo\xff", Some(1), Some(3)),
+                change("tab\there \u{e9}\u{1f600}".as_bytes(), None, Some(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn older_scans_name_the_size_field_size() {
+        let old = scan("{\"path\":\"a\",\"size\":4}\n");
+        let new = scan("{\"path\":\"a\",\"bytes\":6}\n");
+        assert_eq!(old.changes(&new), [change(b"a", Some(4), Some(6))]);
+    }
+
+    #[test]
+    fn mixed_measures_are_refused() {
+        let error = SavedScan::read(
+            &b"{\"path\":\"a\",\"bytes\":1,\"measure\":\"disk\"}\n{\"path\":\"b\",\"bytes\":1,\"measure\":\"apparent\"}\n"[..],
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("line 2"), "{}", error);
+    }
+
+    #[test]
+    fn malformed_lines_are_refused() {
+        for line in [
+            "{\"path\":\"a\"}",
+            "{\"bytes\":1}",
+            "[1]",
+            "{\"path\":\"a\",\"bytes\":1} x",
+        ] {
+            let error = SavedScan::read(format!("\n{}\n", line).as_bytes()).unwrap_err();
+            assert!(
+                error.to_string().starts_with("line 2 "),
+                "{}: {}",
+                line,
+                error
+            );
+        }
+    }
+}
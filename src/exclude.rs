@@ -0,0 +1,240 @@
+use crate::glob::{self, Anchor, Glob};
+use crate::platform::{self, Errno};
+use fxhash::FxHashMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum FileContent {
+    Path(PathBuf),
+    Pattern(OsString),
+    Glob(Glob),
+}
+
+/// The `-X` and `--exclude` rules of a scan. The last rule matching an entry
+/// decides, so a `!` rule re-includes what earlier rules excluded and is in
+/// turn overridden by later ones.
+#[derive(Debug, Default)]
+pub(crate) struct Exclusions {
+    // Each rule's position and whether it is negated.
+    paths: FxHashMap<PathBuf, (usize, bool)>,
+    extensions: FxHashMap<OsString, (usize, bool)>,
+    globs: Vec<(Glob, usize, bool)>,
+    has_negations: bool,
+}
+
+impl Exclusions {
+    pub(crate) fn new(rules: impl IntoIterator<Item = (FileContent, bool)>) -> Exclusions {
+        let mut exclusions = Exclusions::default();
+        for (index, (content, negated)) in rules.into_iter().enumerate() {
+            exclusions.has_negations |= negated;
+            match content {
+                FileContent::Path(path) => {
+                    exclusions.paths.insert(path, (index, negated));
+                }
+                FileContent::Pattern(extension) => {
+                    exclusions.extensions.insert(extension, (index, negated));
+                }
+                FileContent::Glob(glob) => exclusions.globs.push((glob, index, negated)),
+            }
+        }
+        exclusions
+    }
+
+    /// `paths` gives the entry's path relative to the scan root and its
+    /// absolute path, only built when a rule needs them.
+    pub(crate) fn excludes(
+        &self,
+        name: &[u8],
+        is_dir: bool,
+        paths: impl FnOnce() -> (Vec<u8>, PathBuf),
+    ) -> bool {
+        let by_extension = Path::new(&platform::os_str(name))
+            .extension()
+            .and_then(|ext| self.extensions.get(ext))
+            .copied();
+        if by_extension.is_some() && !self.has_negations {
+            return true;
+        }
+        if self.paths.is_empty() && self.globs.is_empty() {
+            return by_extension.is_some_and(|(_, negated)| !negated);
+        }
+
+        let (relative, absolute) = paths();
+        let by_path = self.paths.get(&absolute).copied();
+        let by_glob = self
+            .globs
+            .iter()
+            .rev()
+            .find(|(glob, ..)| match glob.anchor() {
+                Anchor::Basename => glob.is_match(name, is_dir),
+                Anchor::Root => glob.is_match(&relative, is_dir),
+                Anchor::Absolute => glob.is_match(absolute.as_os_str().as_encoded_bytes(), is_dir),
+            })
+            .map(|&(_, index, negated)| (index, negated));
+
+        [by_extension, by_path, by_glob]
+            .into_iter()
+            .flatten()
+            .max_by_key(|&(index, _)| index)
+            .is_some_and(|(_, negated)| !negated)
+    }
+}
+
+// Reads the exclude file given to `-X`, or standard input when it is `-`.
+pub fn exclude_list(file: &Path) -> Vec<(FileContent, bool)> {
+    if file == Path::new("-") {
+        return read_exclude_list(io::stdin().lock(), file);
+    }
+
+    match File::open(file) {
+        Ok(file_handle) => read_exclude_list(BufReader::new(file_handle), file),
+        Err(e) => {
+            eprintln!(
+                "du-rs: cannot access '{}': {}",
+                file.display(),
+                Errno::from(e).desc()
+            );
+            Vec::new()
+        }
+    }
+}
+
+// Lines are kept as raw bytes, so names that are not UTF-8 match exactly.
+pub fn read_exclude_list<R: BufRead>(reader: R, source: &Path) -> Vec<(FileContent, bool)> {
+    let mut rules = Vec::new();
+    for line in reader.split(b'\n') {
+        match line {
+            Ok(line) => rules.extend(parse_exclude_line(&line)),
+            Err(e) => {
+                eprintln!("du-rs: failed reading '{}': {}", source.display(), e);
+                return Vec::new();
+            }
+        }
+    }
+    rules
+}
+
+/// Parses one exclude rule, along with whether it is negated by a leading
+/// `!`. A leading `\!` stands for a literal `!`.
+pub fn parse_exclude_line(line: &[u8]) -> Option<(FileContent, bool)> {
+    let trimmed_line = line.trim_ascii();
+    let (trimmed_line, negated) = match trimmed_line.strip_prefix(b"!") {
+        Some(rest) => (rest, true),
+        None => (
+            trimmed_line
+                .strip_prefix(b"\\")
+                .filter(|rest| rest.starts_with(b"!"))
+                .unwrap_or(trimmed_line),
+            false,
+        ),
+    };
+
+    if trimmed_line.is_empty() {
+        return None;
+    }
+    Some((parse_exclude_rule(trimmed_line), negated))
+}
+
+fn parse_exclude_rule(trimmed_line: &[u8]) -> FileContent {
+    // Only a single extension can be checked against `Path::extension`;
+    // `*.tar.gz` is left to the glob.
+    if let Some(extension) = trimmed_line
+        .strip_prefix(b"*.")
+        .filter(|ext| !ext.contains(&b'/') && !ext.contains(&b'.') && !glob::has_glob_chars(ext))
+    {
+        return FileContent::Pattern(platform::os_str(extension).into_owned());
+    }
+
+    let path = platform::os_str(trimmed_line);
+    let path = Path::new(&path);
+    if path.is_absolute() && !glob::has_glob_chars(trimmed_line) {
+        FileContent::Path(path.to_path_buf())
+    } else {
+        FileContent::Glob(Glob::new(trimmed_line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // A directory of its own under the temp dir, removed once dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("du-rs-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn long_exclude_files_keep_multibyte_names_whole() {
+        let dir = TempDir::new("exclude-list");
+        let unicode = "/srv/données/ünïcödé/日本語";
+        // Lines of 20 bytes put the unicode path at offset 1020, so its
+        // characters cross the 1024-byte boundary.
+        let mut content = String::new();
+        for line in 0..10_000 {
+            if line == 51 {
+                content.push_str(unicode);
+            } else {
+                content.push_str(&format!("/excluded/{:09}", line));
+            }
+            content.push('\n');
+        }
+        assert_eq!(content.find(unicode), Some(1020));
+        let file = dir.0.join("exclude");
+        fs::write(&file, &content).unwrap();
+
+        let rules = exclude_list(&file);
+        assert_eq!(rules.len(), 10_000);
+        assert_eq!(
+            rules[51],
+            (FileContent::Path(PathBuf::from(unicode)), false)
+        );
+        assert_eq!(
+            rules[9_999],
+            (
+                FileContent::Path(PathBuf::from("/excluded/000009999")),
+                false
+            )
+        );
+
+        let exclusions = Exclusions::new(rules);
+        let absolute = |path: &'static str| move || (Vec::new(), PathBuf::from(path));
+        assert!(exclusions.excludes("日本語".as_bytes(), true, absolute(unicode)));
+        assert!(!exclusions.excludes(b"other", true, absolute("/srv/données/other")));
+    }
+
+    #[test]
+    fn negated_rules_re_include_what_earlier_rules_excluded() {
+        let exclusions = |file: &[u8]| Exclusions::new(read_exclude_list(file, Path::new("-")));
+        let excluded = |exclusions: &Exclusions, name: &str| {
+            exclusions.excludes(name.as_bytes(), false, || {
+                (name.into(), PathBuf::from("/scan").join(name))
+            })
+        };
+
+        let keep = exclusions(b"*.tmp\n!keep.tmp\n");
+        assert!(excluded(&keep, "scratch.tmp"));
+        assert!(!excluded(&keep, "keep.tmp"));
+        assert!(!excluded(&keep, "notes.txt"));
+
+        // The last matching rule wins, so an earlier `!` is overridden.
+        let overridden = exclusions(b"!keep.tmp\n*.tmp\n");
+        assert!(excluded(&overridden, "keep.tmp"));
+    }
+}
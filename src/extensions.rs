@@ -0,0 +1,64 @@
+use crate::platform;
+use fxhash::FxHashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Usage of files summed by their extension.
+#[derive(Debug, Default)]
+pub struct ExtensionSizes {
+    // Lowercased extension, `None` for names without one.
+    sizes: Mutex<FxHashMap<Option<Vec<u8>>, Count>>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Count {
+    files: u64,
+    size: i64,
+}
+
+/// The files of one extension in an [`ExtensionSizes`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionTotal {
+    pub extension: Option<Vec<u8>>,
+    pub files: u64,
+    pub size: i64,
+}
+
+impl ExtensionSizes {
+    /// Counts a file named `name`. As with `*.EXT` exclude rules, a leading
+    /// dot does not start an extension, so `.bashrc` has none.
+    pub fn record(&self, name: &[u8], size: i64) {
+        let extension = Path::new(&platform::os_str(name))
+            .extension()
+            .map(|ext| ext.as_encoded_bytes().to_ascii_lowercase());
+        if let Ok(mut sizes) = self.sizes.lock() {
+            let count = sizes.entry(extension).or_default();
+            count.files += 1;
+            count.size = count.size.saturating_add(size);
+        }
+    }
+
+    /// Every extension seen, largest total first.
+    pub fn totals(&self) -> Vec<ExtensionTotal> {
+        let mut totals: Vec<ExtensionTotal> = self
+            .sizes
+            .lock()
+            .map(|sizes| {
+                sizes
+                    .iter()
+                    .map(|(extension, count)| ExtensionTotal {
+                        extension: extension.clone(),
+                        files: count.files,
+                        size: count.size,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        totals.sort_by(|a, b| {
+            b.size
+                .cmp(&a.size)
+                .then_with(|| a.extension.cmp(&b.extension))
+        });
+        totals
+    }
+}
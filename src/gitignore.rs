@@ -0,0 +1,84 @@
+use crate::glob::{Anchor, Glob};
+
+struct Rule {
+    glob: Glob,
+    negated: bool,
+}
+
+pub struct Gitignore {
+    rules: Vec<Rule>,
+}
+
+impl Gitignore {
+    pub fn parse(content: &[u8]) -> Gitignore {
+        let mut rules = Vec::new();
+
+        // Names need not be UTF-8, so patterns are kept as the bytes written.
+        for line in content.split(|&b| b == b'\n') {
+            let line = line.trim_ascii_end();
+            if line.is_empty() || line.starts_with(b"#") {
+                continue;
+            }
+
+            let (line, negated) = match line.strip_prefix(b"!") {
+                Some(rest) => (rest, true),
+                None => (line.strip_prefix(b"\\").unwrap_or(line), false),
+            };
+
+            // A leading `/` anchors the pattern to the directory holding the
+            // `.gitignore`, same as a `/` anywhere else in the pattern.
+            let glob = match line.strip_prefix(b"/") {
+                Some(rest) => Glob::anchored(rest),
+                None => Glob::new(line),
+            };
+
+            rules.push(Rule { glob, negated });
+        }
+
+        Gitignore { rules }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // `relative` is the entry path relative to the directory holding this
+    // `.gitignore`. Returns the verdict of the last matching rule, if any.
+    pub fn matched(&self, name: &[u8], relative: &[u8], is_dir: bool) -> Option<bool> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| match rule.glob.anchor() {
+                Anchor::Basename => rule.glob.is_match(name, is_dir),
+                Anchor::Root | Anchor::Absolute => rule.glob.is_match(relative, is_dir),
+            })
+            .map(|rule| !rule.negated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_keep_names_that_are_not_utf8() {
+        let gitignore =
+            Gitignore::parse(b"# build output\r\ncache-\xff\r\n/out/\xe9t\xe9\n!keep\n");
+        assert_eq!(
+            gitignore.matched(b"cache-\xff", b"a/cache-\xff", false),
+            Some(true)
+        );
+        // Read lossily, \xff and \xfe would both have become U+FFFD.
+        assert_eq!(gitignore.matched(b"cache-\xfe", b"cache-\xfe", false), None);
+        assert_eq!(
+            gitignore.matched(b"\xe9t\xe9", b"out/\xe9t\xe9", true),
+            Some(true)
+        );
+        assert_eq!(
+            gitignore.matched(b"\xe8t\xe8", b"out/\xe8t\xe8", true),
+            None
+        );
+        assert_eq!(gitignore.matched(b"keep", b"keep", false), Some(false));
+    }
+}
@@ -0,0 +1,275 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Anchor {
+    Basename,
+    Root,
+    Absolute,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Glob {
+    items: Vec<Item>,
+    anchor: Anchor,
+    dir_only: bool,
+}
+
+// One step of a pattern, each matching a single byte but for the stars.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Item {
+    Byte(u8),
+    // `?`, any byte but `/`.
+    Any,
+    // `[...]`, members given as single bytes and `a-z` ranges.
+    Class { negated: bool, members: Vec<u8> },
+    // `*`, any run of bytes within one directory.
+    Star,
+    // `**`, any run of bytes. Followed by `/`, the two may also match nothing.
+    DoubleStar { whole_dirs: bool },
+}
+
+impl Glob {
+    // A trailing `/` restricts the pattern to directories. Patterns without a
+    // `/` match the basename at any depth, the rest anchor from the scan root.
+    pub fn new(line: &[u8]) -> Glob {
+        let dir_only = line.len() > 1 && line.ends_with(b"/");
+        let end = line.iter().rposition(|&c| c != b'/').map_or(0, |i| i + 1);
+        let line = &line[..end];
+
+        let (pattern, anchor) = if line.starts_with(b"/") {
+            (line, Anchor::Absolute)
+        } else if line.contains(&b'/') {
+            (line, Anchor::Root)
+        } else {
+            (line, Anchor::Basename)
+        };
+
+        Glob {
+            items: compile(pattern),
+            anchor,
+            dir_only,
+        }
+    }
+
+    /// Matches directories named `name`, which may hold wildcards, at any depth.
+    pub fn dir_name(name: &[u8]) -> Glob {
+        Glob {
+            anchor: Anchor::Basename,
+            dir_only: true,
+            ..Glob::new(name)
+        }
+    }
+
+    pub fn anchored(line: &[u8]) -> Glob {
+        Glob {
+            anchor: Anchor::Root,
+            ..Glob::new(line)
+        }
+    }
+
+    #[inline]
+    pub fn anchor(&self) -> Anchor {
+        self.anchor
+    }
+
+    pub fn is_match(&self, path: &[u8], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        glob_match(&self.items, path)
+    }
+}
+
+pub fn has_glob_chars(s: &[u8]) -> bool {
+    s.iter().any(|c| matches!(c, b'*' | b'?' | b'[' | b'\\'))
+}
+
+fn compile(pattern: &[u8]) -> Vec<Item> {
+    let mut items = Vec::with_capacity(pattern.len());
+    let mut i = 0;
+    while i < pattern.len() {
+        let (item, len) = match pattern[i] {
+            b'*' if pattern.get(i + 1) == Some(&b'*') => {
+                let whole_dirs = pattern.get(i + 2) == Some(&b'/');
+                (Item::DoubleStar { whole_dirs }, 2)
+            }
+            b'*' => (Item::Star, 1),
+            b'?' => (Item::Any, 1),
+            b'[' => match parse_class(&pattern[i + 1..]) {
+                Some((negated, members, class_len)) => (
+                    Item::Class {
+                        negated,
+                        members: members.to_vec(),
+                    },
+                    class_len + 1,
+                ),
+                None => (Item::Byte(b'['), 1),
+            },
+            b'\\' if i + 1 < pattern.len() => (Item::Byte(pattern[i + 1]), 2),
+            c => (Item::Byte(c), 1),
+        };
+        items.push(item);
+        i += len;
+    }
+    items
+}
+
+// Where matching can stand before an item: just reached, or inside a star
+// that has taken bytes already.
+const REACHED: u8 = 1;
+const IN_STAR: u8 = 2;
+
+// `*` and `?` never cross a `/`, `**` does and `**/` may match no directories.
+// Every way of matching is followed at once, a byte at a time, rather than
+// trying one and going back for the next, so no pattern takes longer than
+// its length times the text's.
+fn glob_match(items: &[Item], text: &[u8]) -> bool {
+    // How the text so far can end before each item, the last one standing
+    // for the end of the pattern.
+    let mut states = vec![0u8; items.len() + 1];
+    let mut next = vec![0u8; items.len() + 1];
+    states[0] = REACHED;
+    skip_stars(items, &mut states);
+
+    for &c in text {
+        next.fill(0);
+        for (i, item) in items.iter().enumerate() {
+            if states[i] == 0 {
+                continue;
+            }
+            let matched = match item {
+                Item::Star => {
+                    if c != b'/' {
+                        next[i] |= IN_STAR;
+                    }
+                    continue;
+                }
+                Item::DoubleStar { .. } => {
+                    next[i] |= IN_STAR;
+                    continue;
+                }
+                Item::Byte(b) => c == *b,
+                Item::Any => c != b'/',
+                Item::Class { negated, members } => {
+                    c != b'/' && class_matches(members, c) != *negated
+                }
+            };
+            if matched {
+                next[i + 1] |= REACHED;
+            }
+        }
+        skip_stars(items, &mut next);
+        std::mem::swap(&mut states, &mut next);
+        if states.iter().all(|&state| state == 0) {
+            return false;
+        }
+    }
+    states[items.len()] != 0
+}
+
+// Moves on past stars matching no more, and past the `/` after a `**` that
+// matches no directories at all.
+fn skip_stars(items: &[Item], states: &mut [u8]) {
+    for (i, item) in items.iter().enumerate() {
+        if states[i] == 0 {
+            continue;
+        }
+        match item {
+            Item::Star | Item::DoubleStar { .. } => states[i + 1] |= REACHED,
+            _ => continue,
+        }
+        if *item == (Item::DoubleStar { whole_dirs: true }) && states[i] & REACHED != 0 {
+            states[i + 2] |= REACHED;
+        }
+    }
+}
+
+// Parses the body of a `[...]` class, returning whether it is negated, its
+// members and the number of pattern bytes consumed including the closing `]`.
+fn parse_class(pattern: &[u8]) -> Option<(bool, &[u8], usize)> {
+    let negated = matches!(pattern.first(), Some(b'!') | Some(b'^'));
+    let start = usize::from(negated);
+
+    // A `]` directly after the opening bracket is a literal member.
+    let end = pattern[start..]
+        .iter()
+        .skip(1)
+        .position(|&c| c == b']')
+        .map(|p| start + p + 1)?;
+    Some((negated, &pattern[start..end], end + 1))
+}
+
+fn class_matches(members: &[u8], c: u8) -> bool {
+    let mut i = 0;
+    while i < members.len() {
+        if i + 2 < members.len() && members[i + 1] == b'-' {
+            if members[i] <= c && c <= members[i + 2] {
+                return true;
+            }
+            i += 3;
+        } else {
+            if members[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+        super::glob_match(&compile(pattern), text)
+    }
+
+    #[test]
+    fn stars_stay_within_a_directory() {
+        assert!(glob_match(b"*.log", b"app.log"));
+        assert!(glob_match(b"*", b""));
+        assert!(!glob_match(b"*.log", b"logs/app.log"));
+        assert!(glob_match(b"a*b*c", b"axxbyybc"));
+        assert!(!glob_match(b"a*b*c", b"axxbyyb"));
+        assert!(glob_match(b"src/*/mod.rs", b"src/walk/mod.rs"));
+        assert!(!glob_match(b"src/*/mod.rs", b"src/a/b/mod.rs"));
+        assert!(glob_match(b"a?c", b"abc"));
+        assert!(!glob_match(b"a?c", b"a/c"));
+    }
+
+    #[test]
+    fn double_stars_cross_directories() {
+        assert!(glob_match(b"a/**/b", b"a/b"));
+        assert!(glob_match(b"a/**/b", b"a/x/y/b"));
+        assert!(!glob_match(b"a/**/b", b"a/xb"));
+        assert!(glob_match(b"**/*.rs", b"src/walk/mod.rs"));
+        assert!(glob_match(b"**/*.rs", b"main.rs"));
+        assert!(glob_match(b"logs/**", b"logs/2024/01/app.log"));
+        assert!(glob_match(b"**.tmp", b"a/b/c.tmp"));
+        assert!(glob_match(b"a/**/x*/**/z", b"a/b/xy/c/d/z"));
+        assert!(!glob_match(b"a/**/x*/**/z", b"a/b/y/c/d/z"));
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        assert!(glob_match(b"[abc].txt", b"b.txt"));
+        assert!(!glob_match(b"[!abc].txt", b"b.txt"));
+        assert!(glob_match(b"file[0-9]", b"file7"));
+        assert!(glob_match(b"[]x]", b"]"));
+        assert!(glob_match(b"a[b", b"a[b"));
+        assert!(glob_match(b"\\*.log", b"*.log"));
+        assert!(!glob_match(b"\\*.log", b"app.log"));
+        assert!(glob_match(b"end\\", b"end\\"));
+    }
+
+    #[test]
+    fn many_stars_take_linear_time() {
+        let name = [b'a'; 4096];
+        let pattern = b"*a*a*a*a*a*a*a*a*a*a*a*a*b";
+        assert!(!glob_match(pattern, &name));
+        assert!(!glob_match(b"**a**a**a**a**a**a**a**b", &name));
+        let mut path = b"a/".repeat(2000);
+        path.push(b'b');
+        assert!(glob_match(b"**/a/**/a/**/a/**/b", &path));
+        assert!(!glob_match(b"**/a/**/a/**/a/**/c", &path));
+    }
+}
@@ -0,0 +1,55 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Empty files, then files under 1K, then bins four times wider each up to
+// 1T, and everything larger.
+const BINS: usize = 18;
+
+/// Counts of files by size, in log-scale bins.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    files: [AtomicU64; BINS],
+    bytes: [AtomicU64; BINS],
+}
+
+/// One bin of a [`Histogram`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bin {
+    /// Sizes in the bin are below this many bytes; `None` for the last bin.
+    pub below: Option<u64>,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl Histogram {
+    /// Counts a file of `bytes` bytes.
+    pub fn record(&self, bytes: u64) {
+        let bin = Self::bin_of(bytes);
+        self.files[bin].fetch_add(1, Ordering::Relaxed);
+        self.bytes[bin].fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Every bin, smallest sizes first.
+    pub fn bins(&self) -> Vec<Bin> {
+        (0..BINS)
+            .map(|bin| Bin {
+                below: Self::upper_bound(bin),
+                files: self.files[bin].load(Ordering::Relaxed),
+                bytes: self.bytes[bin].load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    fn upper_bound(bin: usize) -> Option<u64> {
+        match bin {
+            0 => Some(1),
+            _ if bin < BINS - 1 => Some(1024 << (2 * (bin - 1))),
+            _ => None,
+        }
+    }
+
+    fn bin_of(bytes: u64) -> usize {
+        (0..BINS)
+            .find(|&bin| Self::upper_bound(bin).is_none_or(|below| bytes < below))
+            .unwrap_or(BINS - 1)
+    }
+}
@@ -0,0 +1,989 @@
+//! Disk usage scanning behind the `du-rs` command.
+//!
+//! [`scan`] walks a directory tree and returns one [`Entry`] per reported
+//! path, with the root last:
+//!
+//! ```
+//! use du_rs::{scan, ScanOptions, SizeFormat};
+//!
+//! let root = std::env::temp_dir().join(format!("du-rs-doctest-{}", std::process::id()));
+//! std::fs::create_dir_all(root.join("sub"))?;
+//! std::fs::write(root.join("sub/file"), b"hello")?;
+//!
+//! let options = ScanOptions {
+//!     all: true,
+//!     size_format: SizeFormat::Apparent,
+//!     ..ScanOptions::default()
+//! };
+//! let entries = scan(&root, options);
+//! std::fs::remove_dir_all(&root)?;
+//! let entries = entries?;
+//!
+//! let file = entries.iter().find(|e| e.path.ends_with("sub/file")).unwrap();
+//! assert_eq!(file.size, 5);
+//! assert!(!file.is_dir);
+//! assert_eq!(entries.last().unwrap().path, root);
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+pub mod cache;
+pub mod dedup;
+pub mod depth;
+pub mod diff;
+pub mod exclude;
+pub mod extensions;
+pub mod gitignore;
+pub mod glob;
+pub mod histogram;
+mod mounts;
+mod parallel;
+pub mod platform;
+pub mod timestamp;
+pub mod units;
+mod walk;
+
+use cache::DirCache;
+use dedup::DuplicateFinder;
+use depth::DepthSizes;
+use exclude::{exclude_list, parse_exclude_line, Exclusions, FileContent};
+use extensions::ExtensionSizes;
+use fxhash::FxHashSet;
+use glob::Glob;
+use histogram::Histogram;
+use mounts::MountPoints;
+use platform::Errno;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use timestamp::{TimeKind, Timestamp};
+use walk::{
+    record_depth, report_error, unreadable_dir_usage, walk_dir, TraversalConfig, TraversalState,
+};
+
+pub use walk::mtime_of;
+
+pub type Cresult<T> = anyhow::Result<T, anyhow::Error>;
+
+/// Bytes in one unit of `st_blocks`. POSIX leaves the unit open, but Linux,
+/// macOS and the BSDs all count 512-byte blocks whatever the file system, APFS
+/// and HFS+ included. `st_blksize` is the preferred I/O size and says nothing
+/// about how much space a file takes.
+pub const ST_BLOCK_SIZE: i64 = 512;
+
+pub struct FileStats {
+    pub size: i64,
+    /// `st_blocks`, in units of [`ST_BLOCK_SIZE`].
+    pub blocks: i64,
+}
+
+impl FileStats {
+    #[inline]
+    fn size_in_bytes(&self) -> i64 {
+        self.size
+    }
+    #[inline]
+    fn disk_usage_blocks(&self) -> i64 {
+        self.blocks.saturating_mul(ST_BLOCK_SIZE) / 1024
+    }
+    #[inline]
+    fn disk_usage_bytes(&self) -> i64 {
+        self.blocks.saturating_mul(ST_BLOCK_SIZE)
+    }
+
+    /// Whether less than half of the apparent size is allocated on disk.
+    #[inline]
+    pub fn is_sparse(&self) -> bool {
+        self.disk_usage_bytes() < self.size / 2
+    }
+}
+
+#[derive(Debug, Clone)]
+#[repr(u8)]
+pub enum SizeFormat {
+    Bytes,
+    HumanReadable,
+    Blocks,
+    Apparent,
+}
+
+impl SizeFormat {
+    pub fn get_dir_size(&self, stats: &FileStats) -> i64 {
+        match self {
+            SizeFormat::Bytes => stats.size_in_bytes(),
+            SizeFormat::HumanReadable => stats.disk_usage_bytes(),
+            SizeFormat::Blocks => stats.disk_usage_blocks(),
+            SizeFormat::Apparent => stats.size_in_bytes(),
+        }
+    }
+
+    pub fn get_file_size(&self, stats: &FileStats) -> i64 {
+        match self {
+            SizeFormat::Bytes => stats.size_in_bytes(),
+            SizeFormat::HumanReadable => stats.disk_usage_bytes(),
+            SizeFormat::Blocks => stats.disk_usage_blocks(),
+            SizeFormat::Apparent => stats.size_in_bytes(),
+        }
+    }
+
+    /// What [`SizeFormat::get_file_size`] counts, in bytes.
+    pub fn get_file_bytes(&self, stats: &FileStats) -> i64 {
+        match self {
+            SizeFormat::Blocks => stats.disk_usage_bytes(),
+            _ => self.get_file_size(stats),
+        }
+    }
+}
+
+/// Live counters of a running walk.
+#[derive(Debug, Default)]
+pub struct Progress {
+    entries: AtomicU64,
+    current_dir: Mutex<Vec<u8>>,
+}
+
+impl Progress {
+    /// Directory entries looked at so far.
+    pub fn entries(&self) -> u64 {
+        self.entries.load(Ordering::Relaxed)
+    }
+
+    /// The directory most recently entered.
+    pub fn current_dir(&self) -> Vec<u8> {
+        self.current_dir
+            .lock()
+            .map(|dir| dir.clone())
+            .unwrap_or_default()
+    }
+
+    #[inline]
+    pub(crate) fn add_entry(&self) {
+        self.entries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Skipped when a reader holds the lock; the next directory will do.
+    pub(crate) fn enter_dir(&self, path: &[u8]) {
+        if let Ok(mut current) = self.current_dir.try_lock() {
+            current.clear();
+            current.extend_from_slice(path);
+        }
+    }
+}
+
+/// Totals of a walk, for reporting once it is done.
+#[derive(Debug, Default)]
+pub struct ScanStats {
+    dirs: AtomicU64,
+    files: AtomicU64,
+    excluded: AtomicU64,
+}
+
+impl ScanStats {
+    /// Directories read, the roots included.
+    pub fn dirs(&self) -> u64 {
+        self.dirs.load(Ordering::Relaxed)
+    }
+
+    /// Files and other entries that are not directories.
+    pub fn files(&self) -> u64 {
+        self.files.load(Ordering::Relaxed)
+    }
+
+    /// Entries skipped by exclude patterns, `.gitignore` rules or cache tags.
+    pub fn excluded(&self) -> u64 {
+        self.excluded.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub(crate) fn add_dir(&self) {
+        self.dirs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn add_file(&self) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn add_excluded(&self) {
+        self.excluded.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Caps how many directory entries a walk looks at, across every root it is
+/// given. Once they are used up, directories are left with what has been
+/// counted in them so far, and the totals above them stay consistent.
+#[derive(Debug)]
+pub struct EntryLimit {
+    max: u64,
+    entries: AtomicU64,
+    reached: AtomicBool,
+}
+
+impl EntryLimit {
+    pub fn new(max: u64) -> EntryLimit {
+        EntryLimit {
+            max,
+            entries: AtomicU64::new(0),
+            reached: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether an entry was left out for lack of room, so the sizes fall
+    /// short of what is on disk.
+    pub fn reached(&self) -> bool {
+        self.reached.load(Ordering::Relaxed)
+    }
+
+    // Claims room for one more entry.
+    #[inline]
+    pub(crate) fn take(&self) -> bool {
+        if self.entries.fetch_add(1, Ordering::Relaxed) < self.max {
+            true
+        } else {
+            self.reached.store(true, Ordering::Relaxed);
+            false
+        }
+    }
+}
+
+/// Which symlinks a scan follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dereference {
+    Never,
+    /// Only a symlink given as the root.
+    Roots,
+    Always,
+}
+
+/// Controls what [`scan`] and [`walk`] count and report.
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// Deepest level to report, the root being 0; deeper entries still count
+    /// towards their parents. `None` reports every level.
+    pub max_depth: Option<i32>,
+    /// Skip directories on a different device than the root and, where
+    /// `/proc/self/mountinfo` can be read, any mount point below it.
+    pub one_file_system: bool,
+    /// Files with one exclude pattern per line. Rules from later files come
+    /// after those of earlier ones, so they win when both match.
+    pub exclude_from: Vec<PathBuf>,
+    /// Inline exclude patterns, same syntax as `exclude_from` lines.
+    pub exclude: Vec<String>,
+    /// Names of directories to skip, never matching files of the same name.
+    pub exclude_dirs: Vec<String>,
+    /// Skip entries matched by `.gitignore` files found along the way.
+    pub gitignore: bool,
+    /// Skip the contents of directories tagged with a `CACHEDIR.TAG`, except
+    /// the tag itself.
+    pub exclude_caches: bool,
+    pub dereference: Dereference,
+    /// Count every hard link instead of each inode once.
+    pub count_links: bool,
+    pub size_format: SizeFormat,
+    /// Report files as well as directories.
+    pub all: bool,
+    /// Only report the root.
+    pub summarize: bool,
+    /// Report directory sizes without their subdirectories.
+    pub separate_dirs: bool,
+    /// Smallest size to report, in `size_format` units.
+    pub threshold: i64,
+    /// Largest size to report, in `size_format` units.
+    pub max_size: Option<i64>,
+    /// Files smaller than this, in `size_format` units, are left out of every
+    /// total rather than only not reported.
+    pub exclude_smaller_than: Option<i64>,
+    /// Files larger than this, in `size_format` units, are left out of every
+    /// total rather than only not reported.
+    pub exclude_larger_than: Option<i64>,
+    /// Worker threads for the traversal; `0` picks one per CPU and `1` walks
+    /// sequentially. Either way entries are reported in the same order, as
+    /// soon as they are sized. With more than one, which path a hard-linked
+    /// file is counted under may vary.
+    pub threads: usize,
+    /// Updated as the walk goes, for showing progress while it runs.
+    pub progress: Option<Arc<Progress>>,
+    /// Counts what the walk looked at, across every root it is given.
+    pub stats: Option<Arc<ScanStats>>,
+    /// Files counted by size, across every root the walk is given.
+    pub histogram: Option<Arc<Histogram>>,
+    /// Collects every counted file to look for duplicates among afterwards.
+    pub duplicates: Option<Arc<DuplicateFinder>>,
+    /// Usage by depth below the root, across every root the walk is given.
+    pub depth_sizes: Option<Arc<DepthSizes>>,
+    /// Usage by file extension, across every root the walk is given.
+    pub extensions: Option<Arc<ExtensionSizes>>,
+    /// Stop looking at entries once this many have been.
+    pub entry_limit: Option<Arc<EntryLimit>>,
+    /// Only report directories holding at least this many direct entries,
+    /// with that count as their size.
+    pub entries_threshold: Option<u64>,
+    /// Directories from an earlier scan to reuse where unchanged, also
+    /// holding those of this one once it is done. Scans with a cache walk
+    /// sequentially, and reuse nothing when asked for anything that looks
+    /// at each file, such as `all` or `histogram`.
+    pub cache: Option<Arc<DirCache>>,
+    /// Report what lies below each directory at `max_depth` as a single
+    /// `(deeper)` entry inside it.
+    pub collapse: bool,
+    /// Only files modified after this are counted, and only directories
+    /// holding at least one of them are reported.
+    pub newer: Option<Timestamp>,
+    /// Hand sparse files to [`Visitor::sparse`] as they are counted.
+    pub warn_sparse: bool,
+    /// Hand entries that are left out to [`Visitor::skipped`].
+    pub report_skipped: bool,
+    /// Timestamp that entries are dated by.
+    pub time: TimeKind,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            max_depth: None,
+            one_file_system: false,
+            exclude_from: Vec::new(),
+            exclude: Vec::new(),
+            exclude_dirs: Vec::new(),
+            gitignore: false,
+            exclude_caches: false,
+            dereference: Dereference::Never,
+            count_links: false,
+            size_format: SizeFormat::Blocks,
+            all: false,
+            summarize: false,
+            separate_dirs: false,
+            threshold: 0,
+            max_size: None,
+            exclude_smaller_than: None,
+            exclude_larger_than: None,
+            threads: 1,
+            progress: None,
+            stats: None,
+            histogram: None,
+            duplicates: None,
+            depth_sizes: None,
+            extensions: None,
+            entry_limit: None,
+            entries_threshold: None,
+            cache: None,
+            collapse: false,
+            newer: None,
+            warn_sparse: false,
+            report_skipped: false,
+            time: TimeKind::Modified,
+        }
+    }
+}
+
+impl ScanOptions {
+    /// Whether a symlink given as a path to scan is followed.
+    pub fn follows_roots(&self) -> bool {
+        self.dereference != Dereference::Never
+    }
+
+    /// Whether a file of `size` last modified at `mtime` counts at all, see
+    /// [`ScanOptions::exclude_larger_than`] and [`ScanOptions::newer`].
+    pub fn counts_file(&self, size: i64, mtime: Timestamp) -> bool {
+        self.exclude_smaller_than.is_none_or(|min| size >= min)
+            && self.exclude_larger_than.is_none_or(|max| size <= max)
+            && self.newer.is_none_or(|newer| mtime > newer)
+    }
+
+    /// Whether an entry of `size` is printed, see [`ScanOptions::threshold`]
+    /// and [`ScanOptions::max_size`].
+    pub fn shows_size(&self, size: i64) -> bool {
+        size >= self.threshold && self.max_size.is_none_or(|max| size <= max)
+    }
+
+    fn thread_count(&self) -> usize {
+        match self.threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub size: i64,
+    pub is_dir: bool,
+}
+
+/// An entry reported during a [`walk`], borrowed from the traversal.
+pub struct Visit<'a> {
+    pub path: &'a [u8],
+    pub size: i64,
+    /// Latest time of the entry and everything below it, of the kind picked
+    /// by [`ScanOptions::time`].
+    pub mtime: Timestamp,
+    pub is_dir: bool,
+    /// Levels below the root, the root's own entries being at 1.
+    pub depth: i32,
+    /// Inodes counted at and below the entry, 1 for anything but a directory.
+    pub inodes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    ReadDir,
+    Stat,
+    /// A followed symbolic link leads back to a directory being walked.
+    Loop,
+}
+
+/// A path the walk could not account for; the walk carries on without it.
+#[derive(Debug)]
+pub struct WalkError<'a> {
+    pub path: &'a [u8],
+    pub operation: Operation,
+    pub errno: Errno,
+}
+
+impl fmt::Display for WalkError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.operation {
+            Operation::ReadDir => "cannot read directory",
+            Operation::Stat => "cannot access",
+            Operation::Loop => {
+                return write!(
+                    f,
+                    "detected symlink loop at '{}'",
+                    String::from_utf8_lossy(self.path)
+                );
+            }
+        };
+        write!(
+            f,
+            "{} '{}': {}",
+            action,
+            String::from_utf8_lossy(self.path),
+            self.errno.desc()
+        )
+    }
+}
+
+/// Why an entry was left out of the totals or of the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Matched an exclude rule or `.gitignore`, or lies in a cache directory.
+    Excluded,
+    /// What is below a directory on another file system, with
+    /// `one_file_system`. The directory itself is counted.
+    OtherFileSystem,
+    /// A hard link, or under `-L` a link target, whose inode was already
+    /// counted.
+    AlreadyCounted,
+    /// A file outside the `exclude_smaller_than`, `exclude_larger_than` or
+    /// `newer` limits.
+    Filtered,
+    /// Counted, but not reported for being outside `threshold` and
+    /// `max_size`.
+    Threshold,
+}
+
+/// An entry the walk passed over, with [`ScanOptions::report_skipped`].
+#[derive(Debug)]
+pub struct Skipped<'a> {
+    pub path: &'a [u8],
+    pub reason: SkipReason,
+}
+
+impl fmt::Display for Skipped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.reason {
+            SkipReason::Excluded => "excluded",
+            SkipReason::OtherFileSystem => "contents on another file system",
+            SkipReason::AlreadyCounted => "already counted",
+            SkipReason::Filtered => "outside the file size or time limits",
+            SkipReason::Threshold => "counted but outside the size threshold",
+        };
+        write!(
+            f,
+            "skipped '{}': {}",
+            String::from_utf8_lossy(self.path),
+            reason
+        )
+    }
+}
+
+/// A regular file using much less disk space than its apparent size, found
+/// with [`ScanOptions::warn_sparse`].
+#[derive(Debug)]
+pub struct SparseFile<'a> {
+    pub path: &'a [u8],
+    /// Apparent size in bytes.
+    pub size: i64,
+    /// Bytes allocated on disk.
+    pub allocated: i64,
+}
+
+pub trait Visitor {
+    fn visit(&mut self, entry: Visit<'_>) -> Cresult<()>;
+
+    /// Called for every path that could not be read. Ignored by default.
+    fn error(&mut self, error: WalkError<'_>) -> Cresult<()> {
+        let _ = error;
+        Ok(())
+    }
+
+    /// Called for every sparse file counted. Ignored by default.
+    fn sparse(&mut self, file: SparseFile<'_>) -> Cresult<()> {
+        let _ = file;
+        Ok(())
+    }
+
+    /// Called for every entry left out. Ignored by default.
+    fn skipped(&mut self, skipped: Skipped<'_>) -> Cresult<()> {
+        let _ = skipped;
+        Ok(())
+    }
+}
+
+/// Writes each entry as a `size<TAB>path` line, the plain `du` layout, to any
+/// writer, such as a file or a `Vec<u8>` to capture the output in:
+///
+/// ```
+/// use du_rs::{walk, ScanOptions, SizeFormat, WriteVisitor};
+///
+/// let root = std::env::temp_dir().join(format!("du-rs-write-visitor-{}", std::process::id()));
+/// std::fs::create_dir_all(root.join("sub"))?;
+/// std::fs::write(root.join("sub/file"), b"hello")?;
+///
+/// let options = ScanOptions {
+///     all: true,
+///     size_format: SizeFormat::Bytes,
+///     ..ScanOptions::default()
+/// };
+/// let mut visitor = WriteVisitor::new(Vec::new());
+/// let walked = walk(&root, &options, &mut visitor);
+/// // A directory counts its own size along with its files'.
+/// let sub = std::fs::metadata(root.join("sub"))?.len() + 5;
+/// std::fs::remove_dir_all(&root)?;
+/// walked?;
+///
+/// let expected = format!("5\t{0}/sub/file\n{1}\t{0}/sub\n", root.display(), sub);
+/// assert_eq!(String::from_utf8(visitor.into_inner())?, expected);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct WriteVisitor<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> WriteVisitor<W> {
+    pub fn new(writer: W) -> WriteVisitor<W> {
+        WriteVisitor { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> Visitor for WriteVisitor<W> {
+    fn visit(&mut self, entry: Visit<'_>) -> Cresult<()> {
+        let mut buffer = itoa::Buffer::new();
+        self.writer
+            .write_all(buffer.format(entry.size).as_bytes())?;
+        self.writer.write_all(b"\t")?;
+        self.writer.write_all(entry.path)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+impl Visitor for Vec<Entry> {
+    fn visit(&mut self, entry: Visit<'_>) -> Cresult<()> {
+        self.push(Entry {
+            path: PathBuf::from(platform::os_str(entry.path).into_owned()),
+            size: entry.size,
+            is_dir: entry.is_dir,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DirUsage {
+    // Everything below the directory, including itself.
+    pub total: i64,
+    // The directory inode plus its direct non-directory entries.
+    pub own: i64,
+    // Latest time of the directory and everything below it, of the kind
+    // picked by `ScanOptions::time`.
+    pub mtime: Timestamp,
+    // Inodes counted below the directory, including itself.
+    pub inodes: u64,
+    // Non-directory entries counted below the directory.
+    pub files: u64,
+    // Their combined size.
+    pub files_size: i64,
+    // Entries read from the directory itself, not summed from subdirectories.
+    pub entries: u64,
+}
+
+impl DirUsage {
+    // Sizes add up to at most `i64::MAX` rather than wrapping negative, which
+    // a scan could only reach with apparent sizes summed over many hard
+    // links under `-l`.
+    #[inline]
+    pub(crate) fn add(&mut self, size: i64) {
+        self.total = self.total.saturating_add(size);
+        self.own = self.own.saturating_add(size);
+    }
+
+    // Counts a subdirectory below this one, which leaves `own` as it is.
+    #[inline]
+    pub(crate) fn add_subdir(&mut self, subdir: &DirUsage) {
+        self.total = self.total.saturating_add(subdir.total);
+        self.inodes += subdir.inodes;
+        self.files += subdir.files;
+        self.files_size = self.files_size.saturating_add(subdir.files_size);
+        self.mtime = self.mtime.max(subdir.mtime);
+    }
+
+    #[inline]
+    pub fn shown(&self, separate_dirs: bool) -> i64 {
+        if separate_dirs {
+            self.own
+        } else {
+            self.total
+        }
+    }
+}
+
+/// Scans `root` and collects every reported entry, followed by the root itself.
+/// Unreadable paths are skipped silently; use [`walk`] to be told about them.
+pub fn scan(root: &Path, options: ScanOptions) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let usage = walk(root, &options, &mut entries).map_err(io::Error::other)?;
+
+    entries.push(Entry {
+        path: root.to_path_buf(),
+        size: usage.shown(options.separate_dirs),
+        is_dir: true,
+    });
+    Ok(entries)
+}
+
+/// Walks the directory `root`, handing each reported entry to `visitor` as
+/// soon as its size is known. Reported paths are prefixed with `root` as given.
+pub fn walk<V: Visitor>(root: &Path, options: &ScanOptions, visitor: &mut V) -> Cresult<DirUsage> {
+    Scanner::new(options.clone()).walk(root, visitor)
+}
+
+/// Walks any number of roots, counting a hard-linked file only once across
+/// all of them, as a single `du` invocation does.
+pub struct Scanner {
+    options: ScanOptions,
+    seen_inodes: FxHashSet<(u64, u64)>,
+}
+
+impl Scanner {
+    pub fn new(options: ScanOptions) -> Scanner {
+        Scanner {
+            options,
+            seen_inodes: FxHashSet::with_capacity_and_hasher(1024, Default::default()),
+        }
+    }
+
+    pub fn options(&self) -> &ScanOptions {
+        &self.options
+    }
+
+    /// Whether a file with more than one link should be counted: always with
+    /// `count_links`, otherwise only the first time its inode is seen.
+    pub fn count_link(&mut self, dev: u64, ino: u64) -> bool {
+        self.options.count_links || self.seen_inodes.insert((dev, ino))
+    }
+
+    /// Same as [`walk`], sharing hard links seen with earlier calls.
+    pub fn walk<V: Visitor>(&mut self, root: &Path, visitor: &mut V) -> Cresult<DirUsage> {
+        let options = &self.options;
+        let root_dev = if options.one_file_system {
+            platform::stat_at(None, root.as_os_str(), true)
+                .ok()
+                .map(|meta| meta.dev)
+        } else {
+            None
+        };
+        let mount_points = options
+            .one_file_system
+            .then(|| MountPoints::below(root))
+            .flatten();
+
+        let exclusions = (!options.exclude_from.is_empty()
+            || !options.exclude.is_empty()
+            || !options.exclude_dirs.is_empty())
+        .then(|| {
+            let mut rules: Vec<_> = options
+                .exclude_from
+                .iter()
+                .flat_map(|file| exclude_list(file))
+                .collect();
+            rules.extend(
+                options
+                    .exclude
+                    .iter()
+                    .filter_map(|p| parse_exclude_line(p.as_bytes())),
+            );
+            rules.extend(
+                options
+                    .exclude_dirs
+                    .iter()
+                    .map(|name| (FileContent::Glob(Glob::dir_name(name.as_bytes())), false)),
+            );
+            Exclusions::new(rules)
+        });
+
+        let mut path_bytes = Vec::with_capacity(4096);
+        path_bytes.extend_from_slice(root.as_os_str().as_encoded_bytes());
+
+        let root_abs = std::env::current_dir()?.join(root).components().collect();
+
+        let config = TraversalConfig {
+            max_depth: options.max_depth,
+            root_dev,
+            mount_points,
+            exclusions,
+            root_len: path_bytes.len(),
+            root_abs,
+            summarize: options.summarize,
+            collapse: options.collapse,
+            list_files: options.all,
+            threshold_size: options.threshold,
+            max_size: options.max_size,
+            exclude_smaller_than: options.exclude_smaller_than,
+            exclude_larger_than: options.exclude_larger_than,
+            newer: options.newer,
+            count_hard_link: options.count_links,
+            size_format: options.size_format.clone(),
+            follow_links: options.dereference == Dereference::Always,
+            follow_roots: options.follows_roots(),
+            gitignore: options.gitignore,
+            exclude_caches: options.exclude_caches,
+            progress: options.progress.clone(),
+            stats: options.stats.clone(),
+            histogram: options.histogram.clone(),
+            duplicates: options.duplicates.clone(),
+            depth_sizes: options.depth_sizes.clone(),
+            extensions: options.extensions.clone(),
+            entry_limit: options.entry_limit.clone(),
+            entries_threshold: options.entries_threshold,
+            cache: options.cache.clone(),
+            warn_sparse: options.warn_sparse,
+            report_skipped: options.report_skipped,
+            time: options.time,
+            separate_dirs: options.separate_dirs,
+        };
+
+        let threads = options.thread_count();
+        if threads > 1 && options.cache.is_none() {
+            return parallel::walk(path_bytes, &config, threads, &mut self.seen_inodes, visitor);
+        }
+
+        let dir = match platform::open_dir(None, root.as_os_str(), config.follow_roots) {
+            Ok(dir) => dir,
+            Err(errno) => {
+                report_error(visitor, &mut path_bytes, None, Operation::ReadDir, errno)?;
+                let usage =
+                    unreadable_dir_usage(None, root.as_os_str(), &config).unwrap_or_default();
+                record_depth(&config, 0, &usage);
+                return Ok(usage);
+            }
+        };
+
+        let mut state = TraversalState {
+            seen_inodes: std::mem::take(&mut self.seen_inodes),
+            path_bytes,
+            gitignores: Vec::new(),
+            ancestors: Vec::new(),
+        };
+
+        let usage = walk_dir(dir, &config, visitor, &mut state);
+        self.seen_inodes = state.seen_inodes;
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    #[cfg(unix)]
+    use std::fs::File;
+    #[cfg(unix)]
+    use std::os::fd::AsRawFd;
+    #[cfg(unix)]
+    use std::os::unix::fs::MetadataExt;
+
+    // A directory of its own under the temp dir, removed once dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("du-rs-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // What `WriteVisitor` prints for `root`, and the root's usage.
+    fn lines(root: &Path, options: &ScanOptions) -> (String, DirUsage) {
+        let mut visitor = WriteVisitor::new(Vec::new());
+        let usage = walk(root, options, &mut visitor).unwrap();
+        (String::from_utf8(visitor.into_inner()).unwrap(), usage)
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn apparent_and_allocated_sizes_of_a_sparse_file() {
+        let dir = TempDir::new("sparse");
+        let path = dir.0.join("sparse");
+        // `set_len` is an ftruncate, which allocates nothing.
+        File::create(&path).unwrap().set_len(1 << 24).unwrap();
+        let allocated = fs::metadata(&path).unwrap().blocks() as i64 * ST_BLOCK_SIZE;
+        assert!(allocated < 1 << 24);
+
+        let entry = |size_format| {
+            let options = ScanOptions {
+                all: true,
+                size_format,
+                ..ScanOptions::default()
+            };
+            let (printed, _) = lines(&dir.0, &options);
+            printed.lines().next().unwrap().to_string()
+        };
+        let line = |size: i64| format!("{}\t{}", size, path.display());
+        assert_eq!(entry(SizeFormat::Apparent), line(1 << 24));
+        assert_eq!(entry(SizeFormat::HumanReadable), line(allocated));
+        assert_eq!(entry(SizeFormat::Blocks), line(allocated / 1024));
+    }
+
+    #[test]
+    fn hard_links_are_counted_once() {
+        let dir = TempDir::new("hard-links");
+        let sub = dir.0.join("sub");
+        fs::create_dir_all(sub.join("nested")).unwrap();
+        fs::write(sub.join("file"), vec![b'x'; 8000]).unwrap();
+        fs::hard_link(sub.join("file"), sub.join("link")).unwrap();
+        fs::hard_link(sub.join("file"), sub.join("nested/link")).unwrap();
+        let dir_size = |path: &Path| fs::metadata(path).unwrap().len() as i64;
+
+        for threads in [1, 4] {
+            let options = ScanOptions {
+                size_format: SizeFormat::Bytes,
+                threads,
+                ..ScanOptions::default()
+            };
+            let (printed, usage) = lines(&dir.0, &options);
+            // Directories, which have several links of their own, all count.
+            let nested = dir_size(&sub.join("nested"));
+            let sub_total = dir_size(&sub) + nested + 8000;
+            assert_eq!(
+                printed,
+                format!(
+                    "{}\t{}/sub/nested\n{}\t{}/sub\n",
+                    nested,
+                    dir.0.display(),
+                    sub_total,
+                    dir.0.display()
+                )
+            );
+            assert_eq!(usage.total, dir_size(&dir.0) + sub_total);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn trees_deeper_than_the_stack_and_path_max() {
+        use nix::fcntl::{openat, OFlag};
+        use nix::sys::stat::{mkdirat, Mode};
+        use std::os::fd::{FromRawFd, OwnedFd};
+
+        const DEPTH: usize = 5000;
+        let dir = TempDir::new("deep");
+        // The full path is far past PATH_MAX, so each level is made from the
+        // one above it.
+        let mut parent = File::open(&dir.0).unwrap();
+        for _ in 0..DEPTH {
+            mkdirat(Some(parent.as_raw_fd()), "d", Mode::S_IRWXU).unwrap();
+            let fd = openat(
+                Some(parent.as_raw_fd()),
+                "d",
+                OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+                Mode::empty(),
+            )
+            .unwrap();
+            parent = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+        drop(parent);
+
+        for threads in [1, 4] {
+            let options = ScanOptions {
+                threads,
+                ..ScanOptions::default()
+            };
+            let (printed, usage) = lines(&dir.0, &options);
+            let deepest = format!("{}{}", dir.0.display(), "/d".repeat(DEPTH));
+            assert_eq!(printed.lines().count(), DEPTH);
+            assert!(printed.starts_with(&format!("4\t{}\n", deepest)));
+            assert_eq!(usage.inodes, DEPTH as u64 + 1);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn deep_trees_under_a_low_descriptor_limit() {
+        // The limit is process-wide, so it is lowered in a child running
+        // just this test.
+        const CHILD: &str = "DU_RS_TEST_LOW_NOFILE";
+        if std::env::var_os(CHILD).is_none() {
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "tests::deep_trees_under_a_low_descriptor_limit"])
+                .env(CHILD, "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let dir = TempDir::new("nofile");
+        let mut path = dir.0.clone();
+        for level in 0..100 {
+            path.push(format!("level{}", level));
+            fs::create_dir(&path).unwrap();
+            fs::create_dir(path.with_file_name(format!("side{}", level))).unwrap();
+            fs::write(path.with_file_name(format!("file{}", level)), [0; 100]).unwrap();
+        }
+
+        let walks = || {
+            [1, 4].map(|threads| {
+                let options = ScanOptions {
+                    threads,
+                    size_format: SizeFormat::Bytes,
+                    ..ScanOptions::default()
+                };
+                let (printed, usage) = lines(&dir.0, &options);
+                (printed, usage.total, usage.inodes)
+            })
+        };
+        let unlimited = walks();
+        let limit = libc::rlimit {
+            rlim_cur: 24,
+            rlim_max: 24,
+        };
+        assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+        assert_eq!(walks(), unlimited);
+    }
+}
@@ -1,156 +1,492 @@
-use fxhash::FxHashSet;
-use nix::dir::Dir;
-use nix::fcntl::open;
-use nix::fcntl::openat;
-use nix::fcntl::AtFlags;
-use nix::sys::stat::{self, fstatat};
-use nix::{fcntl::OFlag, sys::stat::Mode};
-use std::ffi::{OsStr, OsString};
-use std::io::stdout;
-use std::io::{BufWriter, Write};
-use std::os::unix::ffi::OsStrExt;
+use du_rs::platform::{self, Errno, Metadata};
+use du_rs::timestamp::{TimeKind, TimeStyle, Timestamp};
+use du_rs::units::{
+    block_size_bytes, format_size, get_file_sizes, parse_size_to_bytes, Rounding, UNITS,
+};
+use du_rs::{
+    cache::DirCache,
+    dedup::{DuplicateFinder, DuplicateGroup},
+    depth::DepthSizes,
+    diff::SavedScan,
+    extensions::ExtensionSizes,
+    glob::{Anchor, Glob},
+    histogram::Histogram,
+    mtime_of, Cresult, Dereference, EntryLimit, FileStats, Progress, ScanOptions, ScanStats,
+    Scanner, SizeFormat, SkipReason, Skipped, SparseFile, Visit, Visitor, WalkError, ST_BLOCK_SIZE,
+};
+use flate2::write::GzEncoder;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, stderr, stdout};
+use std::io::{BufReader, BufWriter, IsTerminal, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BinaryHeap, HashMap},
     env,
-    os::fd::RawFd,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     process::exit,
 };
 
-type Cresult<T> = anyhow::Result<T, anyhow::Error>;
-use anyhow::{Context, Error};
-struct FileStats {
-    size: i64,
-    blocks: i64,
+#[derive(Debug, Clone, Copy)]
+enum Takes {
+    Nothing,
+    Value(&'static str),
+    // Only given inline, as `--color=always`.
+    Optional(&'static str),
 }
 
-impl FileStats {
-    #[inline]
-    fn size_in_bytes(&self) -> i64 {
-        self.size
-    }
-    #[inline]
-    fn disk_usage_blocks(&self) -> i64 {
-        (self.blocks * 512) / 1024
-    }
-    #[inline]
-    fn disk_usage_bytes(&self) -> i64 {
-        self.blocks * 512
-    }
+// Both `print_help` and `handle_args` are driven by `OPTIONS`, so the help
+// cannot list an option the parser does not know or miss one it does.
+struct OptSpec {
+    // `handle_args` matches on the first name.
+    names: &'static [&'static str],
+    takes: Takes,
+    // Lines after the first continue the description.
+    help: &'static str,
 }
 
-const UNITS: [(&str, f64); 7] = [
-    ("K", 1_024.0),
-    ("M", 1_048_576.0),
-    ("G", 1_073_741_824.0),
-    ("T", 1_099_511_627_776.0),
-    ("P", 1_125_899_906_842_624.0),
-    ("E", 1_152_921_504_606_846_976.0),
-    ("Z", 1_180_591_620_717_411_303_424.0),
-];
+const fn opt(names: &'static [&'static str], takes: Takes, help: &'static str) -> OptSpec {
+    OptSpec { names, takes, help }
+}
 
-fn parse_size_to_bytes(size_str: &str) -> Option<i64> {
-    let size_str = size_str.trim().to_uppercase();
-    let num_end = size_str
-        .chars()
-        .position(|c| !c.is_ascii_digit() && c != '.')
-        .unwrap_or(size_str.len());
+const OPTIONS: &[OptSpec] = &[
+    opt(&["--help"], Takes::Nothing, "Show this help message and exit"),
+    opt(&["-a", "--all"], Takes::Nothing, "Print files as well as directories"),
+    opt(&["-0", "--null"], Takes::Nothing, "End each output line with NUL, not newline"),
+    opt(
+        &["-o", "--output"],
+        Takes::Value("FILE"),
+        "Write the output to FILE instead of stdout, replacing\nits contents; errors and progress stay on stderr",
+    ),
+    opt(&["--append"], Takes::Nothing, "With --output, add to the end of FILE"),
+    opt(
+        &["--compress"],
+        Takes::Value("FORMAT"),
+        "With --output, compress FILE with 'gzip' or 'zstd'",
+    ),
+    opt(
+        &["--errors"],
+        Takes::Value("FILE"),
+        "Write warnings, and the notes of --warn-sparse, to FILE\ninstead of stderr, replacing its contents",
+    ),
+    opt(
+        &["--output-separator"],
+        Takes::Value("SEP"),
+        "Separate the columns of each line with SEP instead of\npadding the size to a fixed width",
+    ),
+    opt(
+        &["--relative-to"],
+        Takes::Value("DIR"),
+        "Print paths relative to DIR, or absolute where they are\nnot below it",
+    ),
+    opt(
+        &["--absolute"],
+        Takes::Nothing,
+        "Print absolute paths, whatever form PATH was given in",
+    ),
+    opt(
+        &["--no-leading-dot"],
+        Takes::Nothing,
+        "Print './a' as 'a'; the current directory itself stays '.'",
+    ),
+    opt(&["-b"], Takes::Nothing, "Display sizes in bytes"),
+    opt(
+        &["-h", "--human-readable"],
+        Takes::Nothing,
+        "Print sizes in powers of 1024 (1.5K, 234M, 2.0G)",
+    ),
+    opt(&["--si"], Takes::Nothing, "Like -h, but use powers of 1000 (kB, MB, GB)"),
+    opt(
+        &["--round"],
+        Takes::Value("MODE"),
+        "Round scaled sizes 'up', 'down' or 'nearest'; by\ndefault -h rounds to nearest and -B rounds up",
+    ),
+    opt(
+        &["--precision"],
+        Takes::Value("N"),
+        "Print N decimals in human-readable sizes (default 1)",
+    ),
+    opt(
+        &["--decimal-comma"],
+        Takes::Nothing,
+        "Write decimals in human-readable sizes after a comma,\nas in 1,5K; --json and --csv keep the point",
+    ),
+    opt(
+        &["--apparent-size"],
+        Takes::Nothing,
+        "Print apparent sizes rather than disk usage; with -B,\nthose are what is scaled to blocks",
+    ),
+    opt(
+        &["-s", "--summarize"],
+        Takes::Nothing,
+        "Print only one total per PATH, labelled with the PATH",
+    ),
+    opt(
+        &["-c", "--total"],
+        Takes::Nothing,
+        "Also print a grand total of all PATHs, labelled 'total'",
+    ),
+    opt(
+        &["--total-only"],
+        Takes::Nothing,
+        "Print nothing but the grand total of all PATHs",
+    ),
+    opt(
+        &["-S", "--separate-dirs"],
+        Takes::Nothing,
+        "Do not include subdirectory sizes in a directory's size",
+    ),
+    opt(
+        &["--time"],
+        Takes::Optional("WORD"),
+        "Show the latest time of any file in each entry: its\n'mtime' (default), 'atime' or 'ctime'",
+    ),
+    opt(
+        &["--time-style"],
+        Takes::Value("STYLE"),
+        "Time format: 'full-iso', 'long-iso' (default), 'iso',\nor +FORMAT as in strftime(3), with %N for nanoseconds",
+    ),
+    opt(
+        &["-d", "--max-depth"],
+        Takes::Value("DEPTH"),
+        "Only print entries at most DEPTH levels below PATH",
+    ),
+    opt(
+        &["--level1"],
+        Takes::Nothing,
+        "Print the files and directories directly in PATH, like\n-a -d 1",
+    ),
+    opt(
+        &["--collapse"],
+        Takes::Nothing,
+        "With --max-depth, also print the subdirectories of each\ndirectory at DEPTH as one DIR/(deeper) entry",
+    ),
+    opt(
+        &["-B", "--block-size"],
+        Takes::Value("SIZE"),
+        "Print sizes in blocks of SIZE, such as -BM, -B512 or\n--block-size=1M",
+    ),
+    opt(
+        &["-k"],
+        Takes::Nothing,
+        "Like -B1K, printing sizes in 1024-byte blocks",
+    ),
+    opt(
+        &["-m"],
+        Takes::Nothing,
+        "Like -B1M, printing sizes in 1048576-byte blocks",
+    ),
+    opt(
+        &["-t", "--threshold"],
+        Takes::Value("VALUE"),
+        "Set size threshold; a negative VALUE is an upper bound,\nand N% is that share of the PATH the entry is under",
+    ),
+    opt(
+        &["--min-size"],
+        Takes::Value("SIZE"),
+        "Only print entries of at least SIZE",
+    ),
+    opt(
+        &["--max-size"],
+        Takes::Value("SIZE"),
+        "Only print entries of at most SIZE",
+    ),
+    opt(
+        &["--entries-threshold"],
+        Takes::Value("N"),
+        "Only print directories holding at least N entries of\ntheir own, with that count in place of the size",
+    ),
+    opt(
+        &["--exclude-smaller-than"],
+        Takes::Value("SIZE"),
+        "Leave files under SIZE out of every total; -t and\n--min-size only hide entries but still count them",
+    ),
+    opt(
+        &["--exclude-larger-than"],
+        Takes::Value("SIZE"),
+        "Leave files over SIZE out of every total",
+    ),
+    opt(
+        &["--newer"],
+        Takes::Value("FILE"),
+        "Only count files modified after FILE, and only print\ndirectories holding such files",
+    ),
+    opt(
+        &["-x", "--one-file-system"],
+        Takes::Nothing,
+        "Count mount points of other file systems, but not\nwhat is on them",
+    ),
+    opt(&["-L", "--dereference"], Takes::Nothing, "Follow all symbolic links, counting what\nthey point to only once"),
+    opt(
+        &["-H", "-D", "--dereference-args"],
+        Takes::Nothing,
+        "Follow only symbolic links given as PATH",
+    ),
+    opt(
+        &["-P", "--no-dereference"],
+        Takes::Nothing,
+        "Follow no symbolic links (default); the last of\n-H, -L and -P wins",
+    ),
+    opt(
+        &["-l", "--count-links"],
+        Takes::Nothing,
+        "Count sizes many times if hard linked; otherwise a file\nis counted once across all PATHs given",
+    ),
+    opt(
+        &["-X", "--exclude-from"],
+        Takes::Value("FILE"),
+        "Exclude paths matching any pattern in FILE, or\nin stdin if FILE is - (repeatable)",
+    ),
+    opt(
+        &["--files0-from"],
+        Takes::Value("FILE"),
+        "Read NUL-separated PATHs from FILE, or stdin if FILE is -",
+    ),
+    opt(
+        &["--exclude"],
+        Takes::Value("PATTERN"),
+        "Exclude entries matching PATTERN (repeatable); patterns\nwith a / match the path below PATH, others the name",
+    ),
+    opt(
+        &["--include"],
+        Takes::Value("PATTERN"),
+        "Only print entries matching PATTERN and the directories\nholding them (repeatable); totals still count everything",
+    ),
+    opt(
+        &["--exclude-dir"],
+        Takes::Value("NAME"),
+        "Skip directories named NAME, but not files (repeatable)",
+    ),
+    opt(
+        &["--gitignore"],
+        Takes::Nothing,
+        "Skip entries ignored by .gitignore files",
+    ),
+    opt(
+        &["--exclude-caches"],
+        Takes::Nothing,
+        "Skip the contents of directories holding a CACHEDIR.TAG",
+    ),
+    opt(
+        &["-q", "--quiet"],
+        Takes::Nothing,
+        "Do not warn about unreadable files and directories",
+    ),
+    opt(
+        &["--strict"],
+        Takes::Nothing,
+        "Stop at the first file or directory that cannot be read",
+    ),
+    opt(
+        &["-v", "--verbose"],
+        Takes::Nothing,
+        "Note each entry left out and why on stderr; with -s,\nalso print how many files each PATH holds and their\naverage size",
+    ),
+    opt(
+        &["--sort"],
+        Takes::Value("KEY"),
+        "Sort entries by 'size' (largest first), 'name' or 'none',\nthe default, which lists each directory after its\ncontents in name order; applies to whatever entries\n--max-depth would print",
+    ),
+    opt(&["-r", "--reverse"], Takes::Nothing, "Reverse the sort order"),
+    opt(
+        &["--top"],
+        Takes::Value("N"),
+        "Only print the N largest entries, each PATH's own line\nincluded (implies --sort=size)",
+    ),
+    opt(
+        &["--threads"],
+        Takes::Value("N"),
+        "Scan with N threads, up to 1024, or 0 for one per CPU\n(default 1)",
+    ),
+    opt(
+        &["--max-entries"],
+        Takes::Value("N"),
+        "Stop scanning after N directory entries and print what\nwas counted by then, with a warning",
+    ),
+    opt(
+        &["--cache"],
+        Takes::Value("FILE"),
+        "Reuse the files of directories unchanged since the scan\nsaved in FILE, then save this one there; a file\nrewritten in place goes unnoticed until its directory\nchanges",
+    ),
+    opt(
+        &["--progress"],
+        Takes::Nothing,
+        "Show scan progress on stderr when it is a terminal",
+    ),
+    opt(
+        &["--histogram"],
+        Takes::Nothing,
+        "Print how many files fall in each size range instead\nof the size of each directory",
+    ),
+    opt(
+        &["--depth-sizes"],
+        Takes::Nothing,
+        "Print the total at each depth below the root instead of\nthe size of each directory; deeper levels are added\nto the last one --max-depth allows",
+    ),
+    opt(
+        &["--by-extension"],
+        Takes::Nothing,
+        "Print the total of the files with each extension instead\nof the size of each directory, largest first",
+    ),
+    opt(
+        &["--diff"],
+        Takes::Nothing,
+        "Compare two scans saved with --json, given as OLD and\nNEW PATH, and print how much each path grew or shrank;\nthe size options must measure sizes as the scans did",
+    ),
+    opt(
+        &["--dedup-report"],
+        Takes::Nothing,
+        "List groups of files with the same size and first and\nlast 4K instead of the size of each directory",
+    ),
+    opt(
+        &["--dedup-full"],
+        Takes::Nothing,
+        "Like --dedup-report, comparing whole files",
+    ),
+    opt(
+        &["--warn-sparse"],
+        Takes::Nothing,
+        "Note files on stderr that use less than half their\napparent size on disk",
+    ),
+    opt(
+        &["--stats"],
+        Takes::Nothing,
+        "Print counts of directories, files, excluded entries\nand errors on stderr when done",
+    ),
+    opt(
+        &["--color"],
+        Takes::Optional("WHEN"),
+        "Color sizes by magnitude: 'auto' (default), 'always'\nor 'never'; auto honors NO_COLOR",
+    ),
+    opt(&["--tree"], Takes::Nothing, "Print entries as an indented tree"),
+    opt(
+        &["--tree-ascii"],
+        Takes::Nothing,
+        "Like --tree, drawn with ASCII characters only",
+    ),
+    opt(
+        &["--json"],
+        Takes::Nothing,
+        "Print one JSON object per line, with sizes in bytes",
+    ),
+    opt(
+        &["--csv"],
+        Takes::Nothing,
+        "Print CSV rows of size in bytes, human-readable size\nand path, after a header line",
+    ),
+    opt(
+        &["--header"],
+        Takes::Nothing,
+        "Start with a '# sizes in ...' line naming the unit sizes\nare printed in; left out with --json and --csv",
+    ),
+    opt(
+        &["--format"],
+        Takes::Value("TEMPLATE"),
+        "Print each line from TEMPLATE, e.g. '{size}\\t{path}'; fields\nare size, human, blocks, inodes, path, mtime and depth",
+    ),
+];
 
-    let num_part: f64 = size_str[..num_end].parse().ok()?;
-    let unit_part = &size_str[num_end..];
-    let unit_map: HashMap<&str, f64> = UNITS.iter().cloned().collect();
+const USAGE: &str = "Usage: du-rs [OPTIONS] [--] [PATH]...";
 
-    let multiplier = unit_map.get(unit_part).copied().unwrap_or(1.0);
-    Some((num_part * multiplier) as i64)
-}
+const HELP_FOOTER: &str = "\
+Without -B, -b or -h, the block size is taken from DU_BLOCK_SIZE, BLOCK_SIZE
+or BLOCKSIZE, in that order, as a SIZE, 'human-readable' or 'si'. If none is
+set and POSIXLY_CORRECT is, sizes are in 512-byte blocks.
 
-fn get_file_sizes(file_path: Option<&Path>, bytes: Option<i64>) -> String {
-    use std::fmt::Write;
-    let bytes = bytes.unwrap_or_else(|| {
-        file_path
-            .and_then(|path| stat::stat(path).ok())
-            .map_or(0, |res| res.st_blocks * 512)
-    });
+Short options can be combined, as in -sh or -ahd 2. Every argument after --
+is a PATH, even if it starts with '-'.
 
-    let mut output = String::with_capacity(32);
+Exit status is 0 on success, 1 if anything could not be read or written (the
+sizes of what could be read are still printed), and 2 on a usage error.";
 
-    if bytes < 1024 {
-        return format!("{bytes}B");
-    }
+// Column the option descriptions start at.
+const HELP_INDENT: usize = 26;
 
-    let mut value = bytes as f64;
-    let mut unit = "B";
+fn print_help() -> ! {
+    let mut help = format!("{}\nOptions:\n", USAGE);
+    for spec in OPTIONS {
+        let mut left = String::from(if spec.names[0].starts_with("--") {
+            "      "
+        } else {
+            "  "
+        });
+        left.push_str(&spec.names.join(", "));
+        match spec.takes {
+            Takes::Nothing => {}
+            Takes::Value(name) => {
+                left.push(' ');
+                left.push_str(name);
+            }
+            Takes::Optional(name) => {
+                left.push_str("[=");
+                left.push_str(name);
+                left.push(']');
+            }
+        }
 
-    for &(u, div) in UNITS.iter() {
-        if bytes < (div as i64) * 1024 {
-            unit = u;
-            value /= div;
-            break;
+        help.push_str(&left);
+        if left.len() + 2 <= HELP_INDENT {
+            help.push_str(&" ".repeat(HELP_INDENT - left.len()));
+        } else {
+            help.push('\n');
+            help.push_str(&" ".repeat(HELP_INDENT));
+        }
+        for (i, line) in spec.help.lines().enumerate() {
+            if i > 0 {
+                help.push_str(&" ".repeat(HELP_INDENT));
+            }
+            help.push_str(line);
+            help.push('\n');
         }
     }
-    let _ = write!(output, "{:.1}{}", value, unit);
-    output
+    println!("{}\n{}", help, HELP_FOOTER);
+    exit(0);
 }
 
-fn format_size(size: i64, arg: &str) -> Cresult<String> {
-    let arg_from_2 = &arg[2..];
+// Misuse of the command line itself, as opposed to a bad option value.
+fn usage_error(message: std::fmt::Arguments) -> ! {
+    eprintln!("du-rs: {}", message);
+    eprintln!("{}", USAGE);
+    eprintln!("Try 'du-rs --help' for more information.");
+    exit(2);
+}
 
-    if let Some((_, divisor)) = UNITS.iter().find(|&&(u, _)| arg == format!("-B{}", u)) {
-        let adjusted_size = (size as f64 / divisor).ceil() as i64;
-        return Ok(format!("{}{}", adjusted_size, arg_from_2));
-    }
+// Splits `arg` into the options it names, each with any value given in the
+// same argument: `--name=VALUE`, or the rest of a cluster of short options
+// after one that takes a value, as in `-BM` or `-sd2`.
+fn split_options(arg: &str) -> Result<Vec<(&'static OptSpec, Option<&str>)>, String> {
+    let lookup = |name: &str| OPTIONS.iter().find(|spec| spec.names.contains(&name));
 
-    if let Ok(block_size) = arg_from_2.parse::<i64>() {
-        let adjusted_size = (size as f64 / block_size as f64).ceil() as i64;
-        return Ok(adjusted_size.to_string());
-    } else {
-        return Err(Error::msg("-B requires a valid argument"));
+    if arg.starts_with("--") {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg, None),
+        };
+        return match lookup(name) {
+            Some(spec) => Ok(vec![(spec, value)]),
+            None => Err(format!("unrecognized option '{}'", name)),
+        };
     }
-}
-
-#[derive(Debug, Clone)]
-#[repr(u8)]
-enum SizeFormat {
-    Bytes,
-    HumanReadable,
-    Blocks,
-}
 
-impl SizeFormat {
-    fn get_dir_size(&self, stats: &FileStats) -> i64 {
-        match self {
-            SizeFormat::Bytes => 0,
-            SizeFormat::HumanReadable => stats.disk_usage_bytes(),
-            SizeFormat::Blocks => stats.disk_usage_blocks(),
+    let mut options = Vec::new();
+    for (i, c) in arg.char_indices().skip(1) {
+        let Some(spec) = lookup(&format!("-{}", c)) else {
+            return Err(format!("invalid option -- '{}'", c));
+        };
+        if let Takes::Value(_) = spec.takes {
+            let rest = &arg[i + c.len_utf8()..];
+            options.push((spec, (!rest.is_empty()).then_some(rest)));
+            break;
         }
+        options.push((spec, None));
     }
-
-    fn get_file_size(&self, stats: &FileStats) -> i64 {
-        match self {
-            SizeFormat::Bytes => stats.size_in_bytes(),
-            SizeFormat::HumanReadable => stats.disk_usage_bytes(),
-            SizeFormat::Blocks => stats.disk_usage_blocks(),
-        }
-    }
-}
-
-fn print_help() {
-    println!(
-        "Usage: du-rs [OPTIONS] [PATH]
-Options:
-  -h, --help              Show this help message and exit
-  -a, --all               Include hidden files
-  -ah                     Include hidden files and use human-readable sizes
-  -b                      Display sizes in bytes
-  -s, --summarize         Summarize directory sizes
-  -c, --total             Show total size
-  -d, --max-depth DEPTH   Set maximum depth for directory traversal
-  -B<size>                Set block size
-  -t, --threshold VALUE   Set size threshold
-  -x, --one-file-system PATH  Limit scanning to one file system
-  -X, --exclude-from PATH    Exclude paths from a file"
-    );
-    exit(0);
+    Ok(options)
 }
 
 #[derive(Debug, Clone)]
@@ -161,94 +497,597 @@ struct Args {
     summarize: bool,
     bytes: bool,
     total: bool,
+    total_only: bool,
+    diff: bool,
     block_size: String,
     threshold: Option<String>,
-    x: Option<PathBuf>,
-    xclude: Option<PathBuf>,
+    min_size: Option<String>,
+    max_size: Option<String>,
+    entries_threshold: Option<u64>,
+    exclude_smaller_than: Option<String>,
+    exclude_larger_than: Option<String>,
+    newer: Option<Timestamp>,
+    one_file_system: bool,
+    xclude: Vec<PathBuf>,
+    exclude: Vec<String>,
+    include: Vec<String>,
+    exclude_dirs: Vec<String>,
     a: bool,
     count_hardlinks: bool,
-    follow_symlinks: bool,
-    c: bool,
+    dereference: Dereference,
+    json: bool,
+    csv: bool,
+    header: bool,
+    apparent_size: bool,
+    null: bool,
+    output_file: Option<PathBuf>,
+    append: bool,
+    compress: Option<Compression>,
+    errors_file: Option<PathBuf>,
+    collapse: bool,
+    sort: Option<SortKey>,
+    reverse: bool,
+    top: Option<usize>,
+    threads: usize,
+    max_entries: Option<u64>,
+    cache: Option<PathBuf>,
+    progress: bool,
+    stats: bool,
+    warn_sparse: bool,
+    histogram: bool,
+    depth_sizes: bool,
+    by_extension: bool,
+    dedup_report: bool,
+    dedup_full: bool,
+    output_separator: Option<String>,
+    relative_to: Option<PathBuf>,
+    absolute: bool,
+    no_leading_dot: bool,
+    template: Option<Vec<Field>>,
+    gitignore: bool,
+    exclude_caches: bool,
+    quiet: bool,
+    strict: bool,
+    verbose: bool,
+    separate_dirs: bool,
+    time: bool,
+    time_kind: TimeKind,
+    time_style: TimeStyle,
+    si: bool,
+    round: Option<Rounding>,
+    precision: usize,
+    decimal_comma: bool,
+    color: ColorMode,
+    tree: Option<TreeStyle>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    fn enabled(self, to_terminal: bool) -> bool {
+        match self {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && to_terminal,
+        }
+    }
+}
+
+fn parse_color_mode(value: &str) -> ColorMode {
+    match value {
+        "auto" => ColorMode::Auto,
+        "always" => ColorMode::Always,
+        "never" => ColorMode::Never,
+        _ => {
+            eprintln!("du-rs: invalid color mode '{}'", value);
+            exit(2);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Zstd,
+}
+
+fn parse_compression(value: &str) -> Compression {
+    match value {
+        "gzip" => Compression::Gzip,
+        "zstd" => Compression::Zstd,
+        _ => {
+            eprintln!(
+                "du-rs: invalid compression '{}'; expected gzip or zstd",
+                value
+            );
+            exit(2);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Size,
+    Name,
+}
+
+fn parse_sort_key(value: &str) -> Option<SortKey> {
+    match value {
+        "size" => Some(SortKey::Size),
+        "name" => Some(SortKey::Name),
+        "none" => None,
+        _ => {
+            eprintln!("du-rs: invalid sort key '{}'", value);
+            exit(2);
+        }
+    }
+}
+
+impl Args {
+    // What the walk sums: 1K blocks by default, otherwise bytes, apparent or
+    // allocated. A `-B` unit is applied to those bytes at print time, so
+    // with `--apparent-size`, which `-b` followed by `-B` implies as in GNU
+    // du, it scales apparent bytes rather than allocated ones.
+    fn size_format(&self) -> SizeFormat {
+        if self.apparent_size || (self.bytes && !self.block_size.is_empty()) {
+            SizeFormat::Apparent
+        } else if !self.block_size.is_empty() {
+            SizeFormat::HumanReadable
+        } else if self.bytes {
+            SizeFormat::Bytes
+        } else if self.human_readable {
+            SizeFormat::HumanReadable
+        } else {
+            SizeFormat::Blocks
+        }
+    }
+
+    // Apparent sizes are summed in bytes, so without an explicit unit they are
+    // rounded up to 1K blocks at print time.
+    fn block_size(&self) -> Option<String> {
+        if !self.block_size.is_empty() {
+            Some(self.block_size.clone())
+        } else if self.apparent_size && !self.bytes && !self.human_readable {
+            Some("-B1024".to_string())
+        } else {
+            None
+        }
+    }
+
+    // `-t N%` as a signed percentage, negative for an upper bound.
+    fn threshold_percent(&self) -> Option<f64> {
+        let value = self.threshold.as_deref()?;
+        match value.strip_prefix('-') {
+            Some(max) => parse_percent(max).map(|percent| -percent),
+            None => parse_percent(value),
+        }
+    }
+
+    // What a PATH's own line shows for it.
+    fn root_size(&self, usage: &du_rs::DirUsage) -> i64 {
+        if self.entries_threshold.is_some() {
+            usage.entries as i64
+        } else if self.summarize {
+            usage.total
+        } else {
+            usage.shown(self.separate_dirs)
+        }
+    }
+
+    // Whether entries are printed at all, rather than only a total or report.
+    fn prints_entries(&self) -> bool {
+        !self.total_only
+            && !self.histogram
+            && !self.depth_sizes
+            && !self.by_extension
+            && !self.dedup_report
+    }
+
+    fn output_config(&self) -> OutputConfig {
+        OutputConfig {
+            // Entry counts are printed as they are.
+            block_size: self
+                .block_size()
+                .filter(|_| self.entries_threshold.is_none()),
+            format: self.human_readable && self.entries_threshold.is_none(),
+            json: self.json,
+            csv: self.csv,
+            line_end: if self.null { b'\0' } else { b'\n' },
+            separator: self.output_separator.clone(),
+            cwd: (self.absolute || self.relative_to.is_some())
+                .then(|| env::current_dir().unwrap_or_default()),
+            no_leading_dot: self.no_leading_dot,
+            relative_to: self
+                .relative_to
+                .as_ref()
+                .map(|dir| absolute_path(dir, &env::current_dir().unwrap_or_default())),
+            template: self.template.clone().map(|fields| Template {
+                fields,
+                time_style: self.time_style.clone(),
+            }),
+            time_style: self.time.then(|| self.time_style.clone()),
+            file_counts: self.verbose && self.summarize,
+            si: self.si,
+            human_rounding: self.round.unwrap_or(Rounding::Nearest),
+            block_rounding: self.round.unwrap_or(Rounding::Up),
+            precision: self.precision,
+            decimal_point: if self.decimal_comma && !self.json && !self.csv {
+                ','
+            } else {
+                '.'
+            },
+            color: !self.json
+                && !self.csv
+                && self.entries_threshold.is_none()
+                && self
+                    .color
+                    .enabled(self.output_file.is_none() && stdout().is_terminal()),
+            unit: match self.size_format() {
+                SizeFormat::Blocks if self.entries_threshold.is_none() => 1024,
+                _ => 1,
+            },
+            measure: match self.size_format() {
+                _ if self.entries_threshold.is_some() => "entries",
+                SizeFormat::Apparent | SizeFormat::Bytes => "apparent",
+                SizeFormat::Blocks | SizeFormat::HumanReadable => "disk",
+            },
+        }
+    }
+
+    fn scan_options(&self) -> ScanOptions {
+        let size_format = self.size_format();
+        let to_units = |value: &str| {
+            let bytes = parse_size_to_bytes(value).unwrap_or(0);
+            match size_format {
+                SizeFormat::Blocks => bytes / 1024,
+                _ => bytes,
+            }
+        };
+
+        // A negative threshold bounds sizes from above, as in GNU du. A
+        // percentage is applied once the walk is done instead.
+        let threshold = self.threshold.as_deref().filter(|t| !t.ends_with('%'));
+        let (threshold_min, threshold_max) = match threshold {
+            Some(t) => match t.strip_prefix('-') {
+                Some(max) => (None, Some(to_units(max))),
+                None => (Some(to_units(t)), None),
+            },
+            None => (None, None),
+        };
+        let threshold = [threshold_min, self.min_size.as_deref().map(to_units)]
+            .into_iter()
+            .flatten()
+            .max()
+            .unwrap_or(0);
+        let max_size = [threshold_max, self.max_size.as_deref().map(to_units)]
+            .into_iter()
+            .flatten()
+            .min();
+        let exclude_smaller_than = self.exclude_smaller_than.as_deref().map(to_units);
+        let exclude_larger_than = self.exclude_larger_than.as_deref().map(to_units);
+
+        ScanOptions {
+            max_depth: self.depth,
+            one_file_system: self.one_file_system,
+            exclude_from: self.xclude.clone(),
+            exclude: self.exclude.clone(),
+            exclude_dirs: self.exclude_dirs.clone(),
+            gitignore: self.gitignore,
+            exclude_caches: self.exclude_caches,
+            dereference: self.dereference,
+            count_links: self.count_hardlinks,
+            size_format,
+            all: self.a && self.entries_threshold.is_none(),
+            summarize: self.summarize,
+            collapse: self.collapse,
+            separate_dirs: self.separate_dirs,
+            threshold,
+            max_size,
+            exclude_smaller_than,
+            exclude_larger_than,
+            newer: self.newer,
+            threads: self.threads,
+            progress: (self.progress && std::io::stderr().is_terminal())
+                .then(|| Arc::new(Progress::default())),
+            stats: self.stats.then(|| Arc::new(ScanStats::default())),
+            histogram: self.histogram.then(|| Arc::new(Histogram::default())),
+            depth_sizes: self.depth_sizes.then(|| Arc::new(DepthSizes::default())),
+            extensions: self
+                .by_extension
+                .then(|| Arc::new(ExtensionSizes::default())),
+            entry_limit: self.max_entries.map(|max| Arc::new(EntryLimit::new(max))),
+            entries_threshold: self.entries_threshold,
+            // Read by `main`, which reports a cache it cannot use.
+            cache: None,
+            duplicates: self
+                .dedup_report
+                .then(|| Arc::new(DuplicateFinder::default())),
+            warn_sparse: self.warn_sparse,
+            report_skipped: self.verbose,
+            time: self.time_kind,
+        }
+    }
 }
 
 fn handle_args() -> Args {
-    let mut arguments = env::args().skip(1);
+    parse_args(env::args().skip(1))
+}
+
+fn parse_args(mut arguments: impl Iterator<Item = String>) -> Args {
     let mut path_vec = Vec::new();
     let mut human_readable = false;
     let mut depth = None;
     let mut summarize = false;
     let mut bytes = false;
     let mut total = false;
+    let mut total_only = false;
+    let mut diff = false;
     let mut block_size = String::new();
     let mut threshold = None;
-    let mut x = None;
-    let mut xclude = None;
+    let mut min_size = None;
+    let mut max_size = None;
+    let mut entries_threshold = None;
+    let mut exclude_smaller_than = None;
+    let mut exclude_larger_than = None;
+    let mut newer = None;
+    let mut one_file_system = false;
+    let mut xclude = Vec::new();
+    let mut exclude = Vec::new();
+    let mut include = Vec::new();
+    let mut exclude_dirs = Vec::new();
     let mut a = false;
-    let mut follow_symlinks = false;
-    let mut c = false;
+    let mut dereference = Dereference::Never;
     let mut count_hardlinks = false;
+    let mut json = false;
+    let mut csv = false;
+    let mut header = false;
+    let mut apparent_size = false;
+    let mut null = false;
+    let mut output_file = None;
+    let mut append = false;
+    let mut compress = None;
+    let mut errors_file = None;
+    let mut collapse = false;
+    let mut sort = None;
+    let mut reverse = false;
+    let mut top = None;
+    let mut threads = 1;
+    let mut max_entries = None;
+    let mut cache = None;
+    let mut progress = false;
+    let mut stats = false;
+    let mut warn_sparse = false;
+    let mut histogram = false;
+    let mut depth_sizes = false;
+    let mut by_extension = false;
+    let mut dedup_report = false;
+    let mut dedup_full = false;
+    let mut output_separator = None;
+    let mut relative_to = None;
+    let mut absolute = false;
+    let mut no_leading_dot = false;
+    let mut template = None;
+    let mut gitignore = false;
+    let mut exclude_caches = false;
+    let mut quiet = false;
+    let mut strict = false;
+    let mut verbose = false;
+    let mut files0_from = None;
+    let mut separate_dirs = false;
+    let mut time = false;
+    let mut si = false;
+    let mut round = None;
+    let mut precision = 1;
+    let mut decimal_comma = false;
+    let mut color = ColorMode::Auto;
+    let mut tree = None;
+    let mut time_kind = TimeKind::Modified;
+    let mut time_style = TimeStyle::LongIso;
     let mut stop_parsing_flags = false;
 
     while let Some(arg) = arguments.next() {
+        if stop_parsing_flags || !arg.starts_with('-') || arg == "-" {
+            path_vec.push(PathBuf::from(arg));
+            continue;
+        }
         if arg == "--" {
             stop_parsing_flags = true;
             continue;
         }
 
-        if !stop_parsing_flags {
-            match arg.as_str() {
+        let options = split_options(&arg).unwrap_or_else(|e| usage_error(format_args!("{}", e)));
+        for (spec, inline) in options {
+            let value = match spec.takes {
+                Takes::Nothing if inline.is_some() => usage_error(format_args!(
+                    "option '{}' doesn't allow an argument",
+                    spec.names[spec.names.len() - 1]
+                )),
+                Takes::Value(_) => match inline {
+                    Some(value) => value.to_string(),
+                    None => arguments.next().unwrap_or_else(|| {
+                        let name = if arg.starts_with("--") {
+                            arg.as_str()
+                        } else {
+                            spec.names[0]
+                        };
+                        usage_error(format_args!("option '{}' requires an argument", name))
+                    }),
+                },
+                _ => inline.unwrap_or_default().to_string(),
+            };
+
+            match spec.names[0] {
                 "--help" => print_help(),
-                "-h" | "--human-readable" => human_readable = true,
-                "-a" | "--all" => a = true,
-                "-L" => follow_symlinks = true,
-                "-l" => count_hardlinks = true,
-                "-c" | "--total" => {
-                    total = true;
-                    c = true;
-                }
-                "-ah" => {
+                "-h" => human_readable = true,
+                "-a" => a = true,
+                "--level1" => {
                     a = true;
-                    human_readable = true;
+                    depth = Some(1);
+                }
+                // The last of -H, -L and -P wins.
+                "-L" => dereference = Dereference::Always,
+                "-H" => dereference = Dereference::Roots,
+                "-P" => dereference = Dereference::Never,
+                "-l" => count_hardlinks = true,
+                "-c" => total = true,
+                "--total-only" => total_only = true,
+                "--diff" => diff = true,
+                // Like --apparent-size --block-size=1, so it also undoes an
+                // earlier block size.
+                "-b" => {
+                    bytes = true;
+                    block_size.clear();
                 }
-                "-sh" => {
-                    summarize = true;
+                "--si" => {
+                    si = true;
                     human_readable = true;
                 }
-                "-b" => bytes = true,
-                "-s" | "--summarize" => summarize = true,
-                "-d" | "--max-depth" => {
-                    depth = arguments.next().and_then(|v| v.parse().ok());
+                "--round" => round = Some(parse_rounding(&value)),
+                "--precision" => precision = parse_precision(&value),
+                "--decimal-comma" => decimal_comma = true,
+                "--json" => json = true,
+                "--csv" => csv = true,
+                "--header" => header = true,
+                "--tree" => tree = Some(TreeStyle::Unicode),
+                "--tree-ascii" => tree = Some(TreeStyle::Ascii),
+                "--color" if value.is_empty() => color = ColorMode::Always,
+                "--color" => color = parse_color_mode(&value),
+                "--apparent-size" => apparent_size = true,
+                "-0" => null = true,
+                "-o" => output_file = Some(PathBuf::from(value)),
+                "--append" => append = true,
+                "--compress" => compress = Some(parse_compression(&value)),
+                "--errors" => errors_file = Some(PathBuf::from(value)),
+                "--output-separator" => output_separator = Some(value),
+                "--relative-to" => relative_to = Some(PathBuf::from(value)),
+                "--absolute" => absolute = true,
+                "--no-leading-dot" => no_leading_dot = true,
+                "--format" => {
+                    template = Some(parse_template(&value).unwrap_or_else(|e| {
+                        eprintln!("du-rs: {}", e);
+                        exit(2);
+                    }))
                 }
-                _ if arg.starts_with("-B") => {
-                    block_size = arg.clone();
+                "-r" => reverse = true,
+                "--collapse" => collapse = true,
+                "--sort" => sort = parse_sort_key(&value),
+                "--top" => top = Some(parse_count("entry count", &value)),
+                "--progress" => progress = true,
+                "--stats" => stats = true,
+                "--warn-sparse" => warn_sparse = true,
+                "--histogram" => histogram = true,
+                "--depth-sizes" => depth_sizes = true,
+                "--by-extension" => by_extension = true,
+                "--dedup-report" => dedup_report = true,
+                "--dedup-full" => {
+                    dedup_report = true;
+                    dedup_full = true;
                 }
-                "-t" | "--threshold" => {
-                    threshold = arguments.next().and_then(|v| v.parse().ok());
+                "--threads" => threads = parse_thread_count(&value),
+                "--max-entries" => max_entries = Some(parse_count("entry count", &value)),
+                "--cache" => cache = Some(PathBuf::from(value)),
+                "-s" => summarize = true,
+                "-d" => {
+                    let levels: u32 = parse_count("depth", &value);
+                    depth = Some(levels.min(i32::MAX as u32) as i32);
                 }
-                "-x" | "--one-file-system" => {
-                    x = arguments.next().map(PathBuf::from);
+                "-B" => {
+                    block_size = block_size_arg(&value).unwrap_or_else(|| {
+                        eprintln!("du-rs: invalid --block-size argument '{}'", value);
+                        exit(2);
+                    })
                 }
-                "-X" | "--exclude-from" => {
-                    xclude = arguments.next().map(PathBuf::from);
+                "-k" => block_size = "-B1024".to_string(),
+                "-m" => block_size = "-B1048576".to_string(),
+                "-t" => threshold = Some(value),
+                "--min-size" => min_size = Some(value),
+                "--max-size" => max_size = Some(value),
+                "--entries-threshold" => {
+                    entries_threshold = Some(parse_count("entry count", &value))
                 }
-                _ if arg.starts_with('-') => {
-                    eprintln!("Error: Invalid argument '{}'", arg);
-                    exit(1);
+                "--exclude-smaller-than" => exclude_smaller_than = Some(value),
+                "--exclude-larger-than" => exclude_larger_than = Some(value),
+                "--newer" => newer = Some(reference_time(&value)),
+                "-x" => one_file_system = true,
+                "-X" => xclude.push(PathBuf::from(value)),
+                "--gitignore" => gitignore = true,
+                "--exclude-caches" => exclude_caches = true,
+                "-q" => quiet = true,
+                "--strict" => strict = true,
+                "-v" => verbose = true,
+                "--files0-from" => files0_from = Some(value),
+                "-S" => separate_dirs = true,
+                "--time" => {
+                    time = true;
+                    if !value.is_empty() {
+                        time_kind = parse_time_kind(&value);
+                    }
                 }
-                _ => {}
+                "--time-style" => time_style = parse_time_style(&value),
+                "--exclude" => exclude.push(value),
+                "--include" => include.push(value),
+                "--exclude-dir" => exclude_dirs.push(value),
+                name => unreachable!("option {} is not handled", name),
             }
-        } else {
-            path_vec.push(PathBuf::from(arg));
-            continue;
         }
+    }
 
-        if stop_parsing_flags || !arg.starts_with('-') {
-            path_vec.push(PathBuf::from(arg));
+    if compress.is_some() && output_file.is_none() {
+        usage_error(format_args!("--compress needs --output FILE"));
+    }
+    if diff && path_vec.len() != 2 {
+        usage_error(format_args!("--diff takes two saved scans, OLD and NEW"));
+    }
+    if let Some(source) = files0_from {
+        if let Some(extra) = path_vec.first() {
+            eprintln!(
+                "du-rs: extra operand '{}': paths cannot be combined with --files0-from",
+                extra.display()
+            );
+            exit(2);
         }
+        path_vec = read_files0(&source);
+    } else if path_vec.is_empty() {
+        path_vec.push(PathBuf::from("."));
     }
 
-    if path_vec.is_empty() {
-        path_vec.push(PathBuf::from("."));
+    if block_size.is_empty() && !human_readable && !bytes {
+        match env_block_size().as_deref() {
+            Some("human-readable") => human_readable = true,
+            Some("si") => {
+                si = true;
+                human_readable = true;
+            }
+            Some(spec) => block_size = block_size_arg(spec).unwrap_or_default(),
+            None => {}
+        }
+    }
+
+    // Only the grand total is printed, so the walk need not report anything.
+    if total_only {
+        summarize = true;
+        total = true;
+        tree = None;
+    }
+    // Reports that replace the per-directory output.
+    if histogram || depth_sizes || by_extension || dedup_report {
+        summarize = true;
+        tree = None;
+    }
+
+    check_size("threshold", threshold.as_deref(), true);
+    check_size("minimum size", min_size.as_deref(), false);
+    check_size("maximum size", max_size.as_deref(), false);
+    check_size("file size", exclude_smaller_than.as_deref(), false);
+    check_size("file size", exclude_larger_than.as_deref(), false);
+
+    if top.is_some() && sort.is_none() {
+        sort = Some(SortKey::Size);
     }
 
     Args {
@@ -258,516 +1097,2519 @@ fn handle_args() -> Args {
         bytes,
         summarize,
         total,
+        total_only,
+        diff,
         block_size,
         threshold,
+        min_size,
+        max_size,
+        entries_threshold,
+        exclude_smaller_than,
+        exclude_larger_than,
+        newer,
         xclude,
-        x,
-        c,
+        exclude,
+        include,
+        exclude_dirs,
+        one_file_system,
         a,
         count_hardlinks,
-        follow_symlinks,
+        dereference,
+        json,
+        csv,
+        header,
+        apparent_size,
+        null,
+        output_file,
+        append,
+        compress,
+        errors_file,
+        collapse,
+        sort,
+        reverse,
+        top,
+        threads,
+        max_entries,
+        cache,
+        progress,
+        stats,
+        warn_sparse,
+        histogram,
+        depth_sizes,
+        by_extension,
+        dedup_report,
+        dedup_full,
+        output_separator,
+        relative_to,
+        absolute,
+        no_leading_dot,
+        template,
+        gitignore,
+        exclude_caches,
+        quiet,
+        strict,
+        verbose,
+        separate_dirs,
+        time,
+        time_kind,
+        time_style,
+        si,
+        round,
+        precision,
+        decimal_comma,
+        color,
+        tree,
     }
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
-enum FileContent {
-    Path(PathBuf),
-    Pattern(String),
+// Reads NUL-separated paths from `source`, or from stdin when it is `-`.
+fn read_files0(source: &str) -> Vec<PathBuf> {
+    let content = if source == "-" {
+        let mut content = Vec::new();
+        std::io::stdin().read_to_end(&mut content).map(|_| content)
+    } else {
+        std::fs::read(source)
+    };
+
+    let content = content.unwrap_or_else(|e| {
+        eprintln!("du-rs: cannot read paths from '{}': {}", source, e);
+        exit(1);
+    });
+
+    content
+        .split(|&b| b == b'\0')
+        .filter(|name| !name.is_empty())
+        .map(|name| PathBuf::from(platform::os_str(name).into_owned()))
+        .collect()
 }
 
-fn exclude_list(file: &Path) -> HashSet<FileContent> {
-    let file_fd = match nix::fcntl::open(file, OFlag::O_RDONLY, Mode::empty()) {
-        Ok(fd) => fd,
-        Err(e) => {
-            eprintln!("du-rs: cannot access '{}': {}", file.display(), e);
-            return HashSet::new();
-        }
-    };
+// The default block size GNU du reads from the environment.
+fn env_block_size() -> Option<String> {
+    ["DU_BLOCK_SIZE", "BLOCK_SIZE", "BLOCKSIZE"]
+        .iter()
+        .find_map(|name| env::var(name).ok().filter(|v| !v.is_empty()))
+        .or_else(|| env::var_os("POSIXLY_CORRECT").map(|_| "512".to_string()))
+}
 
-    let mut buffer = [0u8; 1024];
-    let mut content = String::new();
-    let mut hs = HashSet::new();
+// Turns a block size spec into a `-B` argument. A bare unit keeps its suffix
+// on the printed sizes, as `-BM` does; anything else is scaled to bytes.
+fn block_size_arg(spec: &str) -> Option<String> {
+    if UNITS.iter().any(|&(unit, _)| unit == spec) {
+        return Some(format!("-B{}", spec));
+    }
+    parse_size_to_bytes(spec)
+        .filter(|&bytes| bytes > 0)
+        .map(|bytes| format!("-B{}", bytes))
+}
 
-    loop {
-        let bytes_read = match nix::unistd::read(file_fd, &mut buffer) {
-            Ok(n) => n,
-            Err(e) => {
-                eprintln!("du-rs: failed reading '{}': {}", file.display(), e);
-                return HashSet::new();
-            }
+fn check_size(kind: &str, value: Option<&str>, signed: bool) {
+    if let Some(value) = value {
+        let magnitude = if signed {
+            value.strip_prefix('-').unwrap_or(value)
+        } else {
+            value
         };
-
-        if bytes_read == 0 {
-            break;
+        let valid = if signed && magnitude.ends_with('%') {
+            parse_percent(magnitude).is_some()
+        } else {
+            parse_size_to_bytes(magnitude).is_some()
+        };
+        if !valid {
+            eprintln!("du-rs: invalid {} '{}'", kind, value);
+            exit(2);
         }
-        content.push_str(&String::from_utf8_lossy(&buffer[..bytes_read]));
-    }
-    if let Err(e) = nix::unistd::close(file_fd) {
-        eprintln!("du-rs: failed to close file {}: {}", file_fd, e);
     }
+}
 
-    let current_dir = match env::current_dir() {
-        Ok(p) => p,
-        Err(e) => {
-            eprintln!("du-rs: cannot determine current directory: {e}");
-            std::process::exit(1);
-        }
-    };
+fn parse_percent(value: &str) -> Option<f64> {
+    let percent: f64 = value.strip_suffix('%')?.parse().ok()?;
+    (percent.is_finite() && percent >= 0.0).then_some(percent)
+}
 
-    for line in content.lines() {
-        let trimmed_line = line.trim();
+fn parse_count<T: std::str::FromStr>(kind: &str, value: &str) -> T {
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("du-rs: invalid {} '{}'", kind, value);
+        exit(2);
+    })
+}
 
-        if trimmed_line.is_empty() {
-            continue;
-        }
+// Far more than any disk keeps busy; a larger count is more likely a typo
+// than a wish for that many threads.
+const MAX_THREADS: usize = 1024;
+
+fn parse_thread_count(value: &str) -> usize {
+    let threads = parse_count("thread count", value);
+    if threads > MAX_THREADS {
+        eprintln!(
+            "du-rs: thread count '{}' is over the limit of {}",
+            value, MAX_THREADS
+        );
+        exit(2);
+    }
+    threads
+}
 
-        let path = Path::new(trimmed_line);
-        if path.is_absolute() {
-            if path.exists() && path.is_dir() {
-                hs.insert(FileContent::Path(path.to_path_buf()));
-            } else if let Some(stripped) = trimmed_line.strip_prefix("*.") {
-                let extension = stripped;
-                hs.insert(FileContent::Pattern(extension.to_string()));
-            }
-        } else {
-            let full_path = current_dir.join(path);
-            if full_path.exists() && full_path.is_dir() {
-                hs.insert(FileContent::Path(full_path));
-            } else if let Some(stripped) = trimmed_line.strip_prefix("*.") {
-                let extension = stripped;
-                hs.insert(FileContent::Pattern(extension.to_string()));
-            }
+fn parse_rounding(value: &str) -> Rounding {
+    Rounding::parse(value).unwrap_or_else(|| {
+        eprintln!("du-rs: invalid rounding mode '{}'", value);
+        exit(2);
+    })
+}
+
+// Keeps the scaled size well within the integers it is rounded in.
+const MAX_PRECISION: usize = 9;
+
+fn parse_precision(value: &str) -> usize {
+    match value.parse() {
+        Ok(precision) if precision <= MAX_PRECISION => precision,
+        _ => {
+            eprintln!("du-rs: invalid precision '{}'", value);
+            exit(2);
         }
     }
-    hs
 }
 
-struct TraversalConfig {
-    max_depth: i32,
-    root_dev: Option<u64>,
-    exclusion_paths: Option<FxHashSet<PathBuf>>,
-    exclusion_patterns: Option<FxHashSet<OsString>>,
-    format: bool,
-    summarize: bool,
-    list_files: bool,
-    threshold_size: i64,
-    count_hard_link: bool,
-    block_size: Option<String>,
-    size_format: SizeFormat,
-    open_flag: OFlag,
-    at_flag: AtFlags,
+// A piece of a `--format` template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Field {
+    Text(Vec<u8>),
+    Size,
+    Human,
+    Blocks,
+    Inodes,
+    Path,
+    Mtime,
+    Depth,
 }
 
-fn process_directories(
-    args: &Args,
-    root_dir: &PathBuf,
-    open_flag: OFlag,
-    at_flag: AtFlags,
-) -> Cresult<i64> {
-    use fxhash::FxHashSet;
-    use nix::fcntl::open;
-    use nix::sys::stat::{stat, Mode};
-    use std::env;
-    use std::ffi::{OsStr, OsString};
-    use std::io::{stdout, BufWriter, Write};
-
-    let max_depth = args.depth.unwrap_or(0);
-
-    let fd = match open(root_dir, open_flag, Mode::empty()) {
-        Ok(fd) => fd,
-        Err(_) => return Ok(0),
-    };
-
-    let root_dev = if args.x.is_some() {
-        stat(root_dir)
-            .context("Failed to get device ID of root directory")
-            .ok()
-            .map(|s| s.st_dev)
-    } else {
-        None
-    };
+struct Template {
+    fields: Vec<Field>,
+    time_style: TimeStyle,
+}
 
-    let (exclusion_paths, exclusion_patterns) = if let Some(exclude_path) = args.xclude.as_deref() {
-        let mut paths = FxHashSet::default();
-        let mut patterns = FxHashSet::default();
+// `{name}` is replaced by a field and `{{`, `}}` are literal braces. `\t`,
+// `\n` and `\\` are unescaped so templates need no shell quoting tricks.
+fn parse_template(value: &str) -> Result<Vec<Field>, String> {
+    let bytes = value.as_bytes();
+    let mut fields = Vec::new();
+    let mut text = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let (field, len) = match (bytes[i], bytes.get(i + 1)) {
+            (b'{', Some(b'{')) => (None, 2),
+            (b'}', Some(b'}')) => (None, 2),
+            (b'\\', Some(&escaped @ (b't' | b'n' | b'\\'))) => {
+                text.push(match escaped {
+                    b't' => b'\t',
+                    b'n' => b'\n',
+                    _ => b'\\',
+                });
+                i += 2;
+                continue;
+            }
+            (b'{', _) => {
+                let Some(end) = bytes[i..].iter().position(|&b| b == b'}') else {
+                    return Err(format!("unclosed '{{' in format '{}'", value));
+                };
+                let name = &value[i + 1..i + end];
+                let field = match name {
+                    "size" => Field::Size,
+                    "human" => Field::Human,
+                    "blocks" => Field::Blocks,
+                    "inodes" => Field::Inodes,
+                    "path" => Field::Path,
+                    "mtime" => Field::Mtime,
+                    "depth" => Field::Depth,
+                    _ => {
+                        return Err(format!(
+                            "unknown field '{{{}}}' in format '{}'; expected size, human, blocks, inodes, path, mtime or depth",
+                            name, value
+                        ));
+                    }
+                };
+                (Some(field), end + 1)
+            }
+            _ => (None, 1),
+        };
 
-        for s in exclude_list(exclude_path) {
-            match s {
-                FileContent::Path(p) => {
-                    paths.insert(p);
-                }
-                FileContent::Pattern(pt) => {
-                    patterns.insert(OsString::from(pt));
+        match field {
+            Some(field) => {
+                if !text.is_empty() {
+                    fields.push(Field::Text(std::mem::take(&mut text)));
                 }
+                fields.push(field);
             }
+            None => text.push(bytes[i]),
         }
-        (Some(paths), Some(patterns))
-    } else {
-        (None, None)
-    };
-
-    let threshold_bytes =
-        parse_size_to_bytes(args.threshold.as_deref().unwrap_or("0")).unwrap_or(0);
-    let threshold_size = if !args.bytes && !args.human_readable {
-        threshold_bytes / 1024
-    } else {
-        threshold_bytes
-    };
-
-    let size_format = if !args.block_size.is_empty() {
-        SizeFormat::HumanReadable
-    } else if args.bytes {
-        SizeFormat::Bytes
-    } else if args.human_readable {
-        SizeFormat::HumanReadable
-    } else {
-        SizeFormat::Blocks
-    };
+        i += len;
+    }
+    if !text.is_empty() {
+        fields.push(Field::Text(text));
+    }
+    Ok(fields)
+}
 
-    let block_size = if args.block_size.is_empty() {
-        None
-    } else {
-        Some(args.block_size.clone())
-    };
+// Like `find -newer`, a symbolic link given as FILE is followed.
+fn reference_time(file: &str) -> Timestamp {
+    match Metadata::of(Path::new(file), true) {
+        Ok(meta) => mtime_of(&meta),
+        Err(errno) => {
+            eprintln!("du-rs: cannot access '{}': {}", file, errno.desc());
+            exit(1);
+        }
+    }
+}
 
-    let config = TraversalConfig {
-        max_depth,
-        root_dev,
-        exclusion_paths,
-        exclusion_patterns,
-        format: args.human_readable,
-        summarize: args.summarize,
-        list_files: args.a,
-        threshold_size,
-        count_hard_link: args.count_hardlinks,
-        block_size,
-        size_format,
-        open_flag,
-        at_flag,
-    };
+fn parse_time_kind(value: &str) -> TimeKind {
+    TimeKind::parse(value).unwrap_or_else(|| {
+        eprintln!("du-rs: invalid time '{}'", value);
+        exit(2);
+    })
+}
 
-    let mut writer = BufWriter::new(stdout());
-    let mut seen_inodes = FxHashSet::with_capacity_and_hasher(1024, Default::default());
-    let mut path_bytes = Vec::with_capacity(4096);
+fn parse_time_style(value: &str) -> TimeStyle {
+    TimeStyle::parse(value).unwrap_or_else(|| {
+        if value.starts_with('+') {
+            eprintln!(
+                "du-rs: invalid time format '{}'; see strftime(3) for the conversions",
+                value
+            );
+        } else {
+            eprintln!("du-rs: invalid time style '{}'", value);
+        }
+        exit(2);
+    })
+}
 
-    let current_dir = env::current_dir()?;
-    let is_current_dir = root_dir == &current_dir || root_dir.as_os_str() == OsStr::new(".");
+// What a line can show about an entry besides its path and size.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct EntryMeta {
+    time: Timestamp,
+    depth: i32,
+    inodes: u64,
+    // Files counted at and below the entry and their combined size, only
+    // filled in for the lines `-s --verbose` prints them on.
+    files: u64,
+    files_size: i64,
+}
 
-    if is_current_dir {
-        path_bytes.extend_from_slice(b".");
-    } else {
-        use std::os::unix::ffi::OsStrExt;
-        path_bytes.extend_from_slice(root_dir.as_os_str().as_bytes());
+impl EntryMeta {
+    // Anything but a directory counts as one file of its own size.
+    fn of(entry: &Visit<'_>) -> EntryMeta {
+        EntryMeta {
+            time: entry.mtime,
+            depth: entry.depth,
+            inodes: entry.inodes,
+            files: u64::from(!entry.is_dir),
+            files_size: if entry.is_dir { 0 } else { entry.size },
+        }
     }
+}
 
-    let total = recursive_dir_iter(
-        fd,
-        0,
-        &config,
-        &mut writer,
-        &mut seen_inodes,
-        &mut path_bytes,
-    )?;
+#[derive(PartialEq, Eq)]
+struct Record {
+    path: Vec<u8>,
+    size: i64,
+    meta: EntryMeta,
+}
 
-    writer.flush()?;
+// Orders by size, with ties ranking the lexically smaller path higher so the
+// `--top` heap evicts deterministically.
+impl Ord for Record {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.size
+            .cmp(&other.size)
+            .then_with(|| other.path.cmp(&self.path))
+    }
+}
 
-    Ok(total)
+impl PartialOrd for Record {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
-fn get_file_info(raw_fd: RawFd, args: &Args, file_path: OsString, at_flag: AtFlags) -> Cresult<()> {
-    let size_format = if !args.block_size.is_empty() {
-        SizeFormat::HumanReadable
-    } else if args.bytes {
-        SizeFormat::Bytes
-    } else if args.human_readable {
-        SizeFormat::HumanReadable
-    } else {
-        SizeFormat::Blocks
-    };
+struct EntryBuffer {
+    entries: Vec<Record>,
+    largest: BinaryHeap<Reverse<Record>>,
+    limit: Option<usize>,
+}
 
-    let mut buf_writer = BufWriter::new(stdout());
+impl EntryBuffer {
+    fn new(limit: Option<usize>) -> Self {
+        EntryBuffer {
+            entries: Vec::new(),
+            largest: BinaryHeap::new(),
+            limit,
+        }
+    }
 
-    let block_size = if args.block_size.is_empty() {
-        None
-    } else {
-        Some(args.block_size.clone())
-    };
+    fn push(&mut self, entry: Record) {
+        match self.limit {
+            Some(limit) => {
+                self.largest.push(Reverse(entry));
+                if self.largest.len() > limit {
+                    self.largest.pop();
+                }
+            }
+            None => self.entries.push(entry),
+        }
+    }
 
-    let meta = {
-        if let Ok(meta) = fstatat(Some(raw_fd), file_path.as_os_str(), at_flag) {
-            meta
-        } else {
-            return Ok(());
+    fn into_sorted(self, key: Option<SortKey>, reverse: bool) -> Vec<Record> {
+        let mut entries = self.entries;
+        entries.extend(self.largest.into_iter().map(|Reverse(entry)| entry));
+        if let Some(key) = key {
+            sort_entries(&mut entries, key, reverse);
         }
-    };
+        entries
+    }
+}
 
-    let file_stats = FileStats {
-        size: meta.st_size,
-        blocks: meta.st_blocks,
-    };
-    let file_size = size_format.get_file_size(&file_stats);
-    write_to_stdout(
-        &mut buf_writer,
-        file_size,
-        file_path.as_bytes(),
-        block_size.as_deref(),
-        args.human_readable,
-    )?;
-    Ok(())
+// The `--include` patterns, which pick what is printed but not what is
+// counted. Patterns are read as `--exclude` reads them.
+struct Includes {
+    globs: Vec<Glob>,
+    // Bytes of an entry's path before the part relative to its root.
+    prefix_len: usize,
+    // Entries shown since their parent directory was, at most one per
+    // directory. Entries arrive before the directory holding them, which is
+    // shown if any of these lie below it.
+    shown: Vec<Vec<u8>>,
 }
 
-fn recursive_dir_iter(
-    raw_fd: RawFd,
-    current_depth: i32,
-    config: &TraversalConfig,
-    writer: &mut BufWriter<std::io::Stdout>,
-    seen_inodes: &mut FxHashSet<(u64, u64)>,
-    path_bytes: &mut Vec<u8>,
-) -> Cresult<i64> {
-    let mut total_size: i64 = 0;
+impl Includes {
+    fn new(patterns: &[String], root: &Path) -> Includes {
+        Includes {
+            globs: patterns.iter().map(|p| Glob::new(p.as_bytes())).collect(),
+            prefix_len: root.as_os_str().len() + 1,
+            shown: Vec::new(),
+        }
+    }
 
-    let meta = {
-        if let Ok(meta) = fstatat(Some(raw_fd), OsStr::new("."), config.at_flag) {
-            meta
-        } else {
-            return Ok(0);
+    // A PATH that is not a directory is matched by its name alone.
+    fn of_file(patterns: &[String], path: &[u8]) -> Includes {
+        Includes {
+            prefix_len: path.iter().rposition(|&c| c == b'/').map_or(0, |i| i + 1),
+            ..Includes::new(patterns, Path::new(""))
         }
-    };
+    }
 
-    if let Some(dev) = config.root_dev {
-        if meta.st_dev != dev {
-            return Ok(0);
+    fn shows(&mut self, entry: &Visit<'_>) -> bool {
+        let relative = entry.path.get(self.prefix_len..).unwrap_or_default();
+        let name = relative.rsplit(|&c| c == b'/').next().unwrap_or_default();
+        let matches = self.globs.iter().any(|glob| match glob.anchor() {
+            Anchor::Basename => glob.is_match(name, entry.is_dir),
+            Anchor::Root => glob.is_match(relative, entry.is_dir),
+            Anchor::Absolute => glob.is_match(entry.path, entry.is_dir),
+        });
+
+        let mut holds_shown = false;
+        if entry.is_dir {
+            while self.shown.last().is_some_and(|path| {
+                path.starts_with(entry.path) && path.get(entry.path.len()) == Some(&b'/')
+            }) {
+                self.shown.pop();
+                holds_shown = true;
+            }
+        }
+        if !matches && !holds_shown {
+            return false;
+        }
+        fn parent(path: &[u8]) -> &[u8] {
+            &path[..path.iter().rposition(|&c| c == b'/').unwrap_or(0)]
         }
+        if self
+            .shown
+            .last()
+            .is_some_and(|last| parent(last) == parent(entry.path))
+        {
+            self.shown.pop();
+        }
+        self.shown.push(entry.path.to_vec());
+        true
     }
+}
 
-    let file_stats = FileStats {
-        size: meta.st_size,
-        blocks: meta.st_blocks,
-    };
-    total_size += config.size_format.get_dir_size(&file_stats);
+// Redraws a status line on stderr about ten times a second until dropped,
+// then clears it.
+struct ProgressLine {
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+    // Held while drawing, and by anything else printing to the terminal so
+    // that it can clear the line first.
+    drawing: Arc<Mutex<()>>,
+}
 
-    let dir = match Dir::from_fd(raw_fd) {
-        Ok(d) => d,
-        Err(_) => return Ok(total_size),
-    };
+impl ProgressLine {
+    fn start(progress: Arc<Progress>) -> ProgressLine {
+        let stop = Arc::new(AtomicBool::new(false));
+        let drawing = Arc::new(Mutex::new(()));
+        let handle = thread::spawn({
+            let stop = Arc::clone(&stop);
+            let drawing = Arc::clone(&drawing);
+            move || {
+                let started = Instant::now();
+                loop {
+                    thread::park_timeout(Duration::from_millis(100));
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
 
-    for entry in dir {
-        let entry = match entry {
-            Ok(e) => e,
-            Err(_) => continue,
-        };
+                    let entries = progress.entries();
+                    let rate = entries as f64 / started.elapsed().as_secs_f64().max(1e-3);
+                    let dir = progress.current_dir();
+                    let dir = &dir[dir.len().saturating_sub(60)..];
+                    let _drawing = drawing.lock();
+                    eprint!(
+                        "\r\x1b[K{} entries, {:.0}/s {}",
+                        entries,
+                        rate,
+                        String::from_utf8_lossy(dir)
+                    );
+                }
+            }
+        });
 
-        let file_name_bytes = entry.file_name().to_bytes();
-        if file_name_bytes == b"." || file_name_bytes == b".." {
-            continue;
+        ProgressLine {
+            stop,
+            handle: Some(handle),
+            drawing,
         }
+    }
+}
 
-        let file_name_osstr = OsStr::from_bytes(file_name_bytes);
-        let excluded = config.exclusion_paths.as_ref().map_or(false, |paths| {
-            let file_path = Path::new(file_name_osstr);
-            paths.contains(file_path)
-        }) || config
-            .exclusion_patterns
-            .as_ref()
-            .map_or(false, |patterns| {
-                Path::new(file_name_osstr)
-                    .extension()
-                    .map_or(false, |ext| patterns.contains(ext))
-            });
-
-        if excluded {
-            continue;
+// Clears the progress line, if one is shown, and keeps it from being drawn
+// again until the guard is dropped.
+fn hide_progress(drawing: &Option<Arc<Mutex<()>>>) -> Option<MutexGuard<'_, ()>> {
+    let guard = drawing.as_ref()?.lock().ok()?;
+    eprint!("\r\x1b[K");
+    Some(guard)
+}
+
+impl Drop for ProgressLine {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
         }
+        eprint!("\r\x1b[K");
+    }
+}
 
-        match entry.file_type() {
-            Some(nix::dir::Type::Directory) => {
-                if config.max_depth > 0 && current_depth >= config.max_depth {
-                    continue;
-                }
+// Writes the output on a thread of its own, in the order it is handed over,
+// so the walk does not stall on a slow terminal or pipe. Writes arrive in
+// chunks from the `BufWriter` in front of it.
+struct OutputThread {
+    chunks: Option<SyncSender<Chunk>>,
+    handle: Option<thread::JoinHandle<io::Result<()>>>,
+}
 
-                let sub_fd = {
-                    match openat(
-                        Some(raw_fd),
-                        file_name_osstr,
-                        config.open_flag,
-                        Mode::empty(),
-                    ) {
-                        Ok(fd) => fd,
-                        Err(_) => continue,
+enum Chunk {
+    Data(Vec<u8>),
+    // Answered once everything before it has been written out.
+    Flush(SyncSender<()>),
+}
+
+impl OutputThread {
+    // Chunks in flight, bounding what a stalled output holds in memory.
+    const QUEUE: usize = 64;
+
+    fn start(mut out: Output) -> OutputThread {
+        let (chunks, received) = sync_channel(OutputThread::QUEUE);
+        let handle = thread::spawn(move || {
+            for chunk in received {
+                match chunk {
+                    Chunk::Data(data) => out.write_all(&data)?,
+                    Chunk::Flush(done) => {
+                        out.flush()?;
+                        let _ = done.send(());
                     }
-                };
+                }
+            }
+            out.finish()
+        });
+        OutputThread {
+            chunks: Some(chunks),
+            handle: Some(handle),
+        }
+    }
 
-                let saved_len = path_bytes.len();
+    fn send(&mut self, chunk: Chunk) -> io::Result<()> {
+        let sent = match &self.chunks {
+            Some(chunks) => chunks.send(chunk).is_ok(),
+            None => false,
+        };
+        if sent {
+            Ok(())
+        } else {
+            // The thread only stops early on a write error; hand that back.
+            self.finish()
+                .and(Err(io::Error::from(io::ErrorKind::BrokenPipe)))
+        }
+    }
 
-                if !path_bytes.is_empty() {
-                    path_bytes.push(b'/');
-                }
-                path_bytes.extend_from_slice(file_name_bytes);
+    // Waits for everything sent to be written.
+    fn finish(&mut self) -> io::Result<()> {
+        self.chunks = None;
+        match self.handle.take().map(thread::JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(io::Error::other("output thread panicked")),
+            None => Ok(()),
+        }
+    }
+}
 
-                let subdir_size = recursive_dir_iter(
-                    sub_fd,
-                    current_depth + 1,
-                    config,
-                    writer,
-                    seen_inodes,
-                    path_bytes,
-                )?;
-                if !config.summarize && subdir_size >= config.threshold_size {
-                    write_to_stdout(
-                        writer,
-                        subdir_size,
-                        &path_bytes,
-                        config.block_size.as_deref(),
-                        config.format,
-                    )?;
-                }
+impl Write for OutputThread {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(Chunk::Data(buf.to_vec()))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let (done, flushed) = sync_channel(1);
+        self.send(Chunk::Flush(done))?;
+        match flushed.recv() {
+            Ok(()) => Ok(()),
+            Err(_) => self
+                .finish()
+                .and(Err(io::Error::from(io::ErrorKind::BrokenPipe))),
+        }
+    }
+}
+
+impl Drop for OutputThread {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+struct OutputConfig {
+    block_size: Option<String>,
+    format: bool,
+    json: bool,
+    csv: bool,
+    line_end: u8,
+    // Written between columns in place of the padded layout when set.
+    separator: Option<String>,
+    // The current directory, set when paths are printed absolute.
+    cwd: Option<PathBuf>,
+    // Where paths below it are printed relative to instead, absolute itself.
+    relative_to: Option<PathBuf>,
+    no_leading_dot: bool,
+    template: Option<Template>,
+    time_style: Option<TimeStyle>,
+    // Whether lines show how many files an entry holds and their average size.
+    file_counts: bool,
+    si: bool,
+    human_rounding: Rounding,
+    block_rounding: Rounding,
+    precision: usize,
+    decimal_point: char,
+    color: bool,
+    // Bytes per unit of the sizes handed to `write_record`.
+    unit: i64,
+    // What the sizes measure, saved with `--json` records for `--diff`.
+    measure: &'static str,
+}
 
-                total_size += subdir_size;
+struct WriterVisitor<'a, W: Write> {
+    writer: &'a mut W,
+    // Where entries that could not be read are reported.
+    warnings: &'a mut dyn Write,
+    output: &'a OutputConfig,
+    // Holds entries back until the traversal is done when sorting.
+    pending: Option<EntryBuffer>,
+    includes: Option<Includes>,
+    quiet: bool,
+    strict: bool,
+    errors: u64,
+    // The lock of the progress line when one is shown.
+    progress: Option<Arc<Mutex<()>>>,
+    // Whether entries go to the terminal the progress line is drawn on.
+    entries_under_progress: bool,
+}
 
-                path_bytes.truncate(saved_len);
+impl<W: Write> Visitor for WriterVisitor<'_, W> {
+    fn visit(&mut self, entry: Visit<'_>) -> Cresult<()> {
+        if self
+            .includes
+            .as_mut()
+            .is_some_and(|includes| !includes.shows(&entry))
+        {
+            return Ok(());
+        }
+        match &mut self.pending {
+            Some(pending) => {
+                pending.push(Record {
+                    path: entry.path.to_vec(),
+                    size: entry.size,
+                    meta: EntryMeta::of(&entry),
+                });
+                Ok(())
+            }
+            None if self.entries_under_progress => {
+                let _hidden = hide_progress(&self.progress);
+                write_record(
+                    self.writer,
+                    entry.size,
+                    &shown_path(entry.path, self.output),
+                    EntryMeta::of(&entry),
+                    self.output,
+                )?;
+                // Out before the line is drawn again below it.
+                self.writer.flush()?;
+                Ok(())
             }
+            None => write_record(
+                self.writer,
+                entry.size,
+                &shown_path(entry.path, self.output),
+                EntryMeta::of(&entry),
+                self.output,
+            ),
+        }
+    }
 
-            _ => {
-                let child_meta = {
-                    match fstatat(Some(raw_fd), file_name_osstr, config.at_flag) {
-                        Ok(m) => m,
-                        Err(_) => continue,
-                    }
-                };
+    fn error(&mut self, error: WalkError<'_>) -> Cresult<()> {
+        self.errors += 1;
+        if !self.quiet {
+            let _hidden = hide_progress(&self.progress);
+            writeln!(self.warnings, "du-rs: {}", error)?;
+        }
+        if self.strict {
+            return Err(Failed.into());
+        }
+        Ok(())
+    }
 
-                if !config.count_hard_link && child_meta.st_nlink > 1 {
-                    let inode = (child_meta.st_dev, child_meta.st_ino);
-                    if !seen_inodes.insert(inode) {
-                        continue;
-                    }
-                }
+    fn sparse(&mut self, file: SparseFile<'_>) -> Cresult<()> {
+        let _hidden = hide_progress(&self.progress);
+        writeln!(
+            self.warnings,
+            "du-rs: note: '{}' is sparse: {} apparent, {} allocated",
+            String::from_utf8_lossy(file.path),
+            human_size(file.size, self.output),
+            human_size(file.allocated, self.output)
+        )?;
+        Ok(())
+    }
 
-                let file_stats = FileStats {
-                    size: child_meta.st_size,
-                    blocks: child_meta.st_blocks,
-                };
+    fn skipped(&mut self, skipped: Skipped<'_>) -> Cresult<()> {
+        let _hidden = hide_progress(&self.progress);
+        writeln!(self.warnings, "du-rs: {}", skipped)?;
+        Ok(())
+    }
+}
 
-                let file_size = config.size_format.get_file_size(&file_stats);
-                total_size += file_size;
+impl<'a, W: Write> WriterVisitor<'a, W> {
+    // Writes every entry as it comes, with nothing held back or left out.
+    fn new(
+        args: &Args,
+        output: &'a OutputConfig,
+        writer: &'a mut W,
+        warnings: &'a mut dyn Write,
+    ) -> WriterVisitor<'a, W> {
+        WriterVisitor {
+            writer,
+            warnings,
+            output,
+            pending: None,
+            includes: None,
+            quiet: args.quiet,
+            strict: args.strict,
+            errors: 0,
+            progress: None,
+            entries_under_progress: false,
+        }
+    }
+}
+
+fn process_directories(
+    args: &Args,
+    scanner: &mut Scanner,
+    root_dir: &PathBuf,
+    writer: &mut impl Write,
+    warnings: &mut dyn Write,
+    errors: &mut u64,
+) -> Cresult<du_rs::DirUsage> {
+    let progress = scanner.options().progress.clone();
+    let tree = args.tree.filter(|_| !args.json && !args.csv);
+    let current_dir = env::current_dir()?;
+    let is_current_dir = root_dir == &current_dir || root_dir.as_os_str() == OsStr::new(".");
+    let root = if is_current_dir {
+        Path::new(".")
+    } else {
+        root_dir.as_path()
+    };
 
-                if config.list_files && !config.summarize && file_size >= config.threshold_size {
-                    let saved_len = path_bytes.len();
+    let output = args.output_config();
+    let percent = args.threshold_percent();
+    let progress_line = progress.map(ProgressLine::start);
+    let mut visitor = WriterVisitor {
+        // A percentage threshold needs the total first.
+        pending: (args.sort.is_some() || tree.is_some() || percent.is_some())
+            .then(|| EntryBuffer::new(args.top.filter(|_| percent.is_none()))),
+        includes: (!args.include.is_empty()).then(|| Includes::new(&args.include, root)),
+        progress: progress_line.as_ref().map(|line| Arc::clone(&line.drawing)),
+        entries_under_progress: progress_line.is_some()
+            && args.output_file.is_none()
+            && stdout().is_terminal(),
+        ..WriterVisitor::new(args, &output, writer, warnings)
+    };
 
-                    if !path_bytes.is_empty() {
-                        path_bytes.push(b'/');
-                    }
-                    path_bytes.extend_from_slice(file_name_bytes);
-
-                    write_to_stdout(
-                        writer,
-                        file_size,
-                        &path_bytes,
-                        config.block_size.as_deref(),
-                        config.format,
-                    )?;
+    let usage = scanner.walk(root, &mut visitor);
+    visitor.progress = None;
+    drop(progress_line);
+    let usage = usage?;
+
+    if let Some(percent) = percent {
+        let pending = visitor
+            .pending
+            .take()
+            .unwrap_or_else(|| EntryBuffer::new(None));
+        let limit = usage.total as f64 * percent.abs() / 100.0;
+        let mut kept = EntryBuffer::new(args.top);
+        for entry in pending.into_sorted(None, false) {
+            let size = entry.size as f64;
+            if (percent >= 0.0 && size >= limit) || (percent < 0.0 && size <= limit) {
+                kept.push(entry);
+            }
+        }
+        visitor.pending = Some(kept);
+    }
 
-                    path_bytes.truncate(saved_len);
+    let root = Record {
+        path: root.as_os_str().as_encoded_bytes().to_vec(),
+        size: args.root_size(&usage),
+        meta: EntryMeta {
+            time: usage.mtime,
+            depth: 0,
+            inodes: usage.inodes,
+            files: usage.files,
+            files_size: usage.files_size,
+        },
+    };
+    let shows_root = args.prints_entries()
+        && args
+            .entries_threshold
+            .is_none_or(|min| usage.entries >= min);
+
+    // The root's own line, unless the entries already took it in.
+    let root = match (tree, visitor.pending.take()) {
+        (Some(style), Some(pending)) => {
+            let records = pending.into_sorted(None, false);
+            write_tree(visitor.writer, root, records, args, style, &output)?;
+            None
+        }
+        (None, Some(mut pending)) => {
+            // Sorted with the rest, so it also counts toward `--top`.
+            let root = match args.sort {
+                Some(_) if shows_root => {
+                    pending.push(root);
+                    None
                 }
+                _ => Some(root),
+            };
+            for entry in &pending.into_sorted(args.sort, args.reverse) {
+                let path = shown_path(&entry.path, &output);
+                write_record(visitor.writer, entry.size, &path, entry.meta, &output)?;
             }
+            root
+        }
+        (_, None) => Some(root),
+    };
+    if let Some(root) = root.filter(|_| shows_root) {
+        let path = shown_path(&root.path, &output);
+        write_record(visitor.writer, root.size, &path, root.meta, &output)?;
+    }
+
+    *errors += visitor.errors;
+    Ok(usage)
+}
+
+// `meta` is the stat `main` already took to tell files from directories. The
+// file is reported as an entry, filtered as any other would be, into `files`
+// when sorting so that PATHs given one after another are sorted together.
+fn report_file(
+    meta: &Metadata,
+    args: &Args,
+    scanner: &mut Scanner,
+    file_path: &OsStr,
+    writer: &mut impl Write,
+    warnings: &mut dyn Write,
+    files: &mut Option<EntryBuffer>,
+) -> Cresult<(i64, EntryMeta)> {
+    let size_format = args.size_format();
+    let file_stats = FileStats {
+        size: meta.size,
+        blocks: meta.blocks,
+    };
+    let file_size = size_format.get_file_size(&file_stats);
+    if !scanner.options().counts_file(file_size, mtime_of(meta)) {
+        return Ok((0, EntryMeta::default()));
+    }
+
+    if meta.nlink > 1 && !scanner.count_link(meta.dev, meta.ino) {
+        return Ok((0, EntryMeta::default()));
+    }
+
+    let output = args.output_config();
+    let mut visitor = WriterVisitor {
+        pending: files
+            .take()
+            .or_else(|| args.sort.map(|_| EntryBuffer::new(args.top))),
+        includes: (!args.include.is_empty())
+            .then(|| Includes::of_file(&args.include, file_path.as_encoded_bytes())),
+        ..WriterVisitor::new(args, &output, writer, warnings)
+    };
+    let entry = Visit {
+        path: file_path.as_encoded_bytes(),
+        size: file_size,
+        mtime: args.time_kind.of(meta),
+        is_dir: false,
+        depth: 0,
+        inodes: 1,
+    };
+    if let Some(histogram) = &scanner.options().histogram {
+        histogram.record(size_format.get_file_bytes(&file_stats) as u64);
+    }
+    if let Some(depth_sizes) = &scanner.options().depth_sizes {
+        depth_sizes.add(0, file_size);
+    }
+    if let Some(extensions) = &scanner.options().extensions {
+        let name = Path::new(file_path).file_name().unwrap_or(file_path);
+        extensions.record(name.as_encoded_bytes(), file_size);
+    }
+    if meta.is_file() {
+        if let Some(duplicates) = &scanner.options().duplicates {
+            duplicates.record(file_path.as_encoded_bytes(), meta.size as u64);
+        }
+        if scanner.options().warn_sparse && file_stats.is_sparse() {
+            visitor.sparse(SparseFile {
+                path: file_path.as_encoded_bytes(),
+                size: file_stats.size,
+                allocated: file_stats.blocks * ST_BLOCK_SIZE,
+            })?;
+        }
+    }
+    let meta = EntryMeta::of(&entry);
+    if args.prints_entries() && args.entries_threshold.is_none() {
+        if scanner.options().shows_size(file_size) {
+            visitor.visit(entry)?;
+        } else if scanner.options().report_skipped {
+            visitor.skipped(Skipped {
+                path: file_path.as_encoded_bytes(),
+                reason: SkipReason::Threshold,
+            })?;
+        }
+    }
+    *files = visitor.pending.take();
+    Ok((file_size, meta))
+}
+
+// Writes the entries of the PATHs `report_file` sorted together.
+fn write_file_entries(
+    writer: &mut impl Write,
+    files: Option<EntryBuffer>,
+    args: &Args,
+    output: &OutputConfig,
+) -> Cresult<()> {
+    for entry in files
+        .map(|files| files.into_sorted(args.sort, args.reverse))
+        .unwrap_or_default()
+    {
+        let path = shown_path(&entry.path, output);
+        write_record(writer, entry.size, &path, entry.meta, output)?;
+    }
+    Ok(())
+}
+
+// One line per size range from the smallest to the largest one holding files,
+// with a bar scaled to the fullest range.
+fn write_histogram(
+    writer: &mut impl Write,
+    histogram: &Histogram,
+    output: &OutputConfig,
+) -> Cresult<()> {
+    const BAR_WIDTH: u64 = 40;
+
+    let bins = histogram.bins();
+    let Some(first) = bins.iter().position(|bin| bin.files > 0) else {
+        return Ok(());
+    };
+    let last = bins.iter().rposition(|bin| bin.files > 0).unwrap_or(first);
+    let fullest = bins.iter().map(|bin| bin.files).max().unwrap_or(1);
+
+    writeln!(writer, "{:>8}  {:>10}  {:>8}", "SIZE", "FILES", "BYTES")?;
+    for (i, bin) in bins.iter().enumerate().take(last + 1).skip(first) {
+        let range = match bin.below {
+            Some(1) => "0".to_string(),
+            Some(below) => format!("< {}", bin_size_label(below)),
+            None => format!(">= {}", bin_size_label(bins[i - 1].below.unwrap_or(0))),
+        };
+        write!(
+            writer,
+            "{:>8}  {:>10}  {:>8}",
+            range,
+            bin.files,
+            human_size(bin.bytes as i64, output)
+        )?;
+        if bin.files > 0 {
+            let bar = (bin.files * BAR_WIDTH / fullest).max(1);
+            write!(writer, "  {}", "#".repeat(bar as usize))?;
+        }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+// One line per depth, the root first. Levels below `--max-depth` are added to
+// the deepest one allowed, which is then marked with a `+`.
+fn write_depth_sizes(
+    writer: &mut impl Write,
+    depth_sizes: &DepthSizes,
+    max_depth: Option<i32>,
+    output: &OutputConfig,
+) -> Cresult<()> {
+    let mut levels = depth_sizes.levels();
+    let mut capped = false;
+    if let Some(max) = max_depth.map(|max| max as usize) {
+        if levels.len() > max + 1 {
+            let below = levels.drain(max + 1..).fold(0, i64::saturating_add);
+            levels[max] = levels[max].saturating_add(below);
+            capped = true;
+        }
+    }
+
+    let last = levels.len().saturating_sub(1);
+    for (depth, &size) in levels.iter().enumerate() {
+        let label = if capped && depth == last {
+            format!("depth {}+", depth)
+        } else {
+            format!("depth {}", depth)
+        };
+        let entry = EntryMeta {
+            depth: depth as i32,
+            ..EntryMeta::default()
+        };
+        write_record(writer, size, label.as_bytes(), entry, output)?;
+    }
+    Ok(())
+}
+
+// Files without an extension are listed as `(none)`, after a `.` that no
+// extension could be confused with.
+fn write_extensions(
+    writer: &mut impl Write,
+    extensions: &ExtensionSizes,
+    output: &OutputConfig,
+) -> Cresult<()> {
+    for total in extensions.totals() {
+        let label = match &total.extension {
+            Some(extension) => [b".", &extension[..]].concat(),
+            None => b"(none)".to_vec(),
+        };
+        let entry = EntryMeta {
+            files: total.files,
+            files_size: total.size,
+            ..EntryMeta::default()
+        };
+        write_record(writer, total.size, &label, entry, output)?;
+    }
+    Ok(())
+}
+
+fn write_duplicates(
+    writer: &mut impl Write,
+    groups: &[DuplicateGroup],
+    output: &OutputConfig,
+) -> Cresult<()> {
+    let mut reclaimable = 0;
+    for group in groups {
+        reclaimable += group.reclaimable();
+        writeln!(
+            writer,
+            "{} files of {}, {} reclaimable:",
+            group.paths.len(),
+            human_size(group.size as i64, output),
+            human_size(group.reclaimable() as i64, output)
+        )?;
+        for path in &group.paths {
+            writer.write_all(b"  ")?;
+            writer.write_all(path.as_os_str().as_encoded_bytes())?;
+            writer.write_all(b"\n")?;
+        }
+        writeln!(writer)?;
+    }
+    writeln!(
+        writer,
+        "{} reclaimable in {} groups of duplicates",
+        human_size(reclaimable as i64, output),
+        groups.len()
+    )?;
+    Ok(())
+}
+
+fn bin_size_label(bytes: u64) -> String {
+    UNITS
+        .iter()
+        .rev()
+        .find(|(_, factor)| bytes as f64 >= *factor && bytes.is_multiple_of(*factor as u64))
+        .map_or_else(
+            || bytes.to_string(),
+            |(unit, factor)| format!("{}{}", bytes / *factor as u64, unit),
+        )
+}
+
+fn sort_entries(entries: &mut [Record], key: SortKey, reverse: bool) {
+    match key {
+        SortKey::Size => {
+            entries.sort_unstable_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)))
         }
+        SortKey::Name => entries.sort_unstable_by(|a, b| a.path.cmp(&b.path)),
+    }
+    if reverse {
+        entries.reverse();
+    }
+}
+
+// `path` as walked, made absolute under `--absolute`, or relative to
+// `--relative-to` where that holds it.
+fn shown_path<'a>(path: &'a [u8], output: &OutputConfig) -> Cow<'a, [u8]> {
+    let Some(cwd) = &output.cwd else {
+        return Cow::Borrowed(if output.no_leading_dot {
+            without_leading_dot(path)
+        } else {
+            path
+        });
+    };
+    let path = absolute_path(Path::new(&platform::os_str(path)), cwd);
+    match output
+        .relative_to
+        .as_ref()
+        .and_then(|base| path.strip_prefix(base).ok())
+    {
+        Some(rest) if rest.as_os_str().is_empty() => Cow::Borrowed(b"."),
+        Some(rest) => Cow::Owned(rest.as_os_str().as_encoded_bytes().to_vec()),
+        None => Cow::Owned(path.into_os_string().into_encoded_bytes()),
+    }
+}
+
+// `./a` and `.//a` become `a`, while `.` and `./` become `.`.
+fn without_leading_dot(mut path: &[u8]) -> &[u8] {
+    while let Some(rest) = path.strip_prefix(b"./") {
+        let slashes = rest.iter().take_while(|&&b| b == b'/').count();
+        path = &rest[slashes..];
+    }
+    if path.is_empty() {
+        b"."
+    } else {
+        path
     }
+}
 
-    Ok(total_size)
+// Taken lexically: `..` drops the component before it, without looking at
+// whether that was a symbolic link.
+fn absolute_path(path: &Path, cwd: &Path) -> PathBuf {
+    let mut absolute = PathBuf::new();
+    for component in cwd.join(path).components() {
+        match component {
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            component => absolute.push(component),
+        }
+    }
+    absolute
 }
 
-fn write_to_stdout(
-    writer: &mut BufWriter<std::io::Stdout>,
+fn write_record(
+    writer: &mut impl Write,
     size: i64,
     path_bytes: &[u8],
-    block_size: Option<&str>,
-    format: bool,
+    entry: EntryMeta,
+    output: &OutputConfig,
 ) -> Cresult<()> {
-    let size_str = if let Some(bs) = block_size {
-        format_size(size, bs)?
-    } else if format {
-        get_file_sizes(None, Some(size))
-    } else {
-        let mut buffer = itoa::Buffer::new();
-        buffer.format(size).to_owned()
-    };
+    let time = entry.time;
+    if output.json {
+        return write_json_record(writer, size, path_bytes, time, false, output);
+    }
+    if output.csv {
+        return write_csv_record(writer, size, path_bytes, time, output);
+    }
+    if let Some(template) = &output.template {
+        return write_template_record(writer, template, size, path_bytes, entry, output);
+    }
 
+    let size_str = size_string(size, output)?;
     let size_len = size_str.len();
-    writer.write_all(size_str.as_bytes())?;
+    write_size(writer, size, &size_str, output)?;
+
+    let separator = match &output.separator {
+        Some(separator) => separator.as_bytes(),
+        None => {
+            if size_len < 10 {
+                static SPACES: &[u8] = b"          ";
+                writer.write_all(&SPACES[..10 - size_len])?;
+            }
+            b" "
+        }
+    };
+    writer.write_all(separator)?;
 
-    if size_len < 10 {
-        static SPACES: &[u8] = b"          ";
-        writer.write_all(&SPACES[..10 - size_len])?;
+    if let Some(style) = &output.time_style {
+        writer.write_all(style.format(time).as_bytes())?;
+        writer.write_all(separator)?;
     }
 
-    writer.write_all(b" ")?;
+    if output.file_counts {
+        let average = match entry.files {
+            0 => 0,
+            files => entry.files_size / files as i64,
+        };
+        write!(
+            writer,
+            "{} file{}, avg {}",
+            entry.files,
+            if entry.files == 1 { "" } else { "s" },
+            size_string(average, output)?
+        )?;
+        writer.write_all(separator)?;
+    }
 
     writer.write_all(path_bytes)?;
 
-    writer.write_all(b"\n")?;
+    writer.write_all(&[output.line_end])?;
 
     Ok(())
 }
 
-fn main() -> Cresult<()> {
-    let g_args = handle_args();
-    let current_dir = env::current_dir()?;
-    let follow_symlink = g_args.follow_symlinks;
+fn size_string(size: i64, output: &OutputConfig) -> Cresult<String> {
+    Ok(if let Some(bs) = output.block_size.as_deref() {
+        format_size(size, bs, output.block_rounding)?
+    } else if output.format {
+        human_size(size, output)
+    } else {
+        let mut buffer = itoa::Buffer::new();
+        buffer.format(size).to_owned()
+    })
+}
+
+fn human_size(bytes: i64, output: &OutputConfig) -> String {
+    get_file_sizes(
+        None,
+        Some(bytes),
+        output.si,
+        output.human_rounding,
+        output.precision,
+        output.decimal_point,
+    )
+}
 
-    let at_flag = if !follow_symlink {
-        AtFlags::AT_SYMLINK_NOFOLLOW
+fn write_size(
+    writer: &mut impl Write,
+    size: i64,
+    size_str: &str,
+    output: &OutputConfig,
+) -> Cresult<()> {
+    if output.color {
+        writer.write_all(size_color(size.saturating_mul(output.unit)))?;
+        writer.write_all(size_str.as_bytes())?;
+        writer.write_all(COLOR_RESET)?;
     } else {
-        AtFlags::empty()
+        writer.write_all(size_str.as_bytes())?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TreeStyle {
+    Unicode,
+    Ascii,
+}
+
+impl TreeStyle {
+    // Connectors for a middle child, the last child, and the indentation
+    // below each of them.
+    fn branches(self) -> [&'static str; 4] {
+        match self {
+            TreeStyle::Unicode => ["├── ", "└── ", "│   ", "    "],
+            TreeStyle::Ascii => ["|-- ", "`-- ", "|   ", "    "],
+        }
+    }
+}
+
+struct TreeNode {
+    record: Record,
+    children: Vec<usize>,
+}
+
+// Rebuilds the hierarchy from the reported paths and prints it below `root`.
+// An entry whose parent was filtered out hangs off its closest reported
+// ancestor.
+fn write_tree(
+    writer: &mut impl Write,
+    root: Record,
+    records: Vec<Record>,
+    args: &Args,
+    style: TreeStyle,
+    output: &OutputConfig,
+) -> Cresult<()> {
+    let mut nodes: Vec<TreeNode> = std::iter::once(root)
+        .chain(records)
+        .map(|record| TreeNode {
+            record,
+            children: Vec::new(),
+        })
+        .collect();
+    let index: HashMap<Vec<u8>, usize> = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| (node.record.path.clone(), i))
+        .collect();
+
+    for i in 1..nodes.len() {
+        let mut parent = 0;
+        let mut path = nodes[i].record.path.as_slice();
+        while let Some(slash) = path.iter().rposition(|&b| b == b'/') {
+            path = &path[..slash];
+            if let Some(&found) = index.get(path) {
+                parent = found;
+                break;
+            }
+        }
+        nodes[parent].children.push(i);
+    }
+
+    for node in 0..nodes.len() {
+        let mut children = std::mem::take(&mut nodes[node].children);
+        children.sort_by(|&a, &b| {
+            let (a, b) = (&nodes[a].record, &nodes[b].record);
+            match args.sort {
+                Some(SortKey::Size) => b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)),
+                _ => a.path.cmp(&b.path),
+            }
+        });
+        if args.reverse {
+            children.reverse();
+        }
+        nodes[node].children = children;
+    }
+
+    let mut prefix = String::new();
+    write_tree_node(writer, &nodes, 0, &mut prefix, None, style, output)
+}
+
+fn write_tree_node(
+    writer: &mut impl Write,
+    nodes: &[TreeNode],
+    node: usize,
+    prefix: &mut String,
+    last: Option<bool>,
+    style: TreeStyle,
+    output: &OutputConfig,
+) -> Cresult<()> {
+    let [branch, last_branch, indent, last_indent] = style.branches();
+    let record = &nodes[node].record;
+
+    writer.write_all(prefix.as_bytes())?;
+    let name = match last {
+        Some(last) => {
+            writer.write_all(if last { last_branch } else { branch }.as_bytes())?;
+            let start = record
+                .path
+                .iter()
+                .rposition(|&b| b == b'/')
+                .map_or(0, |i| i + 1);
+            &record.path[start..]
+        }
+        None => &shown_path(&record.path, output),
     };
 
-    let open_flag = if !follow_symlink {
-        OFlag::O_DIRECTORY | OFlag::O_RDONLY | OFlag::O_NOFOLLOW
+    writer.write_all(b"[")?;
+    write_size(
+        writer,
+        record.size,
+        &size_string(record.size, output)?,
+        output,
+    )?;
+    writer.write_all(b"]  ")?;
+    if let Some(style) = &output.time_style {
+        writer.write_all(style.format(record.meta.time).as_bytes())?;
+        writer.write_all(b" ")?;
+    }
+    writer.write_all(name)?;
+    writer.write_all(&[output.line_end])?;
+
+    let saved_len = prefix.len();
+    if let Some(last) = last {
+        prefix.push_str(if last { last_indent } else { indent });
+    }
+    let children = &nodes[node].children;
+    for (i, &child) in children.iter().enumerate() {
+        let is_last = i + 1 == children.len();
+        write_tree_node(writer, nodes, child, prefix, Some(is_last), style, output)?;
+    }
+    prefix.truncate(saved_len);
+    Ok(())
+}
+
+const COLOR_RESET: &[u8] = b"\x1b[0m";
+
+// Under 1M is green, under 1G yellow and anything larger red.
+fn size_color(bytes: i64) -> &'static [u8] {
+    if bytes < 1 << 20 {
+        b"\x1b[32m"
+    } else if bytes < 1 << 30 {
+        b"\x1b[33m"
+    } else {
+        b"\x1b[31m"
+    }
+}
+
+fn write_template_record(
+    writer: &mut impl Write,
+    template: &Template,
+    size: i64,
+    path_bytes: &[u8],
+    entry: EntryMeta,
+    output: &OutputConfig,
+) -> Cresult<()> {
+    let bytes = size.saturating_mul(output.unit);
+    let mut buffer = itoa::Buffer::new();
+    for field in &template.fields {
+        match field {
+            Field::Text(text) => writer.write_all(text)?,
+            Field::Size => writer.write_all(size_string(size, output)?.as_bytes())?,
+            Field::Human => writer.write_all(human_size(bytes, output).as_bytes())?,
+            Field::Blocks => {
+                let blocks = bytes.saturating_add(1023) / 1024;
+                writer.write_all(buffer.format(blocks).as_bytes())?
+            }
+            Field::Inodes => writer.write_all(buffer.format(entry.inodes).as_bytes())?,
+            Field::Path => writer.write_all(path_bytes)?,
+            Field::Mtime => writer.write_all(template.time_style.format(entry.time).as_bytes())?,
+            Field::Depth => writer.write_all(buffer.format(entry.depth).as_bytes())?,
+        }
+    }
+    writer.write_all(&[output.line_end])?;
+    Ok(())
+}
+
+fn write_json_record(
+    writer: &mut impl Write,
+    size: i64,
+    path_bytes: &[u8],
+    time: Timestamp,
+    total: bool,
+    output: &OutputConfig,
+) -> Cresult<()> {
+    // Always bytes, whatever unit the sizes are counted or printed in.
+    let bytes = size.saturating_mul(output.unit);
+
+    match std::str::from_utf8(path_bytes) {
+        Ok(path) => {
+            writer.write_all(b"{\"path\":\"")?;
+            write_json_escaped(writer, path)?;
+            writer.write_all(b"\"")?;
+        }
+        Err(_) => {
+            writer.write_all(b"{\"path_bytes\":[")?;
+            let mut buffer = itoa::Buffer::new();
+            for (i, &byte) in path_bytes.iter().enumerate() {
+                if i > 0 {
+                    writer.write_all(b",")?;
+                }
+                writer.write_all(buffer.format(byte).as_bytes())?;
+            }
+            writer.write_all(b"]")?;
+        }
+    }
+
+    let mut buffer = itoa::Buffer::new();
+    writer.write_all(b",\"bytes\":")?;
+    writer.write_all(buffer.format(bytes).as_bytes())?;
+    writer.write_all(b",\"measure\":\"")?;
+    writer.write_all(output.measure.as_bytes())?;
+    writer.write_all(b"\"")?;
+
+    if let Some(style) = &output.time_style {
+        writer.write_all(b",\"time\":\"")?;
+        write_json_escaped(writer, &style.format(time))?;
+        writer.write_all(b"\"")?;
+    }
+
+    if total {
+        writer.write_all(b",\"total\":true")?;
+    }
+
+    writer.write_all(b"}")?;
+    writer.write_all(&[output.line_end])?;
+
+    Ok(())
+}
+
+fn write_csv_header(writer: &mut impl Write, output: &OutputConfig) -> Cresult<()> {
+    writer.write_all(b"size_bytes,size_human,path")?;
+    if output.time_style.is_some() {
+        writer.write_all(b",time")?;
+    }
+    writer.write_all(&[output.line_end])?;
+    Ok(())
+}
+
+// JSON and CSV already carry sizes in bytes, so this only precedes the
+// plain, tree and template layouts.
+fn write_size_header(writer: &mut impl Write, args: &Args, output: &OutputConfig) -> Cresult<()> {
+    let unit = if let Some(bs) = output.block_size.as_deref() {
+        match block_size_bytes(bs)? {
+            1 => "bytes".to_string(),
+            bytes => format!("{}-byte blocks", bytes),
+        }
+    } else if output.format {
+        format!(
+            "human-readable units, powers of {}",
+            if output.si { 1000 } else { 1024 }
+        )
+    } else if output.unit == 1 {
+        "bytes".to_string()
     } else {
-        OFlag::O_DIRECTORY | OFlag::O_RDONLY
+        format!("{}-byte blocks", output.unit)
     };
+    let apparent = matches!(args.size_format(), SizeFormat::Bytes | SizeFormat::Apparent);
+    write!(
+        writer,
+        "# {} in {}",
+        if apparent { "apparent sizes" } else { "sizes" },
+        unit
+    )?;
+    writer.write_all(&[output.line_end])?;
+    Ok(())
+}
+
+fn write_csv_record(
+    writer: &mut impl Write,
+    size: i64,
+    path_bytes: &[u8],
+    time: Timestamp,
+    output: &OutputConfig,
+) -> Cresult<()> {
+    let bytes = size.saturating_mul(output.unit);
+    let mut buffer = itoa::Buffer::new();
+    writer.write_all(buffer.format(bytes).as_bytes())?;
+    writer.write_all(b",")?;
+    writer.write_all(human_size(bytes, output).as_bytes())?;
+    writer.write_all(b",")?;
+    write_csv_field(writer, path_bytes)?;
+
+    if let Some(style) = &output.time_style {
+        writer.write_all(b",")?;
+        write_csv_field(writer, style.format(time).as_bytes())?;
+    }
+
+    writer.write_all(&[output.line_end])?;
+    Ok(())
+}
+
+// Quotes fields holding separators, quotes or line breaks as RFC 4180 asks.
+fn write_csv_field(writer: &mut impl Write, field: &[u8]) -> Cresult<()> {
+    if !field
+        .iter()
+        .any(|&b| matches!(b, b',' | b'"' | b'\n' | b'\r'))
+    {
+        writer.write_all(field)?;
+        return Ok(());
+    }
 
-    for path in &g_args.path {
-        let fd = match open(&current_dir, open_flag, Mode::empty()) {
-            Ok(fd) => fd,
-            Err(_) => continue,
+    writer.write_all(b"\"")?;
+    for (i, part) in field.split(|&b| b == b'"').enumerate() {
+        if i > 0 {
+            writer.write_all(b"\"\"")?;
+        }
+        writer.write_all(part)?;
+    }
+    writer.write_all(b"\"")?;
+    Ok(())
+}
+
+fn write_json_escaped(writer: &mut impl Write, s: &str) -> Cresult<()> {
+    let bytes = s.as_bytes();
+    let mut start = 0;
+
+    for (i, &byte) in bytes.iter().enumerate() {
+        let escaped: &[u8] = match byte {
+            b'"' => b"\\\"",
+            b'\\' => b"\\\\",
+            b'\n' => b"\\n",
+            b'\r' => b"\\r",
+            b'\t' => b"\\t",
+            0x08 => b"\\b",
+            0x0c => b"\\f",
+            0x00..=0x1f => {
+                writer.write_all(&bytes[start..i])?;
+                write!(writer, "\\u{:04x}", byte)?;
+                start = i + 1;
+                continue;
+            }
+            _ => continue,
         };
+        writer.write_all(&bytes[start..i])?;
+        writer.write_all(escaped)?;
+        start = i + 1;
+    }
+    writer.write_all(&bytes[start..])?;
+
+    Ok(())
+}
+
+// Where the output goes: stdout or `--output`, which `--compress` encodes
+// on its way to the file.
+enum Output {
+    Plain(Box<dyn Write + Send>),
+    // Taken out once finished, which ends the compressed stream.
+    Gzip(Option<GzEncoder<File>>),
+    Zstd(Option<zstd::Encoder<'static, File>>),
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Plain(out) => out.write(buf),
+            Output::Gzip(Some(encoder)) => encoder.write(buf),
+            Output::Zstd(Some(encoder)) => encoder.write(buf),
+            Output::Gzip(None) | Output::Zstd(None) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Plain(out) => out.flush(),
+            Output::Gzip(encoder) => encoder.as_mut().map_or(Ok(()), Write::flush),
+            Output::Zstd(encoder) => encoder.as_mut().map_or(Ok(()), Write::flush),
+        }
+    }
+}
+
+impl Output {
+    // Flushes, and ends a compressed FILE.
+    fn finish(&mut self) -> io::Result<()> {
+        match self {
+            Output::Plain(out) => out.flush(),
+            Output::Gzip(encoder) => encoder.take().map_or(Ok(()), |e| e.finish().map(drop)),
+            Output::Zstd(encoder) => encoder.take().map_or(Ok(()), |e| e.finish().map(drop)),
+        }
+    }
+}
+
+// Leaving early still ends a compressed FILE properly, rather than leaving a
+// cut-off stream behind.
+impl Drop for Output {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
 
-        match fstatat(Some(fd), path.as_os_str(), at_flag) {
-            Ok(meta)
-                if (meta.st_mode & nix::sys::stat::SFlag::S_IFMT.bits())
-                    == nix::sys::stat::SFlag::S_IFREG.bits() =>
-            {
-                get_file_info(fd, &g_args, path.as_os_str().to_os_string(), at_flag)?;
+fn open_output(args: &Args) -> Output {
+    let Some(path) = &args.output_file else {
+        return Output::Plain(Box::new(stdout()));
+    };
+    let file = create_file(path, args.append);
+    match args.compress {
+        None => Output::Plain(Box::new(file)),
+        Some(Compression::Gzip) => {
+            Output::Gzip(Some(GzEncoder::new(file, flate2::Compression::default())))
+        }
+        Some(Compression::Zstd) => match zstd::Encoder::new(file, 0) {
+            Ok(encoder) => Output::Zstd(Some(encoder)),
+            Err(e) => {
+                eprintln!("du-rs: cannot compress '{}': {}", path.display(), e);
+                exit(1);
             }
-            Ok(meta)
-                if (meta.st_mode & nix::sys::stat::SFlag::S_IFMT.bits())
-                    == nix::sys::stat::SFlag::S_IFDIR.bits() =>
-            {
-                let base_dir = g_args.x.as_ref().unwrap_or(path);
-
-                let dir = if &current_dir == base_dir {
-                    format!(".")
-                } else {
-                    format!("{}", base_dir.display())
-                };
+        },
+    }
+}
 
-                let total_size = process_directories(&g_args, path, open_flag, at_flag)?;
-                let formatted_size = if g_args.human_readable {
-                    get_file_sizes(None, Some(total_size))
-                } else if !g_args.block_size.is_empty() {
-                    format_size(total_size, &g_args.block_size)?
-                } else {
-                    total_size.to_string()
-                };
-                if g_args.summarize {
-                    println!("{:<10} {}", formatted_size, dir);
-                } else if g_args.c && !g_args.summarize || g_args.total {
-                    println!("{:<10} total", formatted_size);
-                } else {
-                    println!("{:<10} {}", formatted_size, dir);
-                }
+// Unbuffered, so warnings keep their order against anything else on stderr.
+fn open_warnings(args: &Args) -> Box<dyn Write> {
+    match &args.errors_file {
+        Some(path) => Box::new(create_file(path, false)),
+        None => Box::new(stderr()),
+    }
+}
+
+fn create_file(path: &Path, append: bool) -> File {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(path);
+    match file {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!(
+                "du-rs: cannot open '{}': {}",
+                path.display(),
+                Errno::from(e).desc()
+            );
+            exit(1);
+        }
+    }
+}
+
+// A cache that is missing or unreadable only means a full scan.
+fn read_cache(path: &Path, options: &ScanOptions) -> DirCache {
+    match File::open(path).and_then(|file| DirCache::read(BufReader::new(file), options)) {
+        Ok(cache) => cache,
+        Err(e) => {
+            if e.kind() != io::ErrorKind::NotFound {
+                eprintln!("du-rs: ignoring cache '{}': {}", path.display(), e);
             }
-            _ => continue,
+            DirCache::new(options)
+        }
+    }
+}
+
+// Written next to `path` and renamed over it, so an interrupted run leaves the
+// previous cache whole.
+fn write_cache(path: &Path, cache: &DirCache) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let result = File::create(&temp)
+        .and_then(|file| cache.write(BufWriter::new(file)))
+        .and_then(|()| std::fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+fn read_saved_scan(path: &Path) -> SavedScan {
+    let scan = File::open(path).and_then(|file| SavedScan::read(BufReader::new(file)));
+    scan.unwrap_or_else(|e| {
+        eprintln!("du-rs: cannot read '{}': {}", path.display(), e);
+        exit(1);
+    })
+}
+
+fn measure_name(measure: Option<&[u8]>) -> String {
+    match measure {
+        Some(b"disk") => "disk usage".to_string(),
+        Some(b"apparent") => "apparent sizes".to_string(),
+        Some(b"entries") => "entry counts".to_string(),
+        Some(other) => format!("sizes measured as '{}'", String::from_utf8_lossy(other)),
+        None => "sizes of unknown measure".to_string(),
+    }
+}
+
+// Scans are only compared when their sizes measure the same thing, and that
+// must be what the size options given measure too, as the changes are printed
+// in their units.
+fn check_measures(
+    old: &SavedScan,
+    new: &SavedScan,
+    args: &Args,
+    output: &OutputConfig,
+) -> Cresult<()> {
+    if old.measure() != new.measure() {
+        return Err(anyhow::anyhow!(
+            "cannot compare '{}', holding {}, with '{}', holding {}",
+            args.path[0].display(),
+            measure_name(old.measure()),
+            args.path[1].display(),
+            measure_name(new.measure())
+        ));
+    }
+    // Scans saved before the measure was recorded hold sizes in the units
+    // they were printed in, which are taken as they are.
+    if old
+        .measure()
+        .is_some_and(|measure| measure != output.measure.as_bytes())
+    {
+        return Err(anyhow::anyhow!(
+            "'{}' and '{}' hold {}, unlike the size options given, which measure {}",
+            args.path[0].display(),
+            args.path[1].display(),
+            measure_name(old.measure()),
+            measure_name(Some(output.measure.as_bytes()))
+        ));
+    }
+    Ok(())
+}
+
+// One line per changed path, largest change first, with the change signed
+// and printed in the units of the size options given.
+fn write_diff(
+    writer: &mut impl Write,
+    old: &SavedScan,
+    new: &SavedScan,
+    args: &Args,
+    output: &OutputConfig,
+) -> Cresult<()> {
+    let changes = old.changes(new);
+    for change in changes.iter().take(args.top.unwrap_or(usize::MAX)) {
+        let delta = change.delta();
+        let sign = if delta < 0 { "-" } else { "+" };
+        let mut size = delta.saturating_abs();
+        if old.measure().is_some() {
+            size = size.saturating_add(output.unit - 1) / output.unit;
+        }
+        let size_str = format!("{}{}", sign, size_string(size, output)?);
+        writer.write_all(size_str.as_bytes())?;
+        match &output.separator {
+            Some(separator) => writer.write_all(separator.as_bytes())?,
+            None => write!(
+                writer,
+                "{:width$} ",
+                "",
+                width = 10usize.saturating_sub(size_str.len())
+            )?,
+        }
+        writer.write_all(&change.path)?;
+        writer.write_all(&[output.line_end])?;
+    }
+    Ok(())
+}
+
+// Stops a run whose reason was already reported, once `run` has unwound and
+// the output was written out.
+#[derive(Debug)]
+struct Failed;
+
+impl std::fmt::Display for Failed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("stopped")
+    }
+}
+
+impl std::error::Error for Failed {}
+
+fn main() {
+    if let Err(e) = run() {
+        if !e.is::<Failed>() {
+            eprintln!("du-rs: {:#}", e);
         }
+        exit(1);
+    }
+}
+
+fn run() -> Cresult<()> {
+    let g_args = handle_args();
+    if g_args.diff {
+        let old = read_saved_scan(&g_args.path[0]);
+        let new = read_saved_scan(&g_args.path[1]);
+        let output = g_args.output_config();
+        check_measures(&old, &new, &g_args, &output)?;
+        let mut writer = BufWriter::new(open_output(&g_args));
+        write_diff(&mut writer, &old, &new, &g_args, &output)?;
+        writer
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .finish()?;
+        return Ok(());
+    }
+
+    // Opened first, as a failure to open it exits on the spot.
+    let mut warnings = open_warnings(&g_args);
+    let mut writer = BufWriter::new(OutputThread::start(open_output(&g_args)));
+    let errors = report_paths(&g_args, &mut writer, &mut warnings)?;
+    writer
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?
+        .finish()?;
+
+    // Like du, unreadable entries still leave a usable report but fail the run.
+    if errors > 0 {
+        exit(1);
     }
     Ok(())
 }
+
+// Scans every PATH and writes the report, returning how many paths could
+// not be read.
+fn report_paths(args: &Args, writer: &mut impl Write, warnings: &mut dyn Write) -> Cresult<u64> {
+    let mut options = args.scan_options();
+    if let Some(path) = &args.cache {
+        options.cache = Some(Arc::new(read_cache(path, &options)));
+    }
+    let mut scanner = Scanner::new(options);
+    let output = args.output_config();
+    let follow_roots = scanner.options().follows_roots();
+    let mut errors = 0;
+    let mut files = 0;
+
+    if output.csv && !output.json {
+        write_csv_header(writer, &output)?;
+    } else if args.header && !output.json {
+        write_size_header(writer, args, &output)?;
+    }
+    let mut grand_total: i64 = 0;
+    let mut totals = EntryMeta::default();
+    // Entries of the files among the PATHs since the last directory.
+    let mut file_entries = None;
+
+    for path in &args.path {
+        match Metadata::of(path, follow_roots) {
+            Ok(meta) if meta.is_dir() => {
+                write_file_entries(writer, file_entries.take(), args, &output)?;
+                let usage =
+                    process_directories(args, &mut scanner, path, writer, warnings, &mut errors)?;
+                grand_total = grand_total.saturating_add(usage.total);
+                totals.time = totals.time.max(usage.mtime);
+                totals.inodes += usage.inodes;
+                totals.files += usage.files;
+                totals.files_size = totals.files_size.saturating_add(usage.files_size);
+            }
+            // Regular files, and anything else that is not a directory, are
+            // reported on their own.
+            Ok(meta) => {
+                files += 1;
+                let (size, entry) = report_file(
+                    &meta,
+                    args,
+                    &mut scanner,
+                    path.as_os_str(),
+                    writer,
+                    warnings,
+                    &mut file_entries,
+                )?;
+                grand_total = grand_total.saturating_add(size);
+                totals.time = totals.time.max(entry.time);
+                totals.inodes += entry.inodes;
+                totals.files += entry.files;
+                totals.files_size = totals.files_size.saturating_add(entry.files_size);
+            }
+            Err(errno) => {
+                if !args.quiet {
+                    writeln!(
+                        warnings,
+                        "du-rs: cannot access '{}': {}",
+                        path.display(),
+                        errno.desc()
+                    )?;
+                }
+                errors += 1;
+                if args.strict {
+                    return Err(Failed.into());
+                }
+            }
+        }
+    }
+
+    write_file_entries(writer, file_entries, args, &output)?;
+
+    if let Some(limit) = &scanner.options().entry_limit {
+        if limit.reached() {
+            writeln!(
+                warnings,
+                "du-rs: stopped after {} entries (--max-entries); sizes are truncated",
+                args.max_entries.unwrap_or_default()
+            )?;
+            errors += 1;
+        }
+    }
+
+    if let (Some(path), Some(cache)) = (&args.cache, &scanner.options().cache) {
+        if let Err(e) = write_cache(path, cache) {
+            writeln!(
+                warnings,
+                "du-rs: cannot save cache '{}': {}",
+                path.display(),
+                e
+            )?;
+        }
+    }
+
+    if let Some(histogram) = &scanner.options().histogram {
+        write_histogram(writer, histogram, &output)?;
+    }
+    if let Some(depth_sizes) = &scanner.options().depth_sizes {
+        write_depth_sizes(writer, depth_sizes, args.depth, &output)?;
+    }
+    if let Some(extensions) = &scanner.options().extensions {
+        write_extensions(writer, extensions, &output)?;
+    }
+    if let Some(duplicates) = &scanner.options().duplicates {
+        let groups = duplicates.groups(args.dedup_full);
+        write_duplicates(writer, &groups, &output)?;
+    }
+
+    // Entry counts of separate directories do not add up to anything.
+    if args.total && args.entries_threshold.is_none() {
+        if output.json {
+            write_json_record(writer, grand_total, b"total", totals.time, true, &output)?;
+        } else {
+            write_record(writer, grand_total, b"total", totals, &output)?;
+        }
+    }
+    // Out before the stats follow it on the terminal.
+    writer.flush()?;
+
+    if let Some(stats) = &scanner.options().stats {
+        eprintln!(
+            "du-rs: stats: dirs={} files={} excluded={} errors={}{}",
+            stats.dirs(),
+            stats.files() + files,
+            stats.excluded(),
+            errors,
+            scanner
+                .options()
+                .cache
+                .as_ref()
+                .map(|cache| format!(" cached={}", cache.reused()))
+                .unwrap_or_default()
+        );
+    }
+
+    Ok(errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    fn text(s: &str) -> Field {
+        Field::Text(s.as_bytes().to_vec())
+    }
+
+    // A directory of its own under the temp dir, removed once dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = env::temp_dir().join(format!("du-rs-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Runs du-rs on `paths` with `options` in-process, returning what it
+    // printed, its warnings and how many paths could not be read.
+    fn du_paths(options: &[&str], paths: &[PathBuf]) -> (Vec<u8>, String, u64) {
+        let mut arguments: Vec<String> = options.iter().map(|s| s.to_string()).collect();
+        arguments.extend(paths.iter().map(|p| p.to_str().unwrap().to_string()));
+        let args = parse_args(arguments.into_iter());
+        let mut printed = Vec::new();
+        let mut warnings = Vec::new();
+        let errors = report_paths(&args, &mut printed, &mut warnings).unwrap();
+        (printed, String::from_utf8(warnings).unwrap(), errors)
+    }
+
+    fn du(options: &[&str], root: &Path) -> (Vec<u8>, String, u64) {
+        du_paths(options, &[root.to_path_buf()])
+    }
+
+    #[test]
+    fn template_fields_and_text() {
+        assert_eq!(
+            parse_template("{size}\\t{human}\\t{path}\\n").unwrap(),
+            [
+                Field::Size,
+                text("\t"),
+                Field::Human,
+                text("\t"),
+                Field::Path,
+                text("\n")
+            ]
+        );
+        assert_eq!(
+            parse_template("[{depth}] {inodes} {blocks} {mtime}").unwrap(),
+            [
+                text("["),
+                Field::Depth,
+                text("] "),
+                Field::Inodes,
+                text(" "),
+                Field::Blocks,
+                text(" "),
+                Field::Mtime
+            ]
+        );
+        assert_eq!(parse_template("").unwrap(), []);
+    }
+
+    #[test]
+    fn template_escapes() {
+        assert_eq!(
+            parse_template("{{size}} {size}").unwrap(),
+            [text("{size} "), Field::Size]
+        );
+        assert_eq!(parse_template("a}b").unwrap(), [text("a}b")]);
+        assert_eq!(parse_template("\\\\t").unwrap(), [text("\\t")]);
+        assert_eq!(parse_template("\\x").unwrap(), [text("\\x")]);
+        assert_eq!(
+            parse_template("{path}\\").unwrap(),
+            [Field::Path, text("\\")]
+        );
+    }
+
+    #[test]
+    fn template_rejects_unknown_fields() {
+        let error = parse_template("{size} {owner}").unwrap_err();
+        assert!(error.starts_with("unknown field '{owner}'"), "{}", error);
+        assert!(parse_template("{}").is_err());
+        assert!(parse_template("{Size}").is_err());
+        assert!(parse_template("{ size }").is_err());
+    }
+
+    #[test]
+    fn template_rejects_unclosed_braces() {
+        for template in ["{", "{path}{", "{size", "{{{"] {
+            let error = parse_template(template).unwrap_err();
+            assert!(error.starts_with("unclosed '{'"), "{}: {}", template, error);
+        }
+    }
+
+    #[test]
+    fn json_sizes_are_bytes_whatever_the_unit() {
+        let dir = TempDir::new("json-bytes");
+        fs::write(dir.0.join("file"), vec![b'x'; 5000]).unwrap();
+        let allocated = Metadata::of(&dir.0.join("file"), true).unwrap().blocks * 512;
+
+        for options in [
+            &["--json", "-a"][..],
+            &["--json", "-a", "-k"],
+            &["--json", "-a", "-B", "512"],
+            &["--json", "-a", "-BM"],
+        ] {
+            let (printed, _, _) = du(options, &dir.0);
+            let printed = String::from_utf8(printed).unwrap();
+            let line = printed
+                .lines()
+                .find(|line| line.contains("/file\""))
+                .unwrap();
+            assert!(
+                line.ends_with(&format!(",\"bytes\":{},\"measure\":\"disk\"}}", allocated)),
+                "{:?}: {}",
+                options,
+                line
+            );
+        }
+
+        let (printed, _, _) = du(&["--json", "-a", "-b"], &dir.0);
+        let printed = String::from_utf8(printed).unwrap();
+        assert!(
+            printed.contains("/file\",\"bytes\":5000,\"measure\":\"apparent\"}"),
+            "{}",
+            printed
+        );
+    }
+
+    #[test]
+    fn diff_refuses_scans_measured_differently() {
+        let dir = TempDir::new("diff-measure");
+        fs::write(dir.0.join("file"), vec![b'x'; 3000]).unwrap();
+        let (disk, _, _) = du(&["--json"], &dir.0);
+        let (apparent, _, _) = du(&["--json", "--apparent-size"], &dir.0);
+        let disk = SavedScan::read(&disk[..]).unwrap();
+        let apparent = SavedScan::read(&apparent[..]).unwrap();
+
+        let args = parse_args(["--diff", "old", "new"].map(String::from).into_iter());
+        let output = args.output_config();
+        let error = check_measures(&disk, &apparent, &args, &output).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("cannot compare 'old', holding disk usage"),
+            "{}",
+            error
+        );
+        assert!(check_measures(&apparent, &apparent, &args, &output).is_err());
+        check_measures(&disk, &disk, &args, &output).unwrap();
+
+        let args = parse_args(
+            ["--diff", "--apparent-size", "old", "new"]
+                .map(String::from)
+                .into_iter(),
+        );
+        check_measures(&apparent, &apparent, &args, &args.output_config()).unwrap();
+    }
+
+    #[test]
+    fn diff_prints_changes_in_the_units_asked_for() {
+        let old = SavedScan::read(&b"{\"path\":\"a\",\"bytes\":1024,\"measure\":\"disk\"}\n"[..])
+            .unwrap();
+        let new =
+            SavedScan::read(&b"{\"path\":\"a\",\"bytes\":1049600,\"measure\":\"disk\"}\n"[..])
+                .unwrap();
+        for (options, expected) in [
+            (&["--diff"][..], "+1024      a\n"),
+            (&["--diff", "-h"], "+1.0M      a\n"),
+            (&["--diff", "-BM"], "+1M        a\n"),
+        ] {
+            let args = parse_args(options.iter().chain(&["old", "new"]).map(|s| s.to_string()));
+            let mut printed = Vec::new();
+            write_diff(&mut printed, &old, &new, &args, &args.output_config()).unwrap();
+            assert_eq!(
+                String::from_utf8(printed).unwrap(),
+                expected,
+                "{:?}",
+                options
+            );
+        }
+    }
+
+    #[test]
+    fn compressed_json_reads_back() {
+        let dir = TempDir::new("compress");
+        fs::create_dir(dir.0.join("sub")).unwrap();
+        fs::write(dir.0.join("sub/file"), b"hello").unwrap();
+        let scanned = dir.0.join("sub");
+        let (expected, _, _) = du(&["--json", "-a"], &scanned);
+
+        for format in ["gzip", "zstd"] {
+            let file = dir.0.join(format!("scan.json.{}", format));
+            let file_arg = file.to_str().unwrap();
+            let args = parse_args(
+                [
+                    "--json",
+                    "-a",
+                    "-o",
+                    file_arg,
+                    "--compress",
+                    format,
+                    scanned.to_str().unwrap(),
+                ]
+                .map(String::from)
+                .into_iter(),
+            );
+            let mut output = open_output(&args);
+            let mut errors = 0;
+            let mut scanner = Scanner::new(args.scan_options());
+            process_directories(
+                &args,
+                &mut scanner,
+                &args.path[0],
+                &mut output,
+                &mut io::sink(),
+                &mut errors,
+            )
+            .unwrap();
+            output.finish().unwrap();
+            drop(output);
+
+            let compressed = fs::read(&file).unwrap();
+            let decoded = match format {
+                "gzip" => {
+                    let mut decoded = Vec::new();
+                    flate2::read::GzDecoder::new(&compressed[..])
+                        .read_to_end(&mut decoded)
+                        .unwrap();
+                    decoded
+                }
+                _ => zstd::decode_all(&compressed[..]).unwrap(),
+            };
+            assert_ne!(compressed, expected);
+            assert_eq!(
+                String::from_utf8(decoded.clone()).unwrap(),
+                String::from_utf8(expected.clone()).unwrap()
+            );
+            let saved = SavedScan::read(&decoded[..]).unwrap();
+            assert_eq!(saved.measure(), Some(&b"disk"[..]));
+        }
+    }
+
+    #[test]
+    fn file_paths_are_filtered_and_sorted_as_entries() {
+        let dir = TempDir::new("file-paths");
+        let files: Vec<PathBuf> = [("small", 10), ("large", 3000), ("medium", 2000)]
+            .iter()
+            .map(|&(name, len)| {
+                let path = dir.0.join(name);
+                fs::write(&path, vec![b'x'; len]).unwrap();
+                path
+            })
+            .collect();
+        let line = |size: usize, name: &str| format!("{}\t{}\n", size, dir.0.join(name).display());
+
+        let (printed, _, _) = du_paths(
+            &["-b", "--output-separator", "\t", "--min-size", "100"],
+            &files,
+        );
+        assert_eq!(
+            String::from_utf8(printed).unwrap(),
+            line(3000, "large") + &line(2000, "medium")
+        );
+        let (printed, _, _) = du_paths(&["-b", "--output-separator", "\t", "--top", "1"], &files);
+        assert_eq!(String::from_utf8(printed).unwrap(), line(3000, "large"));
+        let (printed, _, _) = du_paths(
+            &["-b", "--output-separator", "\t", "--include", "m*"],
+            &files,
+        );
+        assert_eq!(String::from_utf8(printed).unwrap(), line(2000, "medium"));
+    }
+
+    #[test]
+    fn sparse_notes_on_file_paths_go_with_the_warnings() {
+        let dir = TempDir::new("sparse-path");
+        let path = dir.0.join("sparse");
+        File::create(&path).unwrap().set_len(1 << 24).unwrap();
+
+        let (_, warnings, _) = du_paths(&["--warn-sparse"], std::slice::from_ref(&path));
+        assert_eq!(
+            warnings,
+            format!(
+                "du-rs: note: '{}' is sparse: 16.0M apparent, 0 allocated\n",
+                path.display()
+            )
+        );
+    }
+
+    #[test]
+    fn null_ends_records_even_after_names_with_newlines() {
+        let dir = TempDir::new("null");
+        fs::write(dir.0.join("two\nlines"), b"x").unwrap();
+
+        let (printed, _, _) = du(&["-0", "-a", "-b", "--output-separator", "\t"], &dir.0);
+        let records: Vec<&[u8]> = printed.split(|&c| c == 0).collect();
+        let root = dir.0.as_os_str().as_encoded_bytes();
+        assert_eq!(
+            records,
+            [
+                &[b"1\t", root, b"/two\nlines"].concat()[..],
+                &[
+                    format!("{}\t", fs::metadata(&dir.0).unwrap().len() + 1).as_bytes(),
+                    root
+                ]
+                .concat(),
+                b""
+            ]
+        );
+    }
+
+    #[test]
+    fn top_keeps_the_largest_entries_and_the_total() {
+        let dir = TempDir::new("top");
+        for i in 0..50 {
+            let sub = dir.0.join(format!("d{:02}", i));
+            fs::create_dir(&sub).unwrap();
+            fs::write(sub.join("file"), vec![b'x'; (i + 1) * 1000]).unwrap();
+        }
+
+        let (printed, _, _) = du(
+            &["--top", "3", "-c", "-b", "--output-separator", "\t"],
+            &dir.0,
+        );
+        let printed = String::from_utf8(printed).unwrap();
+        let paths: Vec<&str> = printed
+            .lines()
+            .map(|line| line.split('\t').nth(1).unwrap())
+            .collect();
+        let root = dir.0.to_str().unwrap();
+        assert_eq!(
+            paths,
+            [
+                root.to_string(),
+                format!("{}/d49", root),
+                format!("{}/d48", root),
+                "total".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn count_links_counts_every_hard_link() {
+        let dir = TempDir::new("count-links");
+        fs::write(dir.0.join("file"), vec![b'x'; 10_000]).unwrap();
+        fs::hard_link(dir.0.join("file"), dir.0.join("link")).unwrap();
+
+        let size = |options: &[&str]| {
+            let (printed, _, errors) = du(options, &dir.0);
+            assert_eq!(errors, 0);
+            let printed = String::from_utf8(printed).unwrap();
+            printed.split('\t').next().unwrap().parse::<u64>().unwrap()
+        };
+        let once = size(&["-sb", "--output-separator", "\t"]);
+        let twice = size(&["-sbl", "--output-separator", "\t"]);
+        let directory = fs::metadata(&dir.0).unwrap().len();
+        assert_eq!(once, directory + 10_000);
+        assert_eq!(twice, directory + 20_000);
+        assert_eq!(
+            size(&["-sb", "--count-links", "--output-separator", "\t"]),
+            twice
+        );
+    }
+
+    #[test]
+    fn max_entries_stops_with_coherent_partial_totals() {
+        let dir = TempDir::new("max-entries");
+        for sub in ["a", "b"] {
+            fs::create_dir(dir.0.join(sub)).unwrap();
+        }
+        for file in ["a/x", "a/y", "b/z", "w"] {
+            fs::write(dir.0.join(file), [0; 1000]).unwrap();
+        }
+
+        let (printed, warnings, errors) = du(
+            &["-ab", "--max-entries", "2", "--output-separator", "\t"],
+            &dir.0,
+        );
+        assert_eq!(
+            warnings,
+            "du-rs: stopped after 2 entries (--max-entries); sizes are truncated\n"
+        );
+        assert_eq!(errors, 1);
+
+        // Which entries make it depends on the directory order, but each
+        // printed size must add up from what was printed below it.
+        let printed = String::from_utf8(printed).unwrap();
+        let entries: Vec<(u64, PathBuf)> = printed
+            .lines()
+            .map(|line| {
+                let (size, path) = line.split_once('\t').unwrap();
+                (size.parse().unwrap(), PathBuf::from(path))
+            })
+            .collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries.last().unwrap().1, dir.0);
+        for (size, path) in &entries {
+            let metadata = fs::metadata(path).unwrap();
+            let below: u64 = entries
+                .iter()
+                .filter(|(_, child)| child.parent() == Some(path))
+                .map(|(size, _)| size)
+                .sum();
+            assert_eq!(*size, metadata.len() + below, "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn second_cache_run_reuses_unchanged_directories() {
+        let dir = TempDir::new("cache-tree");
+        let saved = TempDir::new("cache-file");
+        let cache = saved.0.join("cache");
+        for sub in ["kept", "changed"] {
+            fs::create_dir(dir.0.join(sub)).unwrap();
+            fs::write(dir.0.join(sub).join("file"), [0; 1000]).unwrap();
+        }
+        // Backdated, so the entry added below changes the time even within
+        // the timestamp granularity.
+        File::open(dir.0.join("changed"))
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH)
+            .unwrap();
+
+        let options = [
+            "-b",
+            "--output-separator",
+            "\t",
+            "--cache",
+            cache.to_str().unwrap(),
+        ];
+        let first = du(&options, &dir.0);
+        assert_eq!(first.2, 0);
+        assert!(cache.exists());
+
+        // Rewriting a file in place leaves its directory's times alone, so
+        // the cached size shows through; a new entry changes them, and that
+        // directory is read again.
+        fs::write(dir.0.join("kept/file"), [0; 3000]).unwrap();
+        fs::write(dir.0.join("changed/new"), [0; 500]).unwrap();
+        let (printed, warnings, errors) = du(&options, &dir.0);
+        assert_eq!((warnings.as_str(), errors), ("", 0));
+        // What each directory holds besides itself.
+        let held = |printed: Vec<u8>, sub: &str| {
+            let path = dir.0.join(sub);
+            let printed = String::from_utf8(printed).unwrap();
+            let line = printed
+                .lines()
+                .find(|line| line.ends_with(&format!("\t{}", path.display())))
+                .unwrap()
+                .to_string();
+            let size: u64 = line.split('\t').next().unwrap().parse().unwrap();
+            size - fs::metadata(&path).unwrap().len()
+        };
+        assert_eq!(held(printed.clone(), "kept"), 1000);
+        assert_eq!(held(printed, "changed"), 1500);
+
+        let (uncached, _, _) = du(&["-b", "--output-separator", "\t"], &dir.0);
+        assert_eq!(held(uncached, "kept"), 3000);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn unreadable_directories_are_reported_and_fail_the_run() {
+        // Root reads any directory whatever its mode.
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+        let dir = TempDir::new("unreadable");
+        let secret = dir.0.join("secret");
+        fs::create_dir(&secret).unwrap();
+        fs::write(secret.join("file"), b"x").unwrap();
+        fs::set_permissions(&secret, fs::Permissions::from_mode(0o000)).unwrap();
+
+        let (printed, warnings, errors) = du(&[], &dir.0);
+        let quiet = du(&["-q"], &dir.0);
+        fs::set_permissions(&secret, fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert_eq!(
+            warnings,
+            format!(
+                "du-rs: cannot read directory '{}': Permission denied\n",
+                secret.display()
+            )
+        );
+        assert_eq!(errors, 1);
+        assert!(String::from_utf8(printed).unwrap().contains("/secret\n"));
+        assert_eq!((quiet.1.as_str(), quiet.2), ("", 1));
+    }
+
+    #[test]
+    fn csv_quotes_paths_with_commas_and_quotes() {
+        let dir = TempDir::new("csv");
+        fs::write(dir.0.join("a,b"), b"abc").unwrap();
+        fs::write(dir.0.join("say \"hi\""), b"abc").unwrap();
+
+        let (printed, _, _) = du(&["--csv", "-a", "-b"], &dir.0);
+        let printed = String::from_utf8(printed).unwrap();
+        let root = dir.0.display();
+        let lines: Vec<&str> = printed.lines().collect();
+        assert_eq!(
+            lines[..3],
+            [
+                "size_bytes,size_human,path".to_string(),
+                format!("3,3,\"{}/a,b\"", root),
+                format!("3,3,\"{}/say \"\"hi\"\"\"", root),
+            ]
+        );
+        assert!(lines[3].ends_with(&format!(",{}", root)), "{}", lines[3]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlink_loops_end_the_walk_under_dereference() {
+        let dir = TempDir::new("symlink-loop");
+        fs::create_dir_all(dir.0.join("a/b")).unwrap();
+        std::os::unix::fs::symlink("../..", dir.0.join("a/b/up")).unwrap();
+        std::os::unix::fs::symlink(".", dir.0.join("self")).unwrap();
+
+        let root = dir.0.display();
+        for threads in ["1", "3"] {
+            let (printed, warnings, errors) = du(&["-L", "--threads", threads], &dir.0);
+            let mut warnings: Vec<&str> = warnings.lines().collect();
+            warnings.sort_unstable();
+            assert_eq!(
+                warnings,
+                [
+                    format!("du-rs: detected symlink loop at '{}/a/b/up'", root),
+                    format!("du-rs: detected symlink loop at '{}/self'", root),
+                ]
+            );
+            assert_eq!(errors, 2);
+            assert_eq!(String::from_utf8(printed).unwrap().lines().count(), 3);
+        }
+    }
+}
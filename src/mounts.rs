@@ -0,0 +1,60 @@
+use fxhash::FxHashSet;
+use std::fs;
+use std::path::Path;
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/// Mount points below a scan root, as paths relative to it. Bind mounts keep
+/// the device of their source, so `-x` needs these to stop at them.
+pub(crate) struct MountPoints {
+    relative: FxHashSet<Vec<u8>>,
+}
+
+impl MountPoints {
+    /// `None` where `/proc` is not mounted, leaving only the device check.
+    pub(crate) fn below(root: &Path) -> Option<MountPoints> {
+        let content = fs::read(MOUNTINFO).ok()?;
+        let root = fs::canonicalize(root).ok()?;
+        let root = root.as_os_str().as_encoded_bytes();
+        let prefix_len = if root == b"/" { 1 } else { root.len() + 1 };
+
+        let relative = content
+            .split(|&b| b == b'\n')
+            .filter_map(|line| line.split(|&b| b == b' ').nth(4))
+            .map(unescape)
+            .filter(|point| {
+                point.len() > prefix_len && point.starts_with(root) && point[prefix_len - 1] == b'/'
+            })
+            .map(|point| point[prefix_len..].to_vec())
+            .collect();
+        Some(MountPoints { relative })
+    }
+
+    #[inline]
+    pub(crate) fn contains(&self, relative: &[u8]) -> bool {
+        !self.relative.is_empty() && self.relative.contains(relative)
+    }
+}
+
+// The kernel writes space, tab, newline and backslash as `\` and three octal
+// digits.
+fn unescape(field: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        let octal = field
+            .get(i + 1..i + 4)
+            .filter(|digits| field[i] == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d)));
+        match octal {
+            Some(digits) => {
+                out.push(digits.iter().fold(0u8, |n, d| (n << 3) | (d - b'0')));
+                i += 4;
+            }
+            None => {
+                out.push(field[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
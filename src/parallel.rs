@@ -0,0 +1,807 @@
+use crate::gitignore::Gitignore;
+use crate::platform::{self, Errno, Metadata, OpenDir, OwnedDir};
+use crate::timestamp::Timestamp;
+use crate::walk::{
+    is_cache_dir, is_dir_entry, is_excluded, load_gitignore, loops_back, record_depth,
+    record_duplicate_candidate, report_deeper, report_error, report_subdir, unreadable_dir_usage,
+    warns_sparse, TraversalConfig, CACHEDIR_TAG, DETACH_BATCH,
+};
+use crate::{
+    Cresult, DirUsage, FileStats, Operation, SkipReason, Skipped, SparseFile, Visit, Visitor,
+    ST_BLOCK_SIZE,
+};
+use fxhash::{FxHashMap, FxHashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+
+const INODE_SHARDS: usize = 64;
+
+// Directories scanned, or being scanned, ahead of the report. Workers wait
+// beyond it, bar one taking the directory the report is waiting for.
+const RUN_AHEAD: usize = 4096;
+
+// Hard-link inodes seen so far, sharded so workers rarely contend on a lock.
+struct SeenInodes {
+    shards: Vec<Mutex<FxHashSet<(u64, u64)>>>,
+}
+
+impl SeenInodes {
+    fn new(seen: FxHashSet<(u64, u64)>) -> SeenInodes {
+        let mut shards: Vec<FxHashSet<(u64, u64)>> =
+            (0..INODE_SHARDS).map(|_| FxHashSet::default()).collect();
+        for inode in seen {
+            shards[Self::shard(inode)].insert(inode);
+        }
+        SeenInodes {
+            shards: shards.into_iter().map(Mutex::new).collect(),
+        }
+    }
+
+    #[inline]
+    fn shard(inode: (u64, u64)) -> usize {
+        (inode.0 ^ inode.1) as usize % INODE_SHARDS
+    }
+
+    #[inline]
+    fn insert(&self, inode: (u64, u64)) -> bool {
+        self.shards[Self::shard(inode)]
+            .lock()
+            .unwrap()
+            .insert(inode)
+    }
+
+    fn into_set(self) -> FxHashSet<(u64, u64)> {
+        self.shards
+            .into_iter()
+            .flat_map(|shard| shard.into_inner().unwrap())
+            .collect()
+    }
+}
+
+// A directory whose subdirectories are still to be opened. They are opened
+// relative to it, so paths of any length can be scanned and a directory
+// renamed mid-scan is not mistaken for another.
+struct DirHandle {
+    id: usize,
+    // Closed once every subdirectory is open, or earlier when descriptors
+    // run short, after which it is reopened from `parent` by `name`.
+    open: Mutex<Option<Arc<OwnedDir>>>,
+    parent: Option<Arc<DirHandle>>,
+    // The name in `parent`, or the root's path.
+    name: Box<[u8]>,
+    inode: (u64, u64),
+    // Subdirectories not opened yet.
+    pending: AtomicUsize,
+}
+
+struct Job {
+    node: usize,
+    // The directory `name` is opened in; the root is opened by its path.
+    parent: Option<Arc<DirHandle>>,
+    name: Box<[u8]>,
+    path: Vec<u8>,
+    depth: i32,
+    gitignores: Vec<(usize, Arc<Gitignore>)>,
+    // Directories above this one, only tracked when following links.
+    ancestors: Vec<(u64, u64)>,
+    // Metadata the parent already fetched while finding out this is a directory.
+    meta: Option<Result<Metadata, Errno>>,
+}
+
+enum Child {
+    File {
+        name: Vec<u8>,
+        size: i64,
+        mtime: Timestamp,
+    },
+    Dir {
+        name: Vec<u8>,
+        node: usize,
+    },
+}
+
+// What one directory contributed on its own, children sorted by name so
+// the report comes out exactly as the sequential walk would print it.
+#[derive(Default)]
+struct Node {
+    found: bool,
+    own: i64,
+    mtime: Timestamp,
+    // The directory itself and the files counted in it.
+    inodes: u64,
+    files: u64,
+    files_size: i64,
+    // Entries read from the directory.
+    entries: u64,
+    children: Vec<Child>,
+    // Paths that could not be read, reported ahead of the directory's entries.
+    errors: Vec<(Vec<u8>, Operation, Errno)>,
+    // Sparse files with their apparent and allocated bytes, reported likewise.
+    sparse: Vec<(Vec<u8>, i64, i64)>,
+    // Entries left out, only gathered when they are to be reported.
+    skipped: Vec<(Vec<u8>, SkipReason)>,
+}
+
+impl Node {
+    fn skip(&mut self, config: &TraversalConfig, dir: &[u8], name: &[u8], reason: SkipReason) {
+        if !config.report_skipped {
+            return;
+        }
+        let mut path = dir.to_vec();
+        if !path.is_empty() && !name.is_empty() {
+            path.push(b'/');
+        }
+        path.extend_from_slice(name);
+        self.skipped.push((path, reason));
+    }
+}
+
+struct Queue {
+    // Taken from the end, so subdirectories are queued last name first and
+    // scanned roughly in the order they are reported.
+    jobs: Vec<Job>,
+    // Jobs queued or still being worked on.
+    pending: usize,
+    // Jobs taken whose directory the report has not taken in turn.
+    unreported: usize,
+    // The directory the report is waiting for.
+    wanted: Option<usize>,
+    cancelled: bool,
+}
+
+struct Pool<'a> {
+    config: &'a TraversalConfig,
+    queue: Mutex<Queue>,
+    ready: Condvar,
+    next_node: AtomicUsize,
+    seen_inodes: SeenInodes,
+    // Directories kept open for their subdirectories, bar the root.
+    open_dirs: Mutex<FxHashMap<usize, Weak<DirHandle>>>,
+    // Directories scanned but not reported yet.
+    finished: Mutex<FxHashMap<usize, Node>>,
+    scanned: Condvar,
+    failed: AtomicBool,
+}
+
+// Wakes the report when a worker panics, which would otherwise wait forever
+// for the directory it was scanning.
+struct WorkerGuard<'a, 'b>(&'a Pool<'b>);
+
+impl Drop for WorkerGuard<'_, '_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.failed.store(true, Ordering::Release);
+            let _finished = self.0.finished.lock();
+            self.0.scanned.notify_all();
+        }
+    }
+}
+
+impl Pool<'_> {
+    fn push(&self, jobs: Vec<Job>) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.cancelled {
+            return;
+        }
+        queue.pending += jobs.len();
+        queue.jobs.extend(jobs.into_iter().rev());
+        self.ready.notify_all();
+    }
+
+    fn pop(&self) -> Option<Job> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if queue.cancelled {
+                return None;
+            }
+            let job = if queue.unreported < RUN_AHEAD {
+                queue.jobs.pop()
+            } else {
+                // Whatever else is held, the report must be able to move on.
+                let wanted = queue.wanted;
+                let index = queue.jobs.iter().rposition(|job| Some(job.node) == wanted);
+                index.map(|index| queue.jobs.remove(index))
+            };
+            if let Some(job) = job {
+                queue.unreported += 1;
+                return Some(job);
+            }
+            if queue.pending == 0 {
+                return None;
+            }
+            queue = self.ready.wait(queue).unwrap();
+        }
+    }
+
+    fn done(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.pending -= 1;
+        if queue.pending == 0 {
+            self.ready.notify_all();
+        }
+    }
+
+    // Stops the workers once their current directory is scanned.
+    fn cancel(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.cancelled = true;
+        queue.jobs.clear();
+        self.ready.notify_all();
+    }
+
+    fn work(&self) {
+        let _guard = WorkerGuard(self);
+        while let Some(job) = self.pop() {
+            let index = job.node;
+            let node = self.scan_dir(job);
+            self.finished.lock().unwrap().insert(index, node);
+            self.scanned.notify_all();
+            self.done();
+        }
+    }
+
+    // Waits for the directory `index` to be scanned and hands it over.
+    fn take(&self, index: usize) -> Node {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            queue.wanted = Some(index);
+            if queue.unreported >= RUN_AHEAD {
+                self.ready.notify_all();
+            }
+        }
+        let node = {
+            let mut finished = self.finished.lock().unwrap();
+            loop {
+                if let Some(node) = finished.remove(&index) {
+                    break node;
+                }
+                if self.failed.load(Ordering::Acquire) {
+                    panic!("traversal worker panicked");
+                }
+                finished = self.scanned.wait(finished).unwrap();
+            }
+        };
+        let mut queue = self.queue.lock().unwrap();
+        queue.wanted = None;
+        queue.unreported -= 1;
+        if queue.unreported == RUN_AHEAD - 1 {
+            self.ready.notify_all();
+        }
+        node
+    }
+
+    // Runs `open`, closing directories held for later while it fails for want
+    // of descriptors.
+    fn with_fds<T>(&self, mut open: impl FnMut() -> Result<T, Errno>) -> Result<T, Errno> {
+        loop {
+            match open() {
+                Err(Errno::EMFILE | Errno::ENFILE) if self.detach_open_dirs() => {}
+                result => return result,
+            }
+        }
+    }
+
+    // Frees descriptors by closing directories kept open for subdirectories
+    // not scanned yet, which reopen them when their turn comes.
+    fn detach_open_dirs(&self) -> bool {
+        let mut detached = 0;
+        self.open_dirs.lock().unwrap().retain(|_, dir| {
+            if detached == DETACH_BATCH {
+                return true;
+            }
+            let Some(dir) = dir.upgrade() else {
+                return false;
+            };
+            // One being reopened right now is left alone.
+            let Ok(mut open) = dir.open.try_lock() else {
+                return true;
+            };
+            if open.take().is_some() {
+                detached += 1;
+            }
+            false
+        });
+        detached > 0
+    }
+
+    // `dir`, reopened from its nearest open ancestor if it was detached,
+    // checking it is still the directory that was scanned.
+    fn open_dir(&self, dir: &Arc<DirHandle>) -> Result<Arc<OwnedDir>, Errno> {
+        let mut closed = Vec::new();
+        let mut base = None;
+        let mut next = Some(dir);
+        while let Some(handle) = next {
+            if let Some(open) = &*handle.open.lock().unwrap() {
+                base = Some(Arc::clone(open));
+                break;
+            }
+            closed.push(handle);
+            next = handle.parent.as_ref();
+        }
+
+        for handle in closed.into_iter().rev() {
+            let follow = if handle.parent.is_some() {
+                self.config.follow_links
+            } else {
+                self.config.follow_roots
+            };
+            let parent = base.as_deref().map(OwnedDir::raw);
+            let dir = self.with_fds(|| {
+                platform::open_dir(parent.as_ref(), &platform::os_str(&handle.name), follow)
+            })?;
+            let meta = platform::stat_dir(&dir.raw())?;
+            if (meta.dev, meta.ino) != handle.inode {
+                return Err(Errno::ENOENT);
+            }
+
+            // Only kept while subdirectories still need it; an ancestor
+            // reopened on the way down is closed again straight away.
+            let mut slot = handle.open.lock().unwrap();
+            let dir = match &*slot {
+                Some(open) => Arc::clone(open),
+                None => {
+                    let dir = Arc::new(dir);
+                    if handle.pending.load(Ordering::Acquire) > 0 {
+                        *slot = Some(Arc::clone(&dir));
+                        self.open_dirs
+                            .lock()
+                            .unwrap()
+                            .insert(handle.id, Arc::downgrade(handle));
+                    }
+                    dir
+                }
+            };
+            base = Some(dir);
+        }
+        Ok(base.expect("the root directory stays open"))
+    }
+
+    // Closes `dir` once its last subdirectory is open. The root stays open so
+    // detached directories can be reopened from it.
+    fn release(&self, dir: &DirHandle) {
+        if dir.pending.fetch_sub(1, Ordering::AcqRel) == 1 && dir.parent.is_some() {
+            dir.open.lock().unwrap().take();
+            self.open_dirs.lock().unwrap().remove(&dir.id);
+        }
+    }
+
+    fn scan_dir(&self, job: Job) -> Node {
+        let config = self.config;
+        let mut node = Node::default();
+
+        let parent = match &job.parent {
+            Some(parent) => self.open_dir(parent).map(Some),
+            None => Ok(None),
+        };
+        let parent_raw = match &parent {
+            Ok(dir) => Ok(dir.as_deref().map(OwnedDir::raw)),
+            Err(errno) => Err(*errno),
+        };
+        let name = platform::os_str(&job.name);
+        let opened = parent_raw.as_ref().map_err(|errno| *errno).and_then(|dir| {
+            let follow = if job.parent.is_some() {
+                config.follow_links
+            } else {
+                config.follow_roots
+            };
+            self.with_fds(|| platform::open_dir(dir.as_ref(), &name, follow))
+        });
+        if let Some(parent) = &job.parent {
+            self.release(parent);
+        }
+        let dir = match opened {
+            Ok(dir) => dir,
+            Err(errno) => {
+                node.errors
+                    .push((job.path.clone(), Operation::ReadDir, errno));
+                if let Some(usage) = parent_raw
+                    .ok()
+                    .and_then(|dir| unreadable_dir_usage(dir.as_ref(), &name, config))
+                {
+                    node.found = true;
+                    node.own = usage.own;
+                    node.mtime = usage.mtime;
+                    node.inodes = usage.inodes;
+                }
+                return node;
+            }
+        };
+        drop(parent);
+        node.found = true;
+
+        let raw_dir = dir.raw();
+        let meta = match job.meta.unwrap_or_else(|| platform::stat_dir(&raw_dir)) {
+            Ok(meta) => meta,
+            Err(errno) => {
+                node.errors.push((job.path.clone(), Operation::Stat, errno));
+                return node;
+            }
+        };
+
+        node.own = config.size_format.get_dir_size(&FileStats {
+            size: meta.size,
+            blocks: meta.blocks,
+        });
+        node.mtime = config.time.of(&meta);
+        node.inodes = 1;
+
+        // As in du, a mount point counts as a directory, just not what is on it.
+        if config.leaves_file_system(meta.dev, &job.path) {
+            node.skip(config, &job.path, b"", SkipReason::OtherFileSystem);
+            return node;
+        }
+
+        let mut reader = match self.with_fds(|| OpenDir::dup(&dir)) {
+            Ok(d) => d,
+            Err(errno) => {
+                node.errors
+                    .push((job.path.clone(), Operation::ReadDir, errno));
+                return node;
+            }
+        };
+
+        let mut gitignores = job.gitignores;
+        if let Some(rules) = config.gitignore.then(|| load_gitignore(&raw_dir)).flatten() {
+            gitignores.push((job.path.len(), Arc::new(rules)));
+        }
+
+        let mut ancestors = job.ancestors;
+        if config.follow_links {
+            ancestors.push((meta.dev, meta.ino));
+        }
+
+        let is_cache = config.exclude_caches && is_cache_dir(&raw_dir);
+        if let Some(progress) = &config.progress {
+            progress.enter_dir(&job.path);
+        }
+        if let Some(stats) = &config.stats {
+            stats.add_dir();
+        }
+
+        let (mut entries, error) = reader.read();
+        drop(reader);
+        entries.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        if let Some(errno) = error {
+            node.errors
+                .push((job.path.clone(), Operation::ReadDir, errno));
+        }
+        let mut subdirs = Vec::new();
+        for entry in &entries {
+            let file_name_bytes = &entry.name[..];
+            if config
+                .entry_limit
+                .as_ref()
+                .is_some_and(|limit| !limit.take())
+            {
+                break;
+            }
+            node.entries += 1;
+            if let Some(progress) = &config.progress {
+                progress.add_entry();
+            }
+            let file_name = platform::os_str(file_name_bytes);
+            let (is_dir, meta) = is_dir_entry(&raw_dir, entry.file_type, &file_name, config);
+            if (is_cache && file_name_bytes != CACHEDIR_TAG)
+                || is_excluded(config, &job.path, &gitignores, file_name_bytes, is_dir)
+            {
+                if let Some(stats) = &config.stats {
+                    stats.add_excluded();
+                }
+                node.skip(config, &job.path, file_name_bytes, SkipReason::Excluded);
+                continue;
+            }
+
+            if is_dir {
+                let mut path = Vec::with_capacity(job.path.len() + file_name_bytes.len() + 1);
+                path.extend_from_slice(&job.path);
+                if !path.is_empty() {
+                    path.push(b'/');
+                }
+                path.extend_from_slice(file_name_bytes);
+
+                if config.follow_links && loops_back(meta.as_ref(), &ancestors) {
+                    node.errors.push((path, Operation::Loop, Errno::ELOOP));
+                    continue;
+                }
+                if config
+                    .dir_identity(meta.as_ref())
+                    .is_some_and(|inode| !self.seen_inodes.insert(inode))
+                {
+                    node.skip(config, &path, b"", SkipReason::AlreadyCounted);
+                    continue;
+                }
+
+                let child = self.next_node.fetch_add(1, Ordering::Relaxed);
+                node.children.push(Child::Dir {
+                    name: file_name_bytes.to_vec(),
+                    node: child,
+                });
+                subdirs.push(Job {
+                    node: child,
+                    parent: None,
+                    name: file_name_bytes.into(),
+                    path,
+                    depth: job.depth + 1,
+                    gitignores: gitignores.clone(),
+                    ancestors: ancestors.clone(),
+                    meta,
+                });
+                continue;
+            }
+
+            if let Some(stats) = &config.stats {
+                stats.add_file();
+            }
+            let child_meta = match meta.unwrap_or_else(|| {
+                platform::stat_at(Some(&raw_dir), &file_name, config.follow_links)
+            }) {
+                Ok(m) => m,
+                Err(errno) => {
+                    let mut path = job.path.clone();
+                    if !path.is_empty() {
+                        path.push(b'/');
+                    }
+                    path.extend_from_slice(file_name_bytes);
+                    node.errors.push((path, Operation::Stat, errno));
+                    continue;
+                }
+            };
+
+            let file_stats = FileStats {
+                size: child_meta.size,
+                blocks: child_meta.blocks,
+            };
+            let file_size = config.size_format.get_file_size(&file_stats);
+            if !config.counts_file(file_size, &child_meta) {
+                if let Some(stats) = &config.stats {
+                    stats.add_excluded();
+                }
+                node.skip(config, &job.path, file_name_bytes, SkipReason::Filtered);
+                continue;
+            }
+
+            if config.counts_once(&child_meta)
+                && !self.seen_inodes.insert((child_meta.dev, child_meta.ino))
+            {
+                node.skip(
+                    config,
+                    &job.path,
+                    file_name_bytes,
+                    SkipReason::AlreadyCounted,
+                );
+                continue;
+            }
+
+            let file_mtime = config.time.of(&child_meta);
+            if let Some(histogram) = &config.histogram {
+                histogram.record(config.size_format.get_file_bytes(&file_stats) as u64);
+            }
+            if let Some(extensions) = &config.extensions {
+                extensions.record(file_name_bytes, file_size);
+            }
+            record_duplicate_candidate(config, &job.path, file_name_bytes, &child_meta);
+            if warns_sparse(config, &child_meta, &file_stats) {
+                let mut path = job.path.clone();
+                if !path.is_empty() {
+                    path.push(b'/');
+                }
+                path.extend_from_slice(file_name_bytes);
+                node.sparse
+                    .push((path, file_stats.size, file_stats.blocks * ST_BLOCK_SIZE));
+            }
+            node.own = node.own.saturating_add(file_size);
+            node.mtime = node.mtime.max(file_mtime);
+            node.inodes += 1;
+            node.files += 1;
+            node.files_size = node.files_size.saturating_add(file_size);
+
+            if !config.list_files || config.summarize || !config.reports(job.depth + 1) {
+                continue;
+            }
+            if config.shows_size(file_size) {
+                node.children.push(Child::File {
+                    name: file_name_bytes.to_vec(),
+                    size: file_size,
+                    mtime: file_mtime,
+                });
+            } else {
+                node.skip(config, &job.path, file_name_bytes, SkipReason::Threshold);
+            }
+        }
+
+        if !subdirs.is_empty() {
+            let dir = Arc::new(DirHandle {
+                id: job.node,
+                open: Mutex::new(Some(Arc::new(dir))),
+                parent: job.parent,
+                name: job.name,
+                inode: (meta.dev, meta.ino),
+                pending: AtomicUsize::new(subdirs.len()),
+            });
+            if dir.parent.is_some() {
+                self.open_dirs
+                    .lock()
+                    .unwrap()
+                    .insert(dir.id, Arc::downgrade(&dir));
+            }
+            for subdir in &mut subdirs {
+                subdir.parent = Some(Arc::clone(&dir));
+            }
+            self.push(subdirs);
+        }
+        node
+    }
+}
+
+/// Walks the tree below `root` on `threads` workers, reporting entries to
+/// `visitor` as they are scanned, in the same order as the sequential walk.
+pub(crate) fn walk<V: Visitor>(
+    root: Vec<u8>,
+    config: &TraversalConfig,
+    threads: usize,
+    seen_inodes: &mut FxHashSet<(u64, u64)>,
+    visitor: &mut V,
+) -> Cresult<DirUsage> {
+    let pool = Pool {
+        config,
+        queue: Mutex::new(Queue {
+            jobs: Vec::new(),
+            pending: 0,
+            unreported: 0,
+            wanted: None,
+            cancelled: false,
+        }),
+        ready: Condvar::new(),
+        next_node: AtomicUsize::new(1),
+        seen_inodes: SeenInodes::new(std::mem::take(seen_inodes)),
+        open_dirs: Mutex::new(FxHashMap::default()),
+        finished: Mutex::new(FxHashMap::default()),
+        scanned: Condvar::new(),
+        failed: AtomicBool::new(false),
+    };
+    pool.push(vec![Job {
+        node: 0,
+        parent: None,
+        name: root.clone().into(),
+        path: root.clone(),
+        depth: 0,
+        gitignores: Vec::new(),
+        ancestors: Vec::new(),
+        meta: None,
+    }]);
+
+    let mut path_bytes = root;
+    let usage = thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| pool.work());
+        }
+        let usage = report(&pool, config, visitor, &mut path_bytes);
+        // Lets the workers go once the visitor has given up.
+        pool.cancel();
+        usage
+    });
+
+    *seen_inodes = pool.seen_inodes.into_set();
+    usage
+}
+
+// A directory being reported, with the children still to come.
+struct Frame {
+    children: std::vec::IntoIter<Child>,
+    depth: i32,
+    path_len: usize,
+    usage: DirUsage,
+    deeper: DirUsage,
+}
+
+// Reports what the directory `node` holds ahead of its entries, and returns
+// it as a frame unless it could not be found at all.
+fn open_frame<V: Visitor>(
+    mut node: Node,
+    depth: i32,
+    path_len: usize,
+    visitor: &mut V,
+) -> Cresult<Option<Frame>> {
+    for (mut path, operation, errno) in std::mem::take(&mut node.errors) {
+        report_error(visitor, &mut path, None, operation, errno)?;
+    }
+    for (path, size, allocated) in &node.sparse {
+        visitor.sparse(SparseFile {
+            path,
+            size: *size,
+            allocated: *allocated,
+        })?;
+    }
+    for (path, reason) in &node.skipped {
+        visitor.skipped(Skipped {
+            path,
+            reason: *reason,
+        })?;
+    }
+    if !node.found && depth > 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(Frame {
+        children: node.children.into_iter(),
+        depth,
+        path_len,
+        usage: DirUsage {
+            total: node.own,
+            own: node.own,
+            mtime: node.mtime,
+            inodes: node.inodes,
+            files: node.files,
+            files_size: node.files_size,
+            entries: node.entries,
+        },
+        deeper: DirUsage::default(),
+    }))
+}
+
+// Reports directories as the workers finish them, post-order from an
+// explicit stack, so only the directories from the root down to the one
+// being reported are held and deep trees cannot overflow the stack.
+fn report<V: Visitor>(
+    pool: &Pool,
+    config: &TraversalConfig,
+    visitor: &mut V,
+    path_bytes: &mut Vec<u8>,
+) -> Cresult<DirUsage> {
+    let root = open_frame(pool.take(0), 0, path_bytes.len(), visitor)?;
+    let mut stack = vec![root.expect("the root is always reported")];
+
+    loop {
+        let frame = stack.last_mut().expect("report stack is never empty here");
+        let depth = frame.depth;
+        match frame.children.next() {
+            Some(Child::File { name, size, mtime }) => {
+                let saved_len = path_bytes.len();
+                if !path_bytes.is_empty() {
+                    path_bytes.push(b'/');
+                }
+                path_bytes.extend_from_slice(&name);
+
+                visitor.visit(Visit {
+                    path: path_bytes,
+                    size,
+                    mtime,
+                    is_dir: false,
+                    depth: depth + 1,
+                    inodes: 1,
+                })?;
+                path_bytes.truncate(saved_len);
+            }
+            Some(Child::Dir { name, node }) => {
+                let saved_len = path_bytes.len();
+                if !path_bytes.is_empty() {
+                    path_bytes.push(b'/');
+                }
+                path_bytes.extend_from_slice(&name);
+
+                match open_frame(pool.take(node), depth + 1, path_bytes.len(), visitor)? {
+                    Some(frame) => stack.push(frame),
+                    None => path_bytes.truncate(saved_len),
+                }
+            }
+            None => {
+                let done = stack.pop().expect("report stack is never empty here");
+                report_deeper(visitor, config, path_bytes, &done.deeper, done.depth)?;
+                record_depth(config, done.depth, &done.usage);
+                let Some(parent) = stack.last_mut() else {
+                    return Ok(done.usage);
+                };
+
+                if !config.summarize && config.reports(done.depth) {
+                    report_subdir(visitor, config, path_bytes, &done.usage, done.depth)?;
+                }
+                parent.usage.add_subdir(&done.usage);
+                if config.collapses(parent.depth) {
+                    parent.deeper.add_subdir(&done.usage);
+                }
+                path_bytes.truncate(parent.path_len);
+            }
+        }
+    }
+}
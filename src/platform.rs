@@ -0,0 +1,133 @@
+use crate::timestamp::Timestamp;
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+// The calls du-rs makes to the operating system. The walks open entries
+// relative to an open directory, so that paths of any length can be walked
+// and a directory renamed mid-scan is not mistaken for another. Everything
+// else only sees the types defined here; each target provides them in a
+// module of its own.
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+use unix as sys;
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+use windows as sys;
+
+#[cfg(not(any(unix, windows)))]
+compile_error!("du-rs runs on Unix-like systems and Windows; see src/platform.rs");
+
+pub use sys::os_str;
+pub(crate) use sys::{
+    local_time, open_dir, read_file_at, stat_at, stat_dir, strftime, OpenDir, OwnedDir, RawDir,
+    STRFTIME_CONVERSIONS, STRFTIME_FLAGS, STRFTIME_MODIFIERS,
+};
+
+/// An error reported by the operating system, with its raw code: an `errno`
+/// value on Unix, a Win32 error code on Windows. The constants give the codes
+/// the walk tells apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(i32);
+
+impl Errno {
+    pub const ENOENT: Errno = Errno(sys::ENOENT);
+    pub const ENOTDIR: Errno = Errno(sys::ENOTDIR);
+    pub const ELOOP: Errno = Errno(sys::ELOOP);
+    pub const EMFILE: Errno = Errno(sys::EMFILE);
+    pub const ENFILE: Errno = Errno(sys::ENFILE);
+    pub const EIO: Errno = Errno(sys::EIO);
+
+    pub fn from_raw(code: i32) -> Errno {
+        Errno(code)
+    }
+
+    pub fn raw(self) -> i32 {
+        self.0
+    }
+
+    /// The system's description of the error, such as "No such file or
+    /// directory".
+    pub fn desc(self) -> Cow<'static, str> {
+        sys::describe(self.0)
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.desc())
+    }
+}
+
+impl std::error::Error for Errno {}
+
+impl From<io::Error> for Errno {
+    fn from(error: io::Error) -> Errno {
+        error.raw_os_error().map_or(Errno::EIO, Errno)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Directory,
+    File,
+    Symlink,
+    /// Devices, FIFOs, sockets and the like.
+    Other,
+}
+
+/// What a scan needs to know of a file, whichever system it runs on.
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    /// The device, or on Windows the volume serial number.
+    pub dev: u64,
+    /// The inode, or on Windows the file index.
+    pub ino: u64,
+    pub nlink: u64,
+    pub kind: FileKind,
+    /// Apparent size in bytes.
+    pub size: i64,
+    /// Space allocated, in units of [`crate::ST_BLOCK_SIZE`]. Windows gives
+    /// the compressed size of a file rather than its clusters.
+    pub blocks: i64,
+    pub modified: Timestamp,
+    pub accessed: Timestamp,
+    /// The last status change. Windows keeps no such time, so there it is the
+    /// last write as well.
+    pub changed: Timestamp,
+}
+
+impl Metadata {
+    /// Metadata of `path`, or with `follow` of what a symlink there points to.
+    pub fn of(path: &Path, follow: bool) -> Result<Metadata, Errno> {
+        stat_at(None, path.as_os_str(), follow)
+    }
+
+    #[inline]
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileKind::Directory
+    }
+
+    #[inline]
+    pub fn is_file(&self) -> bool {
+        self.kind == FileKind::File
+    }
+}
+
+/// An entry's type, as a directory listing gives it without a stat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EntryType {
+    Directory,
+    Symlink,
+    Other,
+}
+
+// What the walk keeps of a directory entry. A whole `dirent` is some 280
+// bytes, which adds up in a directory holding millions of files.
+pub(crate) struct DirEntry {
+    pub(crate) name: Box<[u8]>,
+    pub(crate) file_type: Option<EntryType>,
+}
@@ -0,0 +1,232 @@
+use super::{DirEntry, EntryType, Errno, FileKind, Metadata};
+use crate::timestamp::Timestamp;
+use nix::dir::{Dir, Type};
+use nix::fcntl::{fcntl, openat, AtFlags, FcntlArg, OFlag};
+use nix::sys::stat::{fstat, fstatat, FileStat, Mode};
+use std::borrow::Cow;
+use std::ffi::{CStr, OsStr};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+
+pub(super) const ENOENT: i32 = libc::ENOENT;
+pub(super) const ENOTDIR: i32 = libc::ENOTDIR;
+pub(super) const ELOOP: i32 = libc::ELOOP;
+pub(super) const EMFILE: i32 = libc::EMFILE;
+pub(super) const ENFILE: i32 = libc::ENFILE;
+pub(super) const EIO: i32 = libc::EIO;
+
+pub(super) fn describe(code: i32) -> Cow<'static, str> {
+    Cow::Borrowed(nix::errno::Errno::from_raw(code).desc())
+}
+
+impl From<nix::errno::Errno> for Errno {
+    fn from(errno: nix::errno::Errno) -> Errno {
+        Errno::from_raw(errno as i32)
+    }
+}
+
+/// Path bytes as the walk builds them, back as an `OsStr`. On Unix a name is
+/// any run of bytes, so this never changes them.
+pub fn os_str(bytes: &[u8]) -> Cow<'_, OsStr> {
+    Cow::Borrowed(OsStr::from_bytes(bytes))
+}
+
+// An open directory, by its descriptor.
+pub(crate) struct OwnedDir(OwnedFd);
+
+// A directory's descriptor, for calls on its entries while its `OwnedDir` or
+// `OpenDir` is still open.
+#[derive(Clone, Copy)]
+pub(crate) struct RawDir(RawFd);
+
+impl OwnedDir {
+    pub(crate) fn raw(&self) -> RawDir {
+        RawDir(self.0.as_raw_fd())
+    }
+}
+
+// A directory opened for reading. Its descriptor stays usable for the calls
+// on its entries once they have been read.
+pub(crate) struct OpenDir(Dir);
+
+impl OpenDir {
+    pub(crate) fn new(dir: OwnedDir) -> Result<OpenDir, Errno> {
+        Ok(OpenDir(Dir::from_fd(dir.0.into_raw_fd())?))
+    }
+
+    // Reads the directory open as `dir` through a duplicate, leaving `dir`
+    // free for the calls on its entries from other threads.
+    pub(crate) fn dup(dir: &OwnedDir) -> Result<OpenDir, Errno> {
+        let fd = fcntl(dir.0.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(0))?;
+        Ok(OpenDir(Dir::from_fd(fd)?))
+    }
+
+    pub(crate) fn raw(&self) -> RawDir {
+        RawDir(self.0.as_raw_fd())
+    }
+
+    // Every entry but `.` and `..`, up to the end or the first error.
+    pub(crate) fn read(&mut self) -> (Vec<DirEntry>, Option<Errno>) {
+        let mut entries = Vec::new();
+        for entry in self.0.iter() {
+            match entry {
+                Ok(entry) => {
+                    let name = entry.file_name().to_bytes();
+                    if name != b"." && name != b".." {
+                        entries.push(DirEntry {
+                            name: name.into(),
+                            file_type: entry.file_type().map(|file_type| match file_type {
+                                Type::Directory => EntryType::Directory,
+                                Type::Symlink => EntryType::Symlink,
+                                _ => EntryType::Other,
+                            }),
+                        });
+                    }
+                }
+                Err(errno) => return (entries, Some(errno.into())),
+            }
+        }
+        (entries, None)
+    }
+}
+
+fn open_flag(follow: bool) -> OFlag {
+    if !follow {
+        OFlag::O_DIRECTORY | OFlag::O_RDONLY | OFlag::O_NOFOLLOW
+    } else {
+        OFlag::O_DIRECTORY | OFlag::O_RDONLY
+    }
+}
+
+fn at_flag(follow: bool) -> AtFlags {
+    if !follow {
+        AtFlags::AT_SYMLINK_NOFOLLOW
+    } else {
+        AtFlags::empty()
+    }
+}
+
+// Opens the directory `name` in `dir`, or `name` itself as a path without one.
+// Unless `follow`, a symlink to a directory is refused.
+pub(crate) fn open_dir(
+    dir: Option<&RawDir>,
+    name: &OsStr,
+    follow: bool,
+) -> Result<OwnedDir, Errno> {
+    let fd = openat(
+        dir.map(|dir| dir.0),
+        name,
+        open_flag(follow) | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    // SAFETY: `openat` just returned this descriptor to us.
+    Ok(OwnedDir(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+// Metadata of `name` in `dir`, or of `name` itself as a path without one.
+pub(crate) fn stat_at(dir: Option<&RawDir>, name: &OsStr, follow: bool) -> Result<Metadata, Errno> {
+    Ok(metadata(fstatat(
+        dir.map(|dir| dir.0),
+        name,
+        at_flag(follow),
+    )?))
+}
+
+pub(crate) fn stat_dir(dir: &RawDir) -> Result<Metadata, Errno> {
+    Ok(metadata(fstat(dir.0)?))
+}
+
+// The field types vary between targets, hence the casts.
+#[allow(clippy::unnecessary_cast)]
+fn metadata(stat: FileStat) -> Metadata {
+    let kind = match stat.st_mode & libc::S_IFMT {
+        libc::S_IFDIR => FileKind::Directory,
+        libc::S_IFREG => FileKind::File,
+        libc::S_IFLNK => FileKind::Symlink,
+        _ => FileKind::Other,
+    };
+    Metadata {
+        dev: stat.st_dev as u64,
+        ino: stat.st_ino as u64,
+        nlink: stat.st_nlink as u64,
+        kind,
+        size: stat.st_size as i64,
+        blocks: stat.st_blocks as i64,
+        modified: Timestamp {
+            secs: stat.st_mtime as i64,
+            nsecs: stat.st_mtime_nsec as i64,
+        },
+        accessed: Timestamp {
+            secs: stat.st_atime as i64,
+            nsecs: stat.st_atime_nsec as i64,
+        },
+        changed: Timestamp {
+            secs: stat.st_ctime as i64,
+            nsecs: stat.st_ctime_nsec as i64,
+        },
+    }
+}
+
+// Up to `limit` bytes of the file `name` in `dir`, or `None` when it cannot be
+// opened. A read error ends the content early.
+pub(crate) fn read_file_at(
+    dir: &RawDir,
+    name: &OsStr,
+    follow: bool,
+    limit: usize,
+) -> Option<Vec<u8>> {
+    let flags = if follow {
+        OFlag::O_RDONLY
+    } else {
+        OFlag::O_RDONLY | OFlag::O_NOFOLLOW
+    };
+    let fd = openat(Some(dir.0), name, flags | OFlag::O_CLOEXEC, Mode::empty()).ok()?;
+    // SAFETY: `openat` just returned this descriptor to us.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut content = Vec::new();
+    let mut buffer = [0u8; 4096];
+    while content.len() < limit {
+        let want = buffer.len().min(limit - content.len());
+        match nix::unistd::read(fd.as_raw_fd(), &mut buffer[..want]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => content.extend_from_slice(&buffer[..n]),
+        }
+    }
+    Some(content)
+}
+
+pub(crate) fn local_time(secs: i64) -> Option<libc::tm> {
+    let time = secs as libc::time_t;
+    // SAFETY: `tm` is plain old data and `localtime_r` fully initializes it on
+    // success, which is checked through the returned pointer.
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            None
+        } else {
+            Some(tm)
+        }
+    }
+}
+
+// What glibc's strftime takes after a `%`: flags and a width, an `E` or `O`
+// modifier, then the conversion.
+pub(crate) const STRFTIME_FLAGS: &str = "_-0^#0123456789";
+pub(crate) const STRFTIME_MODIFIERS: &str = "EO";
+pub(crate) const STRFTIME_CONVERSIONS: &str = "aAbBcCdDeFgGhHIjklmMnpPrRsStTuUVwWxXyYzZ+%";
+
+// Writes `tm` as `format` into `buffer`, returning the length written, or 0
+// when it does not fit.
+pub(crate) fn strftime(buffer: &mut [u8], format: &CStr, tm: &libc::tm) -> usize {
+    // SAFETY: the buffer length is passed along and strftime never writes
+    // past it, returning the number of bytes written excluding the NUL.
+    unsafe {
+        libc::strftime(
+            buffer.as_mut_ptr() as *mut libc::c_char,
+            buffer.len(),
+            format.as_ptr(),
+            tm,
+        )
+    }
+}
@@ -0,0 +1,293 @@
+use super::{DirEntry, EntryType, Errno, FileKind, Metadata};
+use crate::timestamp::Timestamp;
+use crate::ST_BLOCK_SIZE;
+use std::borrow::Cow;
+use std::ffi::{CStr, OsStr};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::AsRawHandle;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use windows_sys::Win32::Foundation::{
+    ERROR_CANT_RESOLVE_FILENAME, ERROR_DIRECTORY, ERROR_FILE_NOT_FOUND, ERROR_GEN_FAILURE,
+    ERROR_NO_SYSTEM_RESOURCES, ERROR_TOO_MANY_OPEN_FILES, NO_ERROR,
+};
+use windows_sys::Win32::Storage::FileSystem::{
+    GetCompressedFileSizeW, GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION,
+    FILE_FLAG_BACKUP_SEMANTICS, FILE_FLAG_OPEN_REPARSE_POINT, INVALID_FILE_SIZE,
+};
+
+pub(super) const ENOENT: i32 = ERROR_FILE_NOT_FOUND as i32;
+pub(super) const ENOTDIR: i32 = ERROR_DIRECTORY as i32;
+pub(super) const ELOOP: i32 = ERROR_CANT_RESOLVE_FILENAME as i32;
+pub(super) const EMFILE: i32 = ERROR_TOO_MANY_OPEN_FILES as i32;
+pub(super) const ENFILE: i32 = ERROR_NO_SYSTEM_RESOURCES as i32;
+pub(super) const EIO: i32 = ERROR_GEN_FAILURE as i32;
+
+// The message Windows has for the code, without the " (os error N)" that
+// `io::Error` adds to it.
+pub(super) fn describe(code: i32) -> Cow<'static, str> {
+    let mut message = io::Error::from_raw_os_error(code).to_string();
+    if let Some(end) = message.rfind(" (os error ") {
+        message.truncate(end);
+    }
+    Cow::Owned(message.trim_end_matches(['.', '\r', '\n']).to_string())
+}
+
+/// Path bytes as the walk builds them, back as an `OsStr`. Names from the
+/// system come as UTF-8 bar unpaired surrogates; bytes read from files, such
+/// as exclude lists, can be anything, and what is not UTF-8 is read lossily.
+pub fn os_str(bytes: &[u8]) -> Cow<'_, OsStr> {
+    match std::str::from_utf8(bytes) {
+        Ok(name) => Cow::Borrowed(OsStr::new(name)),
+        Err(_) => Cow::Owned(String::from_utf8_lossy(bytes).into_owned().into()),
+    }
+}
+
+// A directory, by its path. std has no calls relative to a directory handle,
+// so entries are reached by joining their names to it; `stat_dir` then checks
+// a reopened directory is still the one that was walked, as on Unix.
+#[derive(Clone)]
+pub(crate) struct OwnedDir(Arc<Path>);
+
+#[derive(Clone)]
+pub(crate) struct RawDir(Arc<Path>);
+
+impl OwnedDir {
+    pub(crate) fn raw(&self) -> RawDir {
+        RawDir(Arc::clone(&self.0))
+    }
+}
+
+// A directory opened for reading. The listing holds a whole find buffer, so
+// it is boxed to keep the walk's frames small.
+pub(crate) struct OpenDir {
+    path: Arc<Path>,
+    entries: Box<fs::ReadDir>,
+}
+
+impl OpenDir {
+    pub(crate) fn new(dir: OwnedDir) -> Result<OpenDir, Errno> {
+        let entries = Box::new(fs::read_dir(&dir.0)?);
+        Ok(OpenDir {
+            path: dir.0,
+            entries,
+        })
+    }
+
+    pub(crate) fn dup(dir: &OwnedDir) -> Result<OpenDir, Errno> {
+        OpenDir::new(dir.clone())
+    }
+
+    pub(crate) fn raw(&self) -> RawDir {
+        RawDir(Arc::clone(&self.path))
+    }
+
+    // Every entry, up to the end or the first error. std leaves out `.` and
+    // `..` already.
+    pub(crate) fn read(&mut self) -> (Vec<DirEntry>, Option<Errno>) {
+        let mut entries = Vec::new();
+        for entry in &mut self.entries {
+            match entry {
+                Ok(entry) => entries.push(DirEntry {
+                    name: entry.file_name().into_encoded_bytes().into(),
+                    file_type: entry.file_type().ok().map(|file_type| {
+                        if file_type.is_symlink() {
+                            EntryType::Symlink
+                        } else if file_type.is_dir() {
+                            EntryType::Directory
+                        } else {
+                            EntryType::Other
+                        }
+                    }),
+                }),
+                Err(e) => return (entries, Some(e.into())),
+            }
+        }
+        (entries, None)
+    }
+}
+
+fn join(dir: Option<&RawDir>, name: &OsStr) -> PathBuf {
+    match dir {
+        Some(dir) => dir.0.join(name),
+        None => PathBuf::from(name),
+    }
+}
+
+// Opens the directory `name` in `dir`, or `name` itself as a path without one.
+// Unless `follow`, a symlink or junction to a directory is refused.
+pub(crate) fn open_dir(
+    dir: Option<&RawDir>,
+    name: &OsStr,
+    follow: bool,
+) -> Result<OwnedDir, Errno> {
+    let path = join(dir, name);
+    match stat_path(&path, follow)?.kind {
+        FileKind::Directory => Ok(OwnedDir(path.into())),
+        FileKind::Symlink => Err(Errno::ELOOP),
+        _ => Err(Errno::ENOTDIR),
+    }
+}
+
+// Metadata of `name` in `dir`, or of `name` itself as a path without one.
+pub(crate) fn stat_at(dir: Option<&RawDir>, name: &OsStr, follow: bool) -> Result<Metadata, Errno> {
+    stat_path(&join(dir, name), follow)
+}
+
+pub(crate) fn stat_dir(dir: &RawDir) -> Result<Metadata, Errno> {
+    stat_path(&dir.0, true)
+}
+
+// The file is opened without asking for any access, which is enough to read
+// its attributes even when its content is locked. Directories need backup
+// semantics to be opened at all.
+fn stat_path(path: &Path, follow: bool) -> Result<Metadata, Errno> {
+    let mut flags = FILE_FLAG_BACKUP_SEMANTICS;
+    if !follow {
+        flags |= FILE_FLAG_OPEN_REPARSE_POINT;
+    }
+    let file = OpenOptions::new()
+        .access_mode(0)
+        .custom_flags(flags)
+        .open(path)?;
+    let meta = file.metadata()?;
+
+    // SAFETY: the structure is plain old data, filled in on success.
+    let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { std::mem::zeroed() };
+    // SAFETY: the handle stays open for the call as `file` is borrowed.
+    if unsafe { GetFileInformationByHandle(file.as_raw_handle(), &mut info) } == 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    let file_type = meta.file_type();
+    let kind = if file_type.is_symlink() {
+        FileKind::Symlink
+    } else if file_type.is_dir() {
+        FileKind::Directory
+    } else if file_type.is_file() {
+        FileKind::File
+    } else {
+        FileKind::Other
+    };
+    let size = meta.len() as i64;
+    let allocated = match kind {
+        FileKind::File => compressed_size(path).unwrap_or(size),
+        _ => 0,
+    };
+    let modified = timestamp(meta.modified());
+    Ok(Metadata {
+        dev: info.dwVolumeSerialNumber as u64,
+        ino: (info.nFileIndexHigh as u64) << 32 | info.nFileIndexLow as u64,
+        nlink: info.nNumberOfLinks as u64,
+        kind,
+        size,
+        blocks: (allocated + ST_BLOCK_SIZE - 1) / ST_BLOCK_SIZE,
+        modified,
+        accessed: timestamp(meta.accessed()),
+        changed: modified,
+    })
+}
+
+// What the file takes on disk: less than its size when it is compressed or
+// sparse, its size otherwise.
+fn compressed_size(path: &Path) -> Option<i64> {
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+    let mut high = 0;
+    // SAFETY: `wide` is NUL-terminated and outlives the call.
+    let low = unsafe { GetCompressedFileSizeW(wide.as_ptr(), &mut high) };
+    if low == INVALID_FILE_SIZE
+        && io::Error::last_os_error().raw_os_error() != Some(NO_ERROR as i32)
+    {
+        return None;
+    }
+    Some(((high as u64) << 32 | low as u64) as i64)
+}
+
+fn timestamp(time: io::Result<SystemTime>) -> Timestamp {
+    let Ok(time) = time else {
+        return Timestamp::default();
+    };
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => Timestamp {
+            secs: after.as_secs() as i64,
+            nsecs: after.subsec_nanos() as i64,
+        },
+        Err(e) => {
+            let before = e.duration();
+            let (secs, nsecs) = (before.as_secs() as i64, before.subsec_nanos() as i64);
+            if nsecs == 0 {
+                Timestamp { secs: -secs, nsecs }
+            } else {
+                Timestamp {
+                    secs: -secs - 1,
+                    nsecs: 1_000_000_000 - nsecs,
+                }
+            }
+        }
+    }
+}
+
+// Up to `limit` bytes of the file `name` in `dir`, or `None` when it cannot be
+// opened. A read error ends the content early.
+pub(crate) fn read_file_at(
+    dir: &RawDir,
+    name: &OsStr,
+    follow: bool,
+    limit: usize,
+) -> Option<Vec<u8>> {
+    let path = dir.0.join(name);
+    if !follow && fs::symlink_metadata(&path).ok()?.is_symlink() {
+        return None;
+    }
+    let file = File::open(&path).ok()?;
+    let mut content = Vec::new();
+    let _ = file.take(limit as u64).read_to_end(&mut content);
+    Some(content)
+}
+
+pub(crate) fn local_time(secs: i64) -> Option<libc::tm> {
+    let time = secs as libc::time_t;
+    // SAFETY: `tm` is plain old data and `localtime_s` fully initializes it on
+    // success, which is checked through its result.
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        (libc::localtime_s(&mut tm, &time) == 0).then_some(tm)
+    }
+}
+
+// What the C runtime's strftime takes after a `%`: only the `#` flag and the
+// conversions of C89. Anything else makes it call the invalid parameter
+// handler, which ends the process.
+pub(crate) const STRFTIME_FLAGS: &str = "#";
+pub(crate) const STRFTIME_MODIFIERS: &str = "";
+pub(crate) const STRFTIME_CONVERSIONS: &str = "aAbBcdHIjmMpSUwWxXyYzZ%";
+
+extern "C" {
+    // The C runtime has it, the libc crate does not declare it for Windows.
+    #[link_name = "strftime"]
+    fn crt_strftime(
+        buffer: *mut libc::c_char,
+        size: usize,
+        format: *const libc::c_char,
+        tm: *const libc::tm,
+    ) -> usize;
+}
+
+// Writes `tm` as `format` into `buffer`, returning the length written, or 0
+// when it does not fit.
+pub(crate) fn strftime(buffer: &mut [u8], format: &CStr, tm: &libc::tm) -> usize {
+    // SAFETY: the buffer length is passed along and strftime never writes
+    // past it, returning the number of bytes written excluding the NUL.
+    unsafe {
+        crt_strftime(
+            buffer.as_mut_ptr() as *mut libc::c_char,
+            buffer.len(),
+            format.as_ptr(),
+            tm,
+        )
+    }
+}
@@ -0,0 +1,238 @@
+use crate::platform::{self, Metadata};
+use std::ffi::CString;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub secs: i64,
+    pub nsecs: i64,
+}
+
+/// Which of its timestamps an entry is dated by. Access times are only as
+/// good as the mount options allow: `noatime` stops them from being updated,
+/// and `relatime` only updates them once a day or after a modification.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimeKind {
+    #[default]
+    Modified,
+    Accessed,
+    Changed,
+}
+
+impl TimeKind {
+    pub fn parse(value: &str) -> Option<TimeKind> {
+        match value {
+            "mtime" => Some(TimeKind::Modified),
+            "atime" | "access" | "use" => Some(TimeKind::Accessed),
+            "ctime" | "status" => Some(TimeKind::Changed),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn of(self, meta: &Metadata) -> Timestamp {
+        match self {
+            TimeKind::Modified => meta.modified,
+            TimeKind::Accessed => meta.accessed,
+            TimeKind::Changed => meta.changed,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum TimeStyle {
+    FullIso,
+    LongIso,
+    Iso,
+    /// `+FORMAT` as in GNU du: a strftime(3) format, where `%N` also gives
+    /// the nanoseconds.
+    Format(String),
+}
+
+impl TimeStyle {
+    pub fn parse(value: &str) -> Option<TimeStyle> {
+        match value {
+            "full-iso" => Some(TimeStyle::FullIso),
+            "long-iso" => Some(TimeStyle::LongIso),
+            "iso" => Some(TimeStyle::Iso),
+            _ => value
+                .strip_prefix('+')
+                .filter(|format| is_valid_format(format))
+                .map(|format| TimeStyle::Format(format.to_string())),
+        }
+    }
+
+    pub fn format(&self, ts: Timestamp) -> String {
+        let Some(tm) = platform::local_time(ts.secs) else {
+            return ts.secs.to_string();
+        };
+
+        match self {
+            TimeStyle::FullIso => strftime(&format!("%Y-%m-%d %H:%M:%S.{:09} %z", ts.nsecs), &tm),
+            TimeStyle::LongIso => strftime("%Y-%m-%d %H:%M", &tm),
+            TimeStyle::Iso => strftime("%Y-%m-%d", &tm),
+            TimeStyle::Format(format) => strftime(&with_nanoseconds(format, ts.nsecs), &tm),
+        }
+    }
+}
+
+// Only conversions the C library's strftime knows are let through, each with
+// the flags, width and modifier it takes, so a typo is refused up front rather
+// than printed as is, or on Windows aborting the run.
+fn is_valid_format(format: &str) -> bool {
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c == '\0' {
+            return false;
+        }
+        if c != '%' {
+            continue;
+        }
+        let mut conversion = chars.next();
+        while conversion.is_some_and(|c| platform::STRFTIME_FLAGS.contains(c)) {
+            conversion = chars.next();
+        }
+        if conversion.is_some_and(|c| platform::STRFTIME_MODIFIERS.contains(c)) {
+            conversion = chars.next();
+        }
+        if !conversion.is_some_and(|c| c == 'N' || platform::STRFTIME_CONVERSIONS.contains(c)) {
+            return false;
+        }
+    }
+    true
+}
+
+// strftime has no `%N`, so it is filled in beforehand. `%%` is kept whole so
+// `%%N` stays a literal `%N`.
+fn with_nanoseconds(format: &str, nsecs: i64) -> String {
+    let mut out = String::with_capacity(format.len());
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('N') => out.push_str(&format!("{:09}", nsecs)),
+            Some(next) => {
+                out.push('%');
+                out.push(next);
+            }
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+// Room for any sane format; one asking for more is cut to nothing instead.
+const MAX_TIME_LEN: usize = 1 << 20;
+
+fn strftime(format: &str, tm: &libc::tm) -> String {
+    let Ok(format) = CString::new(format) else {
+        return String::new();
+    };
+
+    // strftime returns 0 both for output that does not fit and for output
+    // that is empty, so a format that should print something is retried
+    // with more room.
+    let mut buffer = vec![0u8; 256];
+    loop {
+        let len = platform::strftime(&mut buffer, &format, tm);
+        if len > 0 || format.is_empty() || buffer.len() >= MAX_TIME_LEN {
+            return String::from_utf8_lossy(&buffer[..len]).into_owned();
+        }
+        buffer.resize(buffer.len() * 2, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2021-06-15 08:09:10, whatever the local time zone.
+    fn tm() -> libc::tm {
+        // SAFETY: `tm` is plain old data; the fields strftime reads are set.
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        tm.tm_year = 121;
+        tm.tm_mon = 5;
+        tm.tm_mday = 15;
+        tm.tm_hour = 8;
+        tm.tm_min = 9;
+        tm.tm_sec = 10;
+        tm.tm_wday = 2;
+        tm.tm_yday = 165;
+        tm
+    }
+
+    fn format(format: &str) -> String {
+        TimeStyle::Format(format.to_string()).format(Timestamp {
+            secs: 1_623_744_550,
+            nsecs: 42,
+        })
+    }
+
+    #[test]
+    fn parses_formats() {
+        for value in ["+%Y-%m-%d", "+", "+plain", "+100%%", "+%H:%M %N"] {
+            assert!(
+                matches!(TimeStyle::parse(value), Some(TimeStyle::Format(f)) if f == value[1..]),
+                "{:?}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn parses_glibc_formats() {
+        for value in ["+%-d %_H %05Y", "+%Ec %Od %N", "+%s.%N", "+%F %T"] {
+            assert!(TimeStyle::parse(value).is_some(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_formats() {
+        for value in [
+            "%Y", "+%", "+%Y%", "+%Q", "+%-", "+%E", "+%5", "+a\0b", "bogus", "",
+        ] {
+            assert!(TimeStyle::parse(value).is_none(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn fills_in_nanoseconds() {
+        assert_eq!(with_nanoseconds("%N", 42), "000000042");
+        assert_eq!(with_nanoseconds("%s.%N", 999_999_999), "%s.999999999");
+        assert_eq!(with_nanoseconds("%%N", 42), "%%N");
+        assert_eq!(with_nanoseconds("%%%N", 42), "%%000000042");
+        assert_eq!(with_nanoseconds("50%", 42), "50%");
+    }
+
+    #[test]
+    fn formats_with_strftime() {
+        assert_eq!(strftime("%Y-%m-%d %H:%M:%S", &tm()), "2021-06-15 08:09:10");
+        assert_eq!(strftime("100%%", &tm()), "100%");
+        assert_eq!(strftime("", &tm()), "");
+    }
+
+    #[test]
+    fn formats_past_the_first_buffer() {
+        let long = "%Y".repeat(1000);
+        assert_eq!(strftime(&long, &tm()), "2021".repeat(1000));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn formats_with_glibc_extensions() {
+        assert_eq!(strftime("%F %T", &tm()), "2021-06-15 08:09:10");
+        assert_eq!(strftime("%2000Y", &tm()).len(), 2000);
+    }
+
+    #[test]
+    fn format_style_prints_the_date_column() {
+        let date = format("%Y-%m-%d");
+        assert_eq!(date.len(), 10, "{}", date);
+        assert!(date.starts_with("2021-06-1"), "{}", date);
+        assert_eq!(format("%N"), "000000042");
+        assert_eq!(format("%%N"), "%N");
+    }
+}
@@ -0,0 +1,364 @@
+use crate::platform::Metadata;
+use crate::{Cresult, ST_BLOCK_SIZE};
+use anyhow::Error;
+use std::path::Path;
+
+pub const UNITS: [(&str, f64); 8] = [
+    ("K", 1_024.0),
+    ("M", 1_048_576.0),
+    ("G", 1_073_741_824.0),
+    ("T", 1_099_511_627_776.0),
+    ("P", 1_125_899_906_842_624.0),
+    ("E", 1_152_921_504_606_846_976.0),
+    ("Z", 1_180_591_620_717_411_303_424.0),
+    ("Y", 1_208_925_819_614_629_174_706_176.0),
+];
+
+pub fn parse_size_to_bytes(size_str: &str) -> Option<i64> {
+    let size_str = size_str.trim().to_uppercase();
+    let num_end = size_str
+        .chars()
+        .position(|c| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size_str.len());
+
+    let num_part: f64 = size_str[..num_end].parse().ok()?;
+    let multiplier = unit_multiplier(&size_str[num_end..])?;
+    // Too large for an i64 is an error, as in GNU du, not the largest size.
+    let bytes = num_part * multiplier;
+    (bytes < i64::MAX as f64).then_some(bytes as i64)
+}
+
+// `K` and `KiB` are powers of 1024, `KB` powers of 1000. Expects uppercase.
+fn unit_multiplier(unit: &str) -> Option<f64> {
+    if unit.is_empty() {
+        return Some(1.0);
+    }
+
+    let lookup = |table: &[(&str, f64)], unit: &str| {
+        table
+            .iter()
+            .find(|(u, _)| u.eq_ignore_ascii_case(unit))
+            .map(|&(_, multiplier)| multiplier)
+    };
+
+    match unit.strip_suffix("IB") {
+        Some(binary) => lookup(&UNITS, binary),
+        None => lookup(&SI_UNITS, unit).or_else(|| lookup(&UNITS, unit)),
+    }
+}
+
+const SI_UNITS: [(&str, f64); 8] = [
+    ("kB", 1e3),
+    ("MB", 1e6),
+    ("GB", 1e9),
+    ("TB", 1e12),
+    ("PB", 1e15),
+    ("EB", 1e18),
+    ("ZB", 1e21),
+    ("YB", 1e24),
+];
+
+/// How a scaled size is rounded to the digits that are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    Up,
+    Down,
+    /// Ties go to the even digit, as in `printf`.
+    Nearest,
+}
+
+impl Rounding {
+    pub fn parse(value: &str) -> Option<Rounding> {
+        match value {
+            "up" => Some(Rounding::Up),
+            "down" => Some(Rounding::Down),
+            "nearest" => Some(Rounding::Nearest),
+            _ => None,
+        }
+    }
+
+    // Divides in integers so that SI units, which are not exact in binary
+    // floating point, round the same way as powers of 1024.
+    fn divide(self, n: i128, d: i128) -> i128 {
+        let quotient = n.div_euclid(d);
+        let remainder = n.rem_euclid(d);
+        if remainder == 0 {
+            return quotient;
+        }
+        match self {
+            Rounding::Up => quotient + 1,
+            Rounding::Down => quotient,
+            Rounding::Nearest => match (2 * remainder).cmp(&d) {
+                std::cmp::Ordering::Less => quotient,
+                std::cmp::Ordering::Greater => quotient + 1,
+                std::cmp::Ordering::Equal => quotient + (quotient & 1),
+            },
+        }
+    }
+}
+
+pub fn get_file_sizes(
+    file_path: Option<&Path>,
+    bytes: Option<i64>,
+    si: bool,
+    rounding: Rounding,
+    precision: usize,
+    decimal_point: char,
+) -> String {
+    use std::fmt::Write;
+    let bytes = bytes.unwrap_or_else(|| {
+        file_path
+            .and_then(|path| Metadata::of(path, true).ok())
+            .map_or(0, |meta| meta.blocks.saturating_mul(ST_BLOCK_SIZE))
+    });
+
+    let (units, base) = if si {
+        (&SI_UNITS, 1000)
+    } else {
+        (&UNITS, 1024)
+    };
+    let mut output = String::with_capacity(32);
+
+    // As in GNU du, a size too small for any unit is a bare number of bytes.
+    if bytes < base {
+        return bytes.to_string();
+    }
+
+    // Take the first unit the size stays below `base` of once rounded, so a
+    // value just under a boundary reads `1.0M` rather than `1024.0K`. The
+    // last unit takes whatever is left.
+    let scale = 10i128.pow(precision as u32);
+    let mut unit = "";
+    let mut scaled = 0;
+    for &(u, div) in units.iter() {
+        unit = u;
+        scaled = rounding.divide(bytes as i128 * scale, div as i128);
+        if scaled < base as i128 * scale {
+            break;
+        }
+    }
+
+    let _ = write!(output, "{}", scaled / scale);
+    if precision > 0 {
+        let _ = write!(output, "{}{:0precision$}", decimal_point, scaled % scale);
+    }
+    output.push_str(unit);
+    output
+}
+
+/// `size` in blocks of a `-B` argument such as `-BM` or `-B512`. A bare unit
+/// is kept on the number as a suffix.
+pub fn format_size(size: i64, arg: &str, rounding: Rounding) -> Cresult<String> {
+    let adjusted_size = scale_to_block_size(size, arg, rounding)?;
+
+    match arg
+        .strip_prefix("-B")
+        .filter(|unit| UNITS.iter().any(|&(u, _)| u == *unit))
+    {
+        Some(unit) => Ok(format!("{}{}", adjusted_size, unit)),
+        None => Ok(adjusted_size.to_string()),
+    }
+}
+
+pub fn scale_to_block_size(size: i64, arg: &str, rounding: Rounding) -> Cresult<i64> {
+    let block_size = block_size_bytes(arg)?;
+    Ok(rounding.divide(size as i128, block_size) as i64)
+}
+
+/// Bytes in one block of a `-B` argument: `-B` followed by a unit or by a
+/// positive number of bytes.
+pub fn block_size_bytes(arg: &str) -> Cresult<i128> {
+    let Some(spec) = arg.strip_prefix("-B") else {
+        return Err(Error::msg(format!(
+            "invalid block size argument '{}': expected -B and a size",
+            arg
+        )));
+    };
+    if spec.is_empty() {
+        return Err(Error::msg("-B requires a block size"));
+    }
+    if let Some(&(_, bytes)) = UNITS.iter().find(|&&(u, _)| u == spec) {
+        return Ok(bytes as i128);
+    }
+    match spec.parse::<i64>() {
+        Ok(bytes) if bytes > 0 => Ok(bytes as i128),
+        _ => Err(Error::msg(format!(
+            "invalid block size '{}': expected a unit such as K or M, or a positive number",
+            spec
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn human(bytes: i64, si: bool, rounding: Rounding, precision: usize) -> String {
+        get_file_sizes(None, Some(bytes), si, rounding, precision, '.')
+    }
+
+    #[test]
+    fn parses_bare_numbers() {
+        assert_eq!(parse_size_to_bytes("0"), Some(0));
+        assert_eq!(parse_size_to_bytes("512"), Some(512));
+        assert_eq!(parse_size_to_bytes(" 512 "), Some(512));
+        assert_eq!(parse_size_to_bytes("1.5K"), Some(1536));
+    }
+
+    #[test]
+    fn single_letters_and_iec_suffixes_are_binary() {
+        assert_eq!(parse_size_to_bytes("1K"), Some(1024));
+        assert_eq!(parse_size_to_bytes("1k"), Some(1024));
+        assert_eq!(parse_size_to_bytes("1KiB"), Some(1024));
+        assert_eq!(parse_size_to_bytes("1kib"), Some(1024));
+        assert_eq!(parse_size_to_bytes("2M"), Some(2 << 20));
+        assert_eq!(parse_size_to_bytes("2MiB"), Some(2 << 20));
+        assert_eq!(parse_size_to_bytes("3GiB"), Some(3 << 30));
+        assert_eq!(parse_size_to_bytes("1E"), Some(1 << 60));
+    }
+
+    #[test]
+    fn two_letter_suffixes_are_decimal() {
+        assert_eq!(parse_size_to_bytes("1KB"), Some(1000));
+        assert_eq!(parse_size_to_bytes("1kB"), Some(1000));
+        assert_eq!(parse_size_to_bytes("2MB"), Some(2_000_000));
+        assert_eq!(parse_size_to_bytes("3GB"), Some(3_000_000_000));
+    }
+
+    #[test]
+    fn rejects_malformed_sizes() {
+        for size in [
+            "", "K", "KiB", ".", "1.2.3", "-1", "10Q", "5garbage", "1 K", "1KIBB", "1B",
+        ] {
+            assert_eq!(parse_size_to_bytes(size), None, "{:?}", size);
+        }
+    }
+
+    #[test]
+    fn rejects_sizes_past_i64() {
+        assert_eq!(parse_size_to_bytes("8E"), None);
+        assert_eq!(parse_size_to_bytes("1Z"), None);
+        assert_eq!(parse_size_to_bytes("1YB"), None);
+        assert_eq!(parse_size_to_bytes("99999999999999999999"), None);
+        assert_eq!(parse_size_to_bytes("7E"), Some(7 << 60));
+    }
+
+    #[test]
+    fn unit_multipliers() {
+        assert_eq!(unit_multiplier(""), Some(1.0));
+        assert_eq!(unit_multiplier("K"), Some(1024.0));
+        assert_eq!(unit_multiplier("KB"), Some(1000.0));
+        assert_eq!(unit_multiplier("KIB"), Some(1024.0));
+        assert_eq!(unit_multiplier("Y"), Some(2f64.powi(80)));
+        assert_eq!(unit_multiplier("YB"), Some(1e24));
+        assert_eq!(unit_multiplier("Q"), None);
+        assert_eq!(unit_multiplier("IB"), None);
+    }
+
+    #[test]
+    fn human_sizes_around_1k() {
+        assert_eq!(human(1023, false, Rounding::Up, 1), "1023");
+        assert_eq!(human(1024, false, Rounding::Up, 1), "1.0K");
+        assert_eq!(human(1025, false, Rounding::Up, 1), "1.1K");
+        assert_eq!(human(1025, false, Rounding::Nearest, 1), "1.0K");
+        assert_eq!(human(1025, false, Rounding::Down, 1), "1.0K");
+    }
+
+    #[test]
+    fn human_sizes_below_a_unit_are_bare_bytes() {
+        assert_eq!(human(0, false, Rounding::Up, 1), "0");
+        assert_eq!(human(5, false, Rounding::Up, 1), "5");
+        assert_eq!(human(999, true, Rounding::Up, 1), "999");
+        assert_eq!(human(1000, true, Rounding::Up, 1), "1.0kB");
+    }
+
+    #[test]
+    fn human_size_of_a_five_byte_file() {
+        let path = std::env::temp_dir().join(format!("du-rs-five-bytes-{}", std::process::id()));
+        std::fs::write(&path, b"hello").unwrap();
+        let allocated = Metadata::of(&path, true).unwrap().blocks * ST_BLOCK_SIZE;
+        let size = get_file_sizes(Some(&path), None, false, Rounding::Up, 1, '.');
+        std::fs::remove_file(&path).unwrap();
+
+        // Measured on disk, the file takes a whole block, 4.0K on most file
+        // systems; none print a `B`.
+        assert_eq!(size, human(allocated, false, Rounding::Up, 1));
+        assert!(!size.ends_with('B'), "{}", size);
+    }
+
+    #[test]
+    fn human_sizes_take_the_unit_after_rounding() {
+        // 1023.96K
+        let bytes = 1_048_535;
+        assert_eq!(human(bytes, false, Rounding::Up, 1), "1.0M");
+        assert_eq!(human(bytes, false, Rounding::Nearest, 1), "1.0M");
+        assert_eq!(human(bytes, false, Rounding::Down, 1), "1023.9K");
+        assert_eq!(human(999_960, true, Rounding::Up, 1), "1.0MB");
+        assert_eq!(human(999_960, true, Rounding::Down, 1), "999.9kB");
+    }
+
+    #[test]
+    fn human_sizes_of_large_values() {
+        assert_eq!(human(2 << 40, false, Rounding::Up, 1), "2.0T");
+        assert_eq!(human(1 << 50, false, Rounding::Up, 1), "1.0P");
+        assert_eq!(human(i64::MAX, false, Rounding::Up, 1), "8.0E");
+        assert_eq!(human(i64::MAX, false, Rounding::Down, 1), "7.9E");
+        assert_eq!(human(i64::MAX, true, Rounding::Up, 1), "9.3EB");
+    }
+
+    #[test]
+    fn rounding_modes_on_a_tie() {
+        assert_eq!(human(1536, false, Rounding::Up, 0), "2K");
+        assert_eq!(human(1536, false, Rounding::Down, 0), "1K");
+        assert_eq!(human(1536, false, Rounding::Nearest, 0), "2K");
+        assert_eq!(human(2560, false, Rounding::Nearest, 0), "2K");
+        assert_eq!(human(1536, false, Rounding::Up, 2), "1.50K");
+    }
+
+    #[test]
+    fn block_sizes() {
+        assert_eq!(block_size_bytes("-B1").unwrap(), 1);
+        assert_eq!(block_size_bytes("-B512").unwrap(), 512);
+        assert_eq!(block_size_bytes("-BK").unwrap(), 1024);
+        assert_eq!(block_size_bytes("-BM").unwrap(), 1 << 20);
+    }
+
+    #[test]
+    fn rejects_malformed_block_sizes() {
+        for arg in [
+            "", "B", "-", "-B", "-B0", "-B-1", "-BX", "-Bfoo", "-Bk", "-B1.5", "K",
+        ] {
+            assert!(block_size_bytes(arg).is_err(), "{:?}", arg);
+            assert!(
+                scale_to_block_size(1024, arg, Rounding::Up).is_err(),
+                "{:?}",
+                arg
+            );
+            assert!(format_size(1024, arg, Rounding::Up).is_err(), "{:?}", arg);
+        }
+    }
+
+    #[test]
+    fn scales_to_block_sizes() {
+        assert_eq!(scale_to_block_size(0, "-BK", Rounding::Up).unwrap(), 0);
+        assert_eq!(scale_to_block_size(1, "-BK", Rounding::Up).unwrap(), 1);
+        assert_eq!(scale_to_block_size(1536, "-BK", Rounding::Up).unwrap(), 2);
+        assert_eq!(scale_to_block_size(1536, "-BK", Rounding::Down).unwrap(), 1);
+        assert_eq!(
+            scale_to_block_size(1536, "-BK", Rounding::Nearest).unwrap(),
+            2
+        );
+        assert_eq!(scale_to_block_size(1000, "-B512", Rounding::Up).unwrap(), 2);
+        assert_eq!(
+            scale_to_block_size(i64::MAX, "-B1", Rounding::Up).unwrap(),
+            i64::MAX
+        );
+    }
+
+    #[test]
+    fn formats_block_sizes() {
+        assert_eq!(format_size(2048, "-BK", Rounding::Up).unwrap(), "2K");
+        assert_eq!(format_size(3 << 20, "-BM", Rounding::Up).unwrap(), "3M");
+        assert_eq!(format_size(2048, "-B1024", Rounding::Up).unwrap(), "2");
+    }
+}
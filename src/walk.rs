@@ -0,0 +1,1027 @@
+use crate::cache::{CachedDir, DirCache};
+use crate::dedup::DuplicateFinder;
+use crate::depth::DepthSizes;
+use crate::exclude::Exclusions;
+use crate::extensions::ExtensionSizes;
+use crate::gitignore::Gitignore;
+use crate::histogram::Histogram;
+use crate::mounts::MountPoints;
+use crate::platform::{self, DirEntry, EntryType, Errno, Metadata, OpenDir, OwnedDir, RawDir};
+use crate::timestamp::{TimeKind, Timestamp};
+use crate::{
+    Cresult, DirUsage, EntryLimit, FileStats, Operation, Progress, ScanStats, SizeFormat,
+    SkipReason, Skipped, SparseFile, Visit, Visitor, WalkError, ST_BLOCK_SIZE,
+};
+use fxhash::FxHashSet;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub(crate) struct TraversalConfig {
+    pub(crate) max_depth: Option<i32>,
+    pub(crate) root_dev: Option<u64>,
+    pub(crate) mount_points: Option<MountPoints>,
+    pub(crate) exclusions: Option<Exclusions>,
+    pub(crate) root_len: usize,
+    pub(crate) root_abs: PathBuf,
+    pub(crate) summarize: bool,
+    pub(crate) collapse: bool,
+    pub(crate) list_files: bool,
+    pub(crate) threshold_size: i64,
+    pub(crate) max_size: Option<i64>,
+    pub(crate) exclude_smaller_than: Option<i64>,
+    pub(crate) exclude_larger_than: Option<i64>,
+    pub(crate) newer: Option<Timestamp>,
+    pub(crate) count_hard_link: bool,
+    pub(crate) size_format: SizeFormat,
+    pub(crate) follow_links: bool,
+    // Whether a symlink given as the root is followed.
+    pub(crate) follow_roots: bool,
+    pub(crate) gitignore: bool,
+    pub(crate) exclude_caches: bool,
+    pub(crate) progress: Option<Arc<Progress>>,
+    pub(crate) stats: Option<Arc<ScanStats>>,
+    pub(crate) histogram: Option<Arc<Histogram>>,
+    pub(crate) duplicates: Option<Arc<DuplicateFinder>>,
+    pub(crate) depth_sizes: Option<Arc<DepthSizes>>,
+    pub(crate) extensions: Option<Arc<ExtensionSizes>>,
+    pub(crate) entry_limit: Option<Arc<EntryLimit>>,
+    pub(crate) entries_threshold: Option<u64>,
+    pub(crate) cache: Option<Arc<DirCache>>,
+    pub(crate) warn_sparse: bool,
+    pub(crate) report_skipped: bool,
+    pub(crate) time: TimeKind,
+    pub(crate) separate_dirs: bool,
+}
+
+impl TraversalConfig {
+    // Entries below `max_depth` are still walked so they count towards the
+    // directories that are printed.
+    #[inline]
+    pub(crate) fn reports(&self, depth: i32) -> bool {
+        self.max_depth.is_none_or(|max| depth <= max)
+    }
+
+    // Whether the subdirectories of a directory at `depth` are gathered into
+    // one `(deeper)` entry.
+    #[inline]
+    pub(crate) fn collapses(&self, depth: i32) -> bool {
+        self.collapse && !self.summarize && self.max_depth == Some(depth)
+    }
+
+    // With `-x`, a directory on another device or mounted below the root.
+    #[inline]
+    pub(crate) fn leaves_file_system(&self, dev: u64, path: &[u8]) -> bool {
+        self.root_dev.is_some_and(|root| dev != root)
+            || self.mount_points.as_ref().is_some_and(|mounts| {
+                mounts.contains(path.get(self.root_len + 1..).unwrap_or_default())
+            })
+    }
+
+    // Unlike `shows_size`, this decides what is counted at all.
+    #[inline]
+    pub(crate) fn counts_file(&self, size: i64, meta: &Metadata) -> bool {
+        self.exclude_smaller_than.is_none_or(|min| size >= min)
+            && self.exclude_larger_than.is_none_or(|max| size <= max)
+            && self.newer.is_none_or(|newer| mtime_of(meta) > newer)
+    }
+
+    // A followed link is one more name for its target, so under `-L` every
+    // file is counted once per inode, not just those with several links.
+    #[inline]
+    pub(crate) fn counts_once(&self, meta: &Metadata) -> bool {
+        !self.count_hard_link && (self.follow_links || meta.nlink > 1)
+    }
+
+    // Likewise a directory reached again through a link is not walked twice.
+    // `is_dir_entry` stats every directory entry under `-L`, so its identity
+    // is at hand.
+    #[inline]
+    pub(crate) fn dir_identity(
+        &self,
+        meta: Option<&Result<Metadata, Errno>>,
+    ) -> Option<(u64, u64)> {
+        match meta {
+            Some(Ok(meta)) if self.follow_links && !self.count_hard_link => {
+                Some((meta.dev, meta.ino))
+            }
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn shows_size(&self, size: i64) -> bool {
+        size >= self.threshold_size && self.max_size.is_none_or(|max| size <= max)
+    }
+
+    // Under `--newer` a directory is only of interest for the files it holds.
+    #[inline]
+    pub(crate) fn shows_dir(&self, usage: &DirUsage) -> bool {
+        if let Some(min) = self.entries_threshold {
+            return usage.entries >= min;
+        }
+        self.shows_size(usage.shown(self.separate_dirs))
+            && (self.newer.is_none() || usage.files > 0)
+    }
+
+    // A directory taken from the cache has its files skipped, so nothing
+    // that needs to see each one can be on.
+    fn reuses_cache(&self) -> bool {
+        !self.follow_links
+            && !self.list_files
+            && !self.warn_sparse
+            && !self.report_skipped
+            && self.histogram.is_none()
+            && self.duplicates.is_none()
+            && self.extensions.is_none()
+            && self.entry_limit.is_none()
+    }
+
+    // The size a shown directory is reported with.
+    #[inline]
+    pub(crate) fn dir_size(&self, usage: &DirUsage) -> i64 {
+        match self.entries_threshold {
+            Some(_) => usage.entries as i64,
+            None => usage.shown(self.separate_dirs),
+        }
+    }
+}
+
+pub(crate) struct TraversalState {
+    pub(crate) seen_inodes: FxHashSet<(u64, u64)>,
+    pub(crate) path_bytes: Vec<u8>,
+    // `.gitignore` rules of the directories on the current path, keyed by the
+    // length of `path_bytes` at the directory that holds them.
+    pub(crate) gitignores: Vec<(usize, Arc<Gitignore>)>,
+    // Directories on the current path, only tracked when following links.
+    pub(crate) ancestors: Vec<(u64, u64)>,
+}
+
+#[inline]
+pub fn mtime_of(meta: &Metadata) -> Timestamp {
+    TimeKind::Modified.of(meta)
+}
+
+// Directories closed at once when the walk runs out of descriptors, leaving
+// room for the files a directory opens while it is read.
+pub(crate) const DETACH_BATCH: usize = 8;
+
+// The entries of a directory, then the error that cut reading it short.
+#[derive(Default)]
+struct EntryList {
+    entries: std::vec::IntoIter<DirEntry>,
+    error: Option<Errno>,
+}
+
+impl Iterator for EntryList {
+    type Item = Result<DirEntry, Errno>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.entries.next() {
+            Some(entry) => Some(Ok(entry)),
+            None => self.error.take().map(Err),
+        }
+    }
+}
+
+impl EntryList {
+    fn is_empty(&self) -> bool {
+        self.entries.len() == 0 && self.error.is_none()
+    }
+}
+
+enum Entries {
+    // Read in full and sorted by name, so the output does not depend on the
+    // order the file system hands entries out in. The directory stays open
+    // for the `fstatat` and `openat` calls on its entries.
+    Open(EntryList, OpenDir),
+    // Closed so its subdirectories could be walked with the descriptor; it is
+    // reopened by name once the walk returns to it.
+    Detached(EntryList, Option<OwnedDir>),
+    // Exhausted or could not be read.
+    Done,
+}
+
+// A directory being walked. Its entries are read lazily so the frames on the
+// stack only hold what the directories on the current path need.
+struct Frame {
+    entries: Entries,
+    inode: (u64, u64),
+    depth: i32,
+    // Length of `path_bytes` at this directory.
+    path_len: usize,
+    usage: DirUsage,
+    // Subdirectories below the deepest printed level, under `--collapse`.
+    deeper: DirUsage,
+    has_gitignore: bool,
+    // Whether the directory is on `TraversalState::ancestors`.
+    is_ancestor: bool,
+    is_cache: bool,
+    // What goes into `TraversalConfig::cache` for the directory, dropped once
+    // something in it could not be accounted for the same way next time.
+    record: Option<CachedDir>,
+}
+
+impl Frame {
+    fn raw_dir(&self) -> Option<RawDir> {
+        match &self.entries {
+            Entries::Open(_, dir) => Some(dir.raw()),
+            Entries::Detached(_, dir) => dir.as_ref().map(OwnedDir::raw),
+            Entries::Done => None,
+        }
+    }
+
+    fn next_entry(&mut self) -> Option<Result<DirEntry, Errno>> {
+        match &mut self.entries {
+            Entries::Open(iter, _) => iter.next(),
+            Entries::Detached(iter, _) => iter.next(),
+            Entries::Done => None,
+        }
+    }
+
+    fn needs_reopen(&self) -> bool {
+        matches!(&self.entries, Entries::Detached(iter, None) if !iter.is_empty())
+    }
+
+    // Closes the directory, keeping the entries left to walk.
+    fn detach(&mut self) -> bool {
+        match &mut self.entries {
+            Entries::Open(iter, _) => {
+                self.entries = Entries::Detached(std::mem::take(iter), None);
+                true
+            }
+            Entries::Detached(_, dir) => dir.take().is_some(),
+            Entries::Done => false,
+        }
+    }
+}
+
+// Walks the tree below `dir` with an explicit stack, so its depth is only
+// bounded by memory rather than by the size of the thread's stack.
+pub(crate) fn walk_dir<V: Visitor>(
+    dir: OwnedDir,
+    config: &TraversalConfig,
+    visitor: &mut V,
+    state: &mut TraversalState,
+) -> Cresult<DirUsage> {
+    let mut stack = vec![open_frame(dir, None, 0, config, visitor, state)?];
+
+    loop {
+        if stack.last().is_some_and(Frame::needs_reopen) {
+            if let Err(errno) = reopen(&mut stack, config, &state.path_bytes) {
+                stack
+                    .last_mut()
+                    .expect("walk stack is never empty here")
+                    .entries = Entries::Done;
+                report_error(
+                    visitor,
+                    &mut state.path_bytes,
+                    None,
+                    Operation::ReadDir,
+                    errno,
+                )?;
+            }
+        }
+
+        let frame = stack.last_mut().expect("walk stack is never empty here");
+        let entry = match frame.next_entry() {
+            Some(Ok(_))
+                if config
+                    .entry_limit
+                    .as_ref()
+                    .is_some_and(|limit| !limit.take()) =>
+            {
+                frame.entries = Entries::Done;
+                frame.record = None;
+                continue;
+            }
+            Some(Ok(entry)) => entry,
+            Some(Err(errno)) => {
+                frame.entries = Entries::Done;
+                frame.record = None;
+                state.path_bytes.truncate(frame.path_len);
+                report_error(
+                    visitor,
+                    &mut state.path_bytes,
+                    None,
+                    Operation::ReadDir,
+                    errno,
+                )?;
+                continue;
+            }
+            None => {
+                let done = stack.pop().expect("walk stack is never empty here");
+                close_frame(&done, state);
+                state.path_bytes.truncate(done.path_len);
+                report_deeper(
+                    visitor,
+                    config,
+                    &mut state.path_bytes,
+                    &done.deeper,
+                    done.depth,
+                )?;
+                record_depth(config, done.depth, &done.usage);
+                if let (Some(cache), Some(record)) = (&config.cache, done.record) {
+                    cache.store(done.inode, record, &done.usage);
+                }
+                let Some(parent) = stack.last_mut() else {
+                    return Ok(done.usage);
+                };
+                add_subdir(parent, done.usage, config, visitor, &state.path_bytes)?;
+                state.path_bytes.truncate(parent.path_len);
+                continue;
+            }
+        };
+
+        let raw_dir = frame
+            .raw_dir()
+            .expect("a directory with entries left is open");
+        let depth = frame.depth;
+        let file_name_bytes = &entry.name[..];
+        frame.usage.entries += 1;
+        if let Some(progress) = &config.progress {
+            progress.add_entry();
+        }
+        let file_name_osstr = platform::os_str(file_name_bytes);
+        let (is_dir, meta) = is_dir_entry(&raw_dir, entry.file_type, &file_name_osstr, config);
+
+        if (frame.is_cache && file_name_bytes != CACHEDIR_TAG)
+            || is_excluded(
+                config,
+                &state.path_bytes,
+                &state.gitignores,
+                file_name_bytes,
+                is_dir,
+            )
+        {
+            if let Some(stats) = &config.stats {
+                stats.add_excluded();
+            }
+            report_skipped(
+                visitor,
+                config,
+                &mut state.path_bytes,
+                Some(file_name_bytes),
+                SkipReason::Excluded,
+            )?;
+            continue;
+        }
+
+        if is_dir {
+            let saved_len = state.path_bytes.len();
+
+            if !state.path_bytes.is_empty() {
+                state.path_bytes.push(b'/');
+            }
+            state.path_bytes.extend_from_slice(file_name_bytes);
+
+            if config.follow_links && loops_back(meta.as_ref(), &state.ancestors) {
+                report_error(
+                    visitor,
+                    &mut state.path_bytes,
+                    None,
+                    Operation::Loop,
+                    Errno::ELOOP,
+                )?;
+                state.path_bytes.truncate(saved_len);
+                continue;
+            }
+            if config
+                .dir_identity(meta.as_ref())
+                .is_some_and(|inode| !state.seen_inodes.insert(inode))
+            {
+                report_skipped(
+                    visitor,
+                    config,
+                    &mut state.path_bytes,
+                    None,
+                    SkipReason::AlreadyCounted,
+                )?;
+                state.path_bytes.truncate(saved_len);
+                continue;
+            }
+            if let Some(record) = &mut frame.record {
+                record.subdirs.push(entry.name.clone());
+            }
+
+            let opened = loop {
+                match platform::open_dir(Some(&raw_dir), &file_name_osstr, config.follow_links) {
+                    Err(Errno::EMFILE | Errno::ENFILE) if detach_open_dirs(&mut stack) => {}
+                    result => break result,
+                }
+            };
+            match opened {
+                Ok(sub_dir) => {
+                    let sub = open_frame(sub_dir, meta, depth + 1, config, visitor, state)?;
+                    stack.push(sub);
+                }
+                Err(errno) => {
+                    report_error(
+                        visitor,
+                        &mut state.path_bytes,
+                        None,
+                        Operation::ReadDir,
+                        errno,
+                    )?;
+                    if let Some(usage) =
+                        unreadable_dir_usage(Some(&raw_dir), &file_name_osstr, config)
+                    {
+                        record_depth(config, depth + 1, &usage);
+                        let frame = stack.last_mut().expect("walk stack is never empty here");
+                        add_subdir(frame, usage, config, visitor, &state.path_bytes)?;
+                    }
+                    state.path_bytes.truncate(saved_len);
+                }
+            }
+        } else {
+            if let Some(stats) = &config.stats {
+                stats.add_file();
+            }
+            let child_meta = match meta.unwrap_or_else(|| {
+                platform::stat_at(Some(&raw_dir), &file_name_osstr, config.follow_links)
+            }) {
+                Ok(m) => m,
+                Err(errno) => {
+                    frame.record = None;
+                    report_error(
+                        visitor,
+                        &mut state.path_bytes,
+                        Some(file_name_bytes),
+                        Operation::Stat,
+                        errno,
+                    )?;
+                    continue;
+                }
+            };
+
+            let file_stats = FileStats {
+                size: child_meta.size,
+                blocks: child_meta.blocks,
+            };
+            let file_size = config.size_format.get_file_size(&file_stats);
+            if !config.counts_file(file_size, &child_meta) {
+                if let Some(stats) = &config.stats {
+                    stats.add_excluded();
+                }
+                report_skipped(
+                    visitor,
+                    config,
+                    &mut state.path_bytes,
+                    Some(file_name_bytes),
+                    SkipReason::Filtered,
+                )?;
+                continue;
+            }
+
+            if config.counts_once(&child_meta) {
+                // Which of its links a hard-linked file is counted under
+                // depends on what else is walked, so its directory is read
+                // every time.
+                frame.record = None;
+                let inode = (child_meta.dev, child_meta.ino);
+                if !state.seen_inodes.insert(inode) {
+                    report_skipped(
+                        visitor,
+                        config,
+                        &mut state.path_bytes,
+                        Some(file_name_bytes),
+                        SkipReason::AlreadyCounted,
+                    )?;
+                    continue;
+                }
+            }
+
+            let file_mtime = config.time.of(&child_meta);
+            if let Some(histogram) = &config.histogram {
+                histogram.record(config.size_format.get_file_bytes(&file_stats) as u64);
+            }
+            if let Some(extensions) = &config.extensions {
+                extensions.record(file_name_bytes, file_size);
+            }
+            record_duplicate_candidate(config, &state.path_bytes, file_name_bytes, &child_meta);
+            if warns_sparse(config, &child_meta, &file_stats) {
+                let saved_len = state.path_bytes.len();
+                if !state.path_bytes.is_empty() {
+                    state.path_bytes.push(b'/');
+                }
+                state.path_bytes.extend_from_slice(file_name_bytes);
+                visitor.sparse(SparseFile {
+                    path: &state.path_bytes,
+                    size: file_stats.size,
+                    allocated: file_stats.blocks * ST_BLOCK_SIZE,
+                })?;
+                state.path_bytes.truncate(saved_len);
+            }
+            frame.usage.add(file_size);
+            frame.usage.mtime = frame.usage.mtime.max(file_mtime);
+            frame.usage.inodes += 1;
+            frame.usage.files += 1;
+            frame.usage.files_size = frame.usage.files_size.saturating_add(file_size);
+            if let Some(record) = &mut frame.record {
+                record.add_file(file_size, file_mtime);
+            }
+
+            if !config.list_files || config.summarize || !config.reports(depth + 1) {
+                continue;
+            }
+            if !config.shows_size(file_size) {
+                report_skipped(
+                    visitor,
+                    config,
+                    &mut state.path_bytes,
+                    Some(file_name_bytes),
+                    SkipReason::Threshold,
+                )?;
+            } else {
+                let saved_len = state.path_bytes.len();
+
+                if !state.path_bytes.is_empty() {
+                    state.path_bytes.push(b'/');
+                }
+                state.path_bytes.extend_from_slice(file_name_bytes);
+
+                visitor.visit(Visit {
+                    path: &state.path_bytes,
+                    size: file_size,
+                    mtime: file_mtime,
+                    is_dir: false,
+                    depth: depth + 1,
+                    inodes: 1,
+                })?;
+
+                state.path_bytes.truncate(saved_len);
+            }
+        }
+    }
+}
+
+// Accounts for the directory's own inode and starts reading it.
+// `meta` is the directory's metadata when its parent already had to stat it.
+fn open_frame<V: Visitor>(
+    dir: OwnedDir,
+    meta: Option<Result<Metadata, Errno>>,
+    depth: i32,
+    config: &TraversalConfig,
+    visitor: &mut V,
+    state: &mut TraversalState,
+) -> Cresult<Frame> {
+    let raw_dir = dir.raw();
+    let mut frame = Frame {
+        entries: Entries::Done,
+        inode: (0, 0),
+        depth,
+        path_len: state.path_bytes.len(),
+        usage: DirUsage::default(),
+        deeper: DirUsage::default(),
+        has_gitignore: false,
+        is_ancestor: false,
+        is_cache: false,
+        record: None,
+    };
+
+    let meta = match meta.unwrap_or_else(|| platform::stat_dir(&raw_dir)) {
+        Ok(meta) => meta,
+        Err(errno) => {
+            report_error(visitor, &mut state.path_bytes, None, Operation::Stat, errno)?;
+            return Ok(frame);
+        }
+    };
+
+    frame.inode = (meta.dev, meta.ino);
+    frame.usage.inodes = 1;
+
+    let dir_size = config.size_format.get_dir_size(&FileStats {
+        size: meta.size,
+        blocks: meta.blocks,
+    });
+    frame.usage.add(dir_size);
+    frame.usage.mtime = config.time.of(&meta);
+
+    // As in du, a mount point counts as a directory, just not what is on it.
+    if config.leaves_file_system(meta.dev, &state.path_bytes) {
+        report_skipped(
+            visitor,
+            config,
+            &mut state.path_bytes,
+            None,
+            SkipReason::OtherFileSystem,
+        )?;
+        return Ok(frame);
+    }
+
+    let mut dir = match OpenDir::new(dir) {
+        Ok(d) => d,
+        Err(errno) => {
+            report_error(
+                visitor,
+                &mut state.path_bytes,
+                None,
+                Operation::ReadDir,
+                errno,
+            )?;
+            return Ok(frame);
+        }
+    };
+
+    if let Some(rules) = config.gitignore.then(|| load_gitignore(&raw_dir)).flatten() {
+        state.gitignores.push((frame.path_len, Arc::new(rules)));
+        frame.has_gitignore = true;
+    }
+
+    frame.is_cache = config.exclude_caches && is_cache_dir(&raw_dir);
+    if let Some(progress) = &config.progress {
+        progress.enter_dir(&state.path_bytes);
+    }
+    if let Some(stats) = &config.stats {
+        stats.add_dir();
+    }
+    if config.follow_links {
+        state.ancestors.push(frame.inode);
+        frame.is_ancestor = true;
+    }
+
+    if let Some(cache) = &config.cache {
+        if let Some(cached) = config.reuses_cache().then(|| cache.lookup(&meta)).flatten() {
+            cached.add_files_to(&mut frame.usage);
+            frame.record = Some(cached.reuse());
+            let subdirs = cached.subdirs.iter().map(|name| DirEntry {
+                name: name.clone(),
+                file_type: Some(EntryType::Directory),
+            });
+            frame.entries = Entries::Open(
+                EntryList {
+                    entries: subdirs.collect::<Vec<_>>().into_iter(),
+                    error: None,
+                },
+                dir,
+            );
+            return Ok(frame);
+        }
+        frame.record = Some(CachedDir::new(&meta));
+    }
+    frame.entries = Entries::Open(read_sorted(&mut dir), dir);
+    Ok(frame)
+}
+
+// Reads a directory up to its end or first error.
+fn read_sorted(dir: &mut OpenDir) -> EntryList {
+    let (mut entries, error) = dir.read();
+    entries.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    EntryList {
+        entries: entries.into_iter(),
+        error,
+    }
+}
+
+// Frees descriptors by closing the shallowest open directories below the one
+// being read. The root stays open so detached directories can be reopened.
+fn detach_open_dirs(stack: &mut [Frame]) -> bool {
+    let Some((_, below)) = stack.split_last_mut() else {
+        return false;
+    };
+    let mut detached = 0;
+    for frame in below.iter_mut().skip(1) {
+        if frame.detach() {
+            detached += 1;
+            if detached == DETACH_BATCH {
+                break;
+            }
+        }
+    }
+    detached > 0
+}
+
+// Reopens the directory on top of the stack from its nearest open ancestor,
+// checking it is still the directory that was detached.
+fn reopen(stack: &mut [Frame], config: &TraversalConfig, path_bytes: &[u8]) -> Result<(), Errno> {
+    let (top, below) = stack
+        .split_last_mut()
+        .expect("walk stack is never empty here");
+    let base = below
+        .iter()
+        .rposition(|frame| frame.raw_dir().is_some())
+        .expect("the root directory stays open");
+
+    let mut dir: Option<OwnedDir> = None;
+    let mut parent_len = below[base].path_len;
+    for frame in below[base + 1..].iter().chain(std::iter::once(&*top)) {
+        let parent = match &dir {
+            Some(dir) => dir.raw(),
+            None => below[base].raw_dir().expect("checked above"),
+        };
+        let name = &path_bytes[parent_len + 1..frame.path_len];
+        dir = Some(platform::open_dir(
+            Some(&parent),
+            &platform::os_str(name),
+            config.follow_links,
+        )?);
+        parent_len = frame.path_len;
+    }
+
+    let dir = dir.expect("the root directory is never detached");
+    let meta = platform::stat_dir(&dir.raw())?;
+    if (meta.dev, meta.ino) != top.inode {
+        return Err(Errno::ENOENT);
+    }
+    if let Entries::Detached(_, slot) = &mut top.entries {
+        *slot = Some(dir);
+    }
+    Ok(())
+}
+
+fn close_frame(frame: &Frame, state: &mut TraversalState) {
+    if frame.has_gitignore {
+        state.gitignores.pop();
+    }
+    if frame.is_ancestor {
+        state.ancestors.pop();
+    }
+}
+
+fn add_subdir<V: Visitor>(
+    parent: &mut Frame,
+    subdir_usage: DirUsage,
+    config: &TraversalConfig,
+    visitor: &mut V,
+    path: &[u8],
+) -> Cresult<()> {
+    if !config.summarize && config.reports(parent.depth + 1) {
+        report_subdir(visitor, config, path, &subdir_usage, parent.depth + 1)?;
+    }
+
+    parent.usage.add_subdir(&subdir_usage);
+    if config.collapses(parent.depth) {
+        parent.deeper.add_subdir(&subdir_usage);
+    }
+    Ok(())
+}
+
+// Reports the subdirectories gathered in `deeper` as one entry inside the
+// directory at `path_bytes`.
+pub(crate) fn report_deeper<V: Visitor>(
+    visitor: &mut V,
+    config: &TraversalConfig,
+    path_bytes: &mut Vec<u8>,
+    deeper: &DirUsage,
+    depth: i32,
+) -> Cresult<()> {
+    if deeper.inodes == 0 || !config.shows_dir(deeper) {
+        return Ok(());
+    }
+
+    let saved_len = path_bytes.len();
+    path_bytes.extend_from_slice(b"/(deeper)");
+    let result = visitor.visit(Visit {
+        path: path_bytes,
+        size: deeper.total,
+        mtime: deeper.mtime,
+        is_dir: true,
+        depth: depth + 1,
+        inodes: deeper.inodes,
+    });
+    path_bytes.truncate(saved_len);
+    result
+}
+
+// Under `-L` a symlink to a directory is walked like the directory itself.
+// When the entry type alone does not tell, or when following links, the entry
+// is stat'd and the result handed back so it is not fetched again.
+pub(crate) fn is_dir_entry(
+    dir: &RawDir,
+    file_type: Option<EntryType>,
+    name: &OsStr,
+    config: &TraversalConfig,
+) -> (bool, Option<Result<Metadata, Errno>>) {
+    match file_type {
+        Some(EntryType::Directory | EntryType::Symlink) if config.follow_links => {}
+        Some(EntryType::Directory) => return (true, None),
+        None => {}
+        Some(_) => return (false, None),
+    }
+    let meta = platform::stat_at(Some(dir), name, config.follow_links);
+    let is_dir = meta.as_ref().is_ok_and(Metadata::is_dir);
+    (is_dir, Some(meta))
+}
+
+// Only a followed link can lead back to a directory on the current path, and
+// `is_dir_entry` has already stat'd every one of those.
+pub(crate) fn loops_back(meta: Option<&Result<Metadata, Errno>>, ancestors: &[(u64, u64)]) -> bool {
+    matches!(meta, Some(Ok(meta)) if ancestors.contains(&(meta.dev, meta.ino)))
+}
+
+// Only regular files have content to compare, and opening a FIFO would block.
+pub(crate) fn record_duplicate_candidate(
+    config: &TraversalConfig,
+    dir: &[u8],
+    name: &[u8],
+    meta: &Metadata,
+) {
+    let Some(duplicates) = &config.duplicates else {
+        return;
+    };
+    if !meta.is_file() {
+        return;
+    }
+
+    let mut path = Vec::with_capacity(dir.len() + name.len() + 1);
+    path.extend_from_slice(dir);
+    if !path.is_empty() {
+        path.push(b'/');
+    }
+    path.extend_from_slice(name);
+    duplicates.record(&path, meta.size as u64);
+}
+
+// Symlinks and special files also report sizes larger than their blocks, so
+// only regular files are considered.
+#[inline]
+pub(crate) fn warns_sparse(config: &TraversalConfig, meta: &Metadata, stats: &FileStats) -> bool {
+    config.warn_sparse && meta.is_file() && stats.is_sparse()
+}
+
+#[inline]
+pub(crate) fn record_depth(config: &TraversalConfig, depth: i32, usage: &DirUsage) {
+    if let Some(depth_sizes) = &config.depth_sizes {
+        // Nothing was counted past a mount point left out by `-x`.
+        if usage.inodes > 0 {
+            depth_sizes.add(depth, usage.own);
+        }
+    }
+}
+
+// A directory that cannot be opened still counts with its own inode, as in du.
+pub(crate) fn unreadable_dir_usage(
+    dir: Option<&RawDir>,
+    name: &OsStr,
+    config: &TraversalConfig,
+) -> Option<DirUsage> {
+    let meta = platform::stat_at(dir, name, config.follow_links).ok()?;
+    let size = config.size_format.get_dir_size(&FileStats {
+        size: meta.size,
+        blocks: meta.blocks,
+    });
+    Some(DirUsage {
+        total: size,
+        own: size,
+        mtime: config.time.of(&meta),
+        inodes: 1,
+        files: 0,
+        files_size: 0,
+        entries: 0,
+    })
+}
+
+// A directory that is walked but not shown is left out by the threshold, or
+// under `--newer` for holding no recent files.
+pub(crate) fn report_subdir<V: Visitor>(
+    visitor: &mut V,
+    config: &TraversalConfig,
+    path: &[u8],
+    usage: &DirUsage,
+    depth: i32,
+) -> Cresult<()> {
+    if config.shows_dir(usage) {
+        visitor.visit(Visit {
+            path,
+            size: config.dir_size(usage),
+            mtime: usage.mtime,
+            is_dir: true,
+            depth,
+            inodes: usage.inodes,
+        })
+    } else if config.report_skipped
+        && (config.entries_threshold.is_some()
+            || !config.shows_size(usage.shown(config.separate_dirs)))
+    {
+        visitor.skipped(Skipped {
+            path,
+            reason: SkipReason::Threshold,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+// Does nothing unless skipped entries are reported, sparing the path.
+pub(crate) fn report_skipped<V: Visitor>(
+    visitor: &mut V,
+    config: &TraversalConfig,
+    path_bytes: &mut Vec<u8>,
+    name: Option<&[u8]>,
+    reason: SkipReason,
+) -> Cresult<()> {
+    if !config.report_skipped {
+        return Ok(());
+    }
+    let saved_len = path_bytes.len();
+    if let Some(name) = name {
+        if !path_bytes.is_empty() {
+            path_bytes.push(b'/');
+        }
+        path_bytes.extend_from_slice(name);
+    }
+
+    let result = visitor.skipped(Skipped {
+        path: path_bytes,
+        reason,
+    });
+    path_bytes.truncate(saved_len);
+    result
+}
+
+// Reports an error on `path_bytes`, or on `name` inside it when given.
+pub(crate) fn report_error<V: Visitor>(
+    visitor: &mut V,
+    path_bytes: &mut Vec<u8>,
+    name: Option<&[u8]>,
+    operation: Operation,
+    errno: Errno,
+) -> Cresult<()> {
+    let saved_len = path_bytes.len();
+    if let Some(name) = name {
+        if !path_bytes.is_empty() {
+            path_bytes.push(b'/');
+        }
+        path_bytes.extend_from_slice(name);
+    }
+
+    let result = visitor.error(WalkError {
+        path: path_bytes,
+        operation,
+        errno,
+    });
+    path_bytes.truncate(saved_len);
+    result
+}
+
+pub(crate) const CACHEDIR_TAG: &[u8] = b"CACHEDIR.TAG";
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+// Cache directories are tagged per https://bford.info/cachedir/. Like tar,
+// only the tag file itself is kept from their contents.
+pub(crate) fn is_cache_dir(dir: &RawDir) -> bool {
+    platform::read_file_at(
+        dir,
+        &platform::os_str(CACHEDIR_TAG),
+        false,
+        CACHEDIR_SIGNATURE.len(),
+    )
+    .is_some_and(|content| content == CACHEDIR_SIGNATURE)
+}
+
+pub(crate) fn load_gitignore(dir: &RawDir) -> Option<Gitignore> {
+    let content = platform::read_file_at(dir, OsStr::new(".gitignore"), true, usize::MAX)?;
+    let rules = Gitignore::parse(&content);
+    (!rules.is_empty()).then_some(rules)
+}
+
+fn is_gitignored(
+    path_bytes: &[u8],
+    gitignores: &[(usize, Arc<Gitignore>)],
+    name: &[u8],
+    is_dir: bool,
+) -> bool {
+    let mut ignored = false;
+
+    // Deeper `.gitignore` files take precedence over their parents.
+    for (base_len, rules) in gitignores.iter().rev() {
+        let mut relative = Vec::with_capacity(path_bytes.len() + name.len());
+        if path_bytes.len() > *base_len {
+            relative.extend_from_slice(&path_bytes[base_len + 1..]);
+            relative.push(b'/');
+        }
+        relative.extend_from_slice(name);
+
+        if let Some(verdict) = rules.matched(name, &relative, is_dir) {
+            ignored = verdict;
+            break;
+        }
+    }
+    ignored
+}
+
+pub(crate) fn is_excluded(
+    config: &TraversalConfig,
+    path_bytes: &[u8],
+    gitignores: &[(usize, Arc<Gitignore>)],
+    name: &[u8],
+    is_dir: bool,
+) -> bool {
+    if config.gitignore && is_gitignored(path_bytes, gitignores, name, is_dir) {
+        return true;
+    }
+
+    config.exclusions.as_ref().is_some_and(|exclusions| {
+        exclusions.excludes(name, is_dir, || {
+            // Path relative to the scan root, e.g. `a/b/name`.
+            let mut relative = Vec::with_capacity(path_bytes.len() + name.len());
+            if path_bytes.len() > config.root_len {
+                relative.extend_from_slice(&path_bytes[config.root_len + 1..]);
+                relative.push(b'/');
+            }
+            relative.extend_from_slice(name);
+            let absolute = config.root_abs.join(platform::os_str(&relative));
+            (relative, absolute)
+        })
+    })
+}